@@ -0,0 +1,268 @@
+use std::str::FromStr;
+
+/// Options for automatic gain control of the levels shown by an effect.
+///
+/// Times are in seconds and levels are in dB.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AgcOptions {
+    /// How quickly the tracked peak rises to a louder level.
+    pub attack: f32,
+    /// How quickly the tracked peak falls back after the level drops.
+    pub release: f32,
+    /// The output, from 0.0 to 1.0, that the tracked peak is shown at.
+    pub target: f32,
+    /// The range below the tracked peak that is shown.
+    pub range: f32,
+    /// How quickly the noise floor rises when the level stays above it, in dB per second.
+    /// The floor falls immediately to any quieter level.
+    pub floor_rise: f32,
+    /// Levels less than this far above the noise floor are shown as off.
+    pub floor_margin: f32,
+    /// Track each band separately instead of tracking the loudest band for all of them.
+    pub per_band: bool,
+}
+impl Default for AgcOptions {
+    fn default() -> Self {
+        AgcOptions {
+            attack: 0.05,
+            release: 2.0,
+            target: 0.9,
+            range: 40.0,
+            floor_rise: 3.0,
+            floor_margin: 6.0,
+            per_band: true,
+        }
+    }
+}
+impl FromStr for AgcOptions {
+    type Err = String;
+    /// Parses `,` separated `KEY=VALUE` options, where the keys are the names of the fields,
+    /// or `global` to track all bands together.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = AgcOptions::default();
+        for param in s.split(',').filter(|p| !p.is_empty()) {
+            let mut kv = param.splitn(2, '=');
+            let key = kv.next().unwrap();
+            if key == "global" {
+                options.per_band = false;
+                continue;
+            }
+            let value = kv
+                .next()
+                .ok_or_else(|| format!("AGC option {} is missing a value", key))
+                .and_then(|v| {
+                    f32::from_str(v).map_err(|e| format!("Invalid AGC option {}: {:?}", param, e))
+                })?;
+            match key {
+                "attack" => options.attack = value,
+                "release" => options.release = value,
+                "target" => options.target = value,
+                "range" => options.range = value,
+                "floor-rise" => options.floor_rise = value,
+                "floor-margin" => options.floor_margin = value,
+                _ => return Err(format!("Unknown AGC option: {}", key)),
+            }
+        }
+        if options.attack < 0.0 || options.release < 0.0 || !(options.range > 0.0) {
+            return Err(
+                "AGC times must not be negative and the range must be positive".to_string(),
+            );
+        }
+        Ok(options)
+    }
+}
+
+/// The peak and noise floor tracked for one band.
+#[derive(Clone, Copy, Debug)]
+struct Tracker {
+    peak: f32,
+    floor: f32,
+}
+impl Tracker {
+    fn update(&mut self, options: &AgcOptions, level: f32, dt: f32) {
+        if !level.is_finite() {
+            return;
+        }
+        if !self.peak.is_finite() {
+            // nothing has been heard yet
+            *self = Tracker {
+                peak: level,
+                floor: level,
+            };
+            return;
+        }
+        let time = if level > self.peak {
+            options.attack
+        } else {
+            options.release
+        };
+        self.peak += (level - self.peak) * smoothing(dt, time);
+        if level < self.floor {
+            self.floor = level;
+        } else {
+            self.floor = (self.floor + options.floor_rise * dt).min(level);
+        }
+    }
+    /// Maps `level` to [0.0, 1.0] relative to the tracked peak and noise floor.
+    fn normalize(&self, options: &AgcOptions, level: f32) -> f32 {
+        let bottom = (self.peak - options.range).max(self.floor + options.floor_margin);
+        if !(self.peak > bottom) || !level.is_finite() {
+            return 0.0; // nothing but noise
+        }
+        ((level - bottom) / (self.peak - bottom) * options.target)
+            .min(1.0)
+            .max(0.0)
+    }
+}
+
+/// The fraction of the way a one-pole filter with time constant `time` moves in `dt` seconds.
+#[inline]
+fn smoothing(dt: f32, time: f32) -> f32 {
+    if time <= 0.0 {
+        1.0
+    } else {
+        1.0 - (-dt / time).exp()
+    }
+}
+
+/// Automatic gain control, which scales levels relative to their recent peak
+/// so that quiet rooms are not dark and loud ones do not saturate.
+#[derive(Clone, Debug, Default)]
+pub struct Agc {
+    trackers: Vec<Tracker>,
+    time: Option<u64>,
+}
+impl Agc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Forget the tracked levels.
+    pub fn reset(&mut self) {
+        self.trackers.clear();
+        self.time = None;
+    }
+    /// Tracks `levels`, in dB, measured at `time` in microseconds,
+    /// and writes each level scaled to [0.0, 1.0] into `out`.
+    pub fn process(&mut self, options: &AgcOptions, levels: &[f32], time: u64, out: &mut [f32]) {
+        let n = if options.per_band { levels.len() } else { 1 };
+        let loudest = levels.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        if self.trackers.len() != n {
+            self.trackers.clear();
+            self.time = None;
+        }
+        let dt = match self.time {
+            Some(prev) => time.saturating_sub(prev) as f32 / 1_000_000.0,
+            None => {
+                let initial = |i: usize| {
+                    let level = if options.per_band { levels[i] } else { loudest };
+                    Tracker {
+                        peak: level,
+                        floor: level,
+                    }
+                };
+                self.trackers.extend((0..n).map(initial));
+                0.0
+            }
+        };
+        self.time = Some(time);
+        if options.per_band {
+            for (t, level) in self.trackers.iter_mut().zip(levels) {
+                t.update(options, *level, dt);
+            }
+        } else if let Some(t) = self.trackers.first_mut() {
+            t.update(options, loudest, dt);
+        }
+        for (i, (o, level)) in out.iter_mut().zip(levels).enumerate() {
+            let t = &self.trackers[if options.per_band { i } else { 0 }];
+            *o = t.normalize(options, *level);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Alternates each band between its level and 20 dB below it every 20 ms for `secs`,
+    /// returning the output of the last loud frame.
+    fn run(agc: &mut Agc, options: &AgcOptions, levels: &[f32], start: u64, secs: u64) -> Vec<f32> {
+        let mut out = vec![0.0; levels.len()];
+        let quiet: Vec<f32> = levels.iter().map(|l| l - 20.0).collect();
+        for i in 0..secs * 25 {
+            let time = start + i * 40_000;
+            agc.process(options, &quiet, time, &mut out);
+            agc.process(options, levels, time + 20_000, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn parses() {
+        let options = AgcOptions::from_str("attack=0.1,floor-margin=3,global").unwrap();
+        assert_eq!(options.attack, 0.1);
+        assert_eq!(options.floor_margin, 3.0);
+        assert!(!options.per_band);
+        assert_eq!(AgcOptions::from_str("").unwrap(), AgcOptions::default());
+        assert!(AgcOptions::from_str("attack").is_err());
+        assert!(AgcOptions::from_str("attack=fast").is_err());
+        assert!(AgcOptions::from_str("gain=3").is_err());
+        assert!(AgcOptions::from_str("release=-1").is_err());
+        assert!(AgcOptions::from_str("range=0").is_err());
+    }
+
+    #[test]
+    fn quiet_and_loud_rooms_look_alike() {
+        let options = AgcOptions::default();
+        let quiet = run(&mut Agc::new(), &options, &[-70.0], 0, 5);
+        let loud = run(&mut Agc::new(), &options, &[-10.0], 0, 5);
+        assert!((quiet[0] - loud[0]).abs() < 0.001, "{:?} {:?}", quiet, loud);
+        // slightly over the target, as the peak is released a little between loud frames
+        assert!(quiet[0] > 0.8 && quiet[0] < 0.95, "{:?}", quiet);
+    }
+
+    #[test]
+    fn attacks_quickly_and_releases_slowly() {
+        let options = AgcOptions::default();
+        let mut agc = Agc::new();
+        run(&mut agc, &options, &[-40.0], 0, 5);
+        // a sudden rise saturates for no more than a few frames
+        let mut out = [0.0];
+        agc.process(&options, &[-20.0], 5_000_000, &mut out);
+        assert_eq!(out[0], 1.0);
+        let louder = run(&mut agc, &options, &[-20.0], 5_020_000, 1);
+        assert!(louder[0] < 0.95, "{:?}", louder);
+        // after a drop the output only recovers over the release time
+        let soon = run(&mut agc, &options, &[-35.0], 6_020_000, 1);
+        let later = run(&mut agc, &options, &[-35.0], 7_020_000, 5);
+        assert!(soon[0] + 0.2 < later[0], "{:?} {:?}", soon, later);
+    }
+
+    #[test]
+    fn global_keeps_band_balance() {
+        let levels = [-20.0, -30.0];
+        let per_band = run(&mut Agc::new(), &AgcOptions::default(), &levels, 0, 5);
+        assert!((per_band[0] - per_band[1]).abs() < 0.001, "{:?}", per_band);
+        let options = AgcOptions {
+            per_band: false,
+            ..AgcOptions::default()
+        };
+        let global = run(&mut Agc::new(), &options, &levels, 0, 5);
+        assert!(global[0] > global[1] + 0.1, "{:?}", global);
+    }
+
+    #[test]
+    fn silence_is_dark() {
+        let options = AgcOptions::default();
+        let mut agc = Agc::new();
+        let mut out = [1.0; 2];
+        agc.process(&options, &[f32::NEG_INFINITY; 2], 0, &mut out);
+        assert_eq!(out, [0.0; 2]);
+        // a constant level is indistinguishable from the noise floor
+        for i in 0..100 {
+            agc.process(&options, &[-30.0; 2], i * 20_000, &mut out);
+        }
+        assert_eq!(out, [0.0; 2]);
+        let out = run(&mut agc, &options, &[-30.0; 2], 2_000_000, 2);
+        assert!(out[0] > 0.5, "{:?}", out);
+    }
+}
@@ -1,7 +1,5 @@
+use crate::spectrum::SpectrumAnalyzer;
 use crate::Error;
-use rustfft::num_complex::Complex;
-use rustfft::num_traits::Zero;
-use rustfft::FFT;
 use std::time::Duration;
 
 pub trait InactiveAudioSource {
@@ -12,6 +10,51 @@ pub trait InactiveAudioSource {
 #[derive(Clone, Copy)]
 pub struct AudioSourceOptions {
     pub stats: u16,
+    /// The number of frames in each `StereoSample` produced by the source.
+    pub sample_size: usize,
+    /// The number of frames in each FFT window.
+    pub fft_size: usize,
+    /// The number of frames between the starts of successive FFT windows.
+    pub hop_size: usize,
+    /// The number of channels captured by sources that can capture any number,
+    /// such as the ports registered by the JACK source.
+    pub channels: usize,
+}
+impl Default for AudioSourceOptions {
+    fn default() -> Self {
+        AudioSourceOptions {
+            stats: 0,
+            sample_size: 768,
+            fft_size: 256,
+            hop_size: 256,
+            channels: 2,
+        }
+    }
+}
+impl AudioSourceOptions {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.fft_size == 0 || self.hop_size == 0 {
+            return Err(Error::Unrecoverable(
+                "FFT and hop size must be non-zero".to_string(),
+            ));
+        }
+        if self.channels == 0 {
+            return Err(Error::Unrecoverable(
+                "There must be at least one channel".to_string(),
+            ));
+        }
+        if self.fft_size > self.sample_size {
+            return Err(Error::Unrecoverable(
+                "FFT size cannot be larger than the sample size".to_string(),
+            ));
+        }
+        Ok(())
+    }
+    /// The number of FFT windows that fit in a single sample.
+    #[inline]
+    pub fn n_windows(&self) -> usize {
+        (self.sample_size - self.fft_size) / self.hop_size + 1
+    }
 }
 
 pub trait ActiveAudioSource {
@@ -20,6 +63,15 @@ pub trait ActiveAudioSource {
     fn cur_time(&self) -> u64;
     fn recv(&mut self) -> Result<StereoSample, Error>;
     fn recv_timeout(&mut self, timeout: Duration) -> Result<StereoSample, Error>;
+    /// Give a sample back to the source once it is no longer needed,
+    /// so the source can reuse its buffers instead of allocating new ones.
+    #[inline]
+    fn recycle(&mut self, _ss: StereoSample) {}
+    /// The number of samples discarded because the consumer was not keeping up.
+    #[inline]
+    fn overruns(&self) -> usize {
+        0
+    }
     #[inline]
     fn try_recv(&mut self) -> Result<StereoSample, Error> {
         self.recv_timeout(Duration::from_secs(0))
@@ -58,87 +110,183 @@ impl<T: ActiveAudioSource> Iterator for TryIter<'_, T> {
     }
 }
 
+/// A block of audio frames with any number of channels, each stored separately.
 #[derive(Debug)]
-pub struct StereoSample {
+pub struct Sample {
     sample_size: usize,
-    left: Vec<f32>,
-    right: Vec<f32>,
+    channels: Vec<Vec<f32>>,
     rate: u32,
     time: u64,
 }
+/// Most sources and effects only deal with two channels, so samples are usually stereo.
+pub type StereoSample = Sample;
 
-impl StereoSample {
+impl Sample {
+    /// Creates an empty stereo sample.
+    #[inline]
     pub fn new(sample_size: usize, rate: u32, time: u64) -> Self {
-        StereoSample {
+        Self::with_channels(2, sample_size, rate, time)
+    }
+    /// Creates an empty sample with `channels` channels.
+    pub fn with_channels(channels: usize, sample_size: usize, rate: u32, time: u64) -> Self {
+        assert!(channels > 0);
+        Sample {
             sample_size,
+            channels: (0..channels)
+                .map(|_| Vec::with_capacity(sample_size))
+                .collect(),
             rate,
-            left: Vec::with_capacity(sample_size),
-            right: Vec::with_capacity(sample_size),
             time,
         }
     }
+    /// Empty the sample so its buffers can be reused for a new sample.
+    #[inline]
+    pub fn reset(&mut self, rate: u32, time: u64) {
+        for ch in self.channels.iter_mut() {
+            ch.clear();
+        }
+        self.rate = rate;
+        self.time = time;
+    }
+    /// Appends frames to a stereo sample, returning true once the sample is full.
+    #[inline]
     pub fn extend(&mut self, left: &[f32], right: &[f32]) -> bool {
-        assert_eq!(left.len(), right.len());
-        let len = left.len();
-        let remaining = self.sample_size - self.left.len();
-        if remaining > len {
-            self.left.extend_from_slice(left);
-            self.right.extend_from_slice(right);
-            false //return false if not full
-        } else {
-            self.left.extend_from_slice(&left[0..remaining]);
-            self.right.extend_from_slice(&right[0..remaining]);
-            true //return true if full
+        assert_eq!(self.channels.len(), 2);
+        self.extend_channels([left, right].iter().copied())
+    }
+    /// Appends an equal number of frames to every channel, returning true once the sample is full.
+    ///
+    /// `channels` must yield exactly one slice for each channel of the sample.
+    pub fn extend_channels<'a, I>(&mut self, channels: I) -> bool
+    where
+        I: IntoIterator<Item = &'a [f32]>,
+    {
+        let remaining = self.sample_size - self.len();
+        let mut count = 0;
+        let mut full = false;
+        let mut len = None;
+        for (ch, data) in self.channels.iter_mut().zip(channels) {
+            assert_eq!(*len.get_or_insert(data.len()), data.len());
+            if remaining > data.len() {
+                ch.extend_from_slice(data);
+            } else {
+                ch.extend_from_slice(&data[0..remaining]);
+                full = true;
+            }
+            count += 1;
         }
+        assert_eq!(count, self.channels.len());
+        full
     }
     pub fn len(&self) -> usize {
-        self.left.len()
-    }
-    pub fn spectrogram<T: FFT<f32>>(&self, fft: &T) -> (Vec<f32>, Vec<f32>) {
-        let mut l_in: Vec<Complex<f32>> = self.left.iter().map(|f| Complex::new(*f, 0.0)).collect();
-        let mut r_in: Vec<Complex<f32>> =
-            self.right.iter().map(|f| Complex::new(*f, 0.0)).collect();
-        let mut l_out: Vec<Complex<f32>> = vec![Complex::zero(); self.sample_size];
-        let mut r_out: Vec<Complex<f32>> = vec![Complex::zero(); self.sample_size];
-        fft.process_multi(&mut l_in, &mut l_out);
-        fft.process_multi(&mut r_in, &mut r_out);
-        /* normalize complex-valued amp and convert to amp-to-dB log_10 (amp^2).
-        Using norm_sqr() is a simplification that
-                    allows us to avoid an expensive sqrt operation for a value
-                                we would either just sqaure before being input to log10() ( or double after the log10(0).
-                                        */
-        (
-            l_out
-                .into_iter()
-                .map(|c| c.norm_sqr().log10() * 10.0)
-                .collect(),
-            r_out
-                .into_iter()
-                .map(|c| c.norm_sqr().log10() * 10.0)
-                .collect(),
-        )
+        self.channels[0].len()
+    }
+    /// The number of channels in the sample.
+    #[inline]
+    pub fn channels(&self) -> usize {
+        self.channels.len()
+    }
+    #[inline]
+    pub fn channel(&self, i: usize) -> &[f32] {
+        &self.channels[i]
+    }
+    /// The first channel.
+    #[inline]
+    pub fn left(&self) -> &[f32] {
+        &self.channels[0]
+    }
+    /// The second channel, or the first channel of mono samples.
+    #[inline]
+    pub fn right(&self) -> &[f32] {
+        &self.channels[1.min(self.channels.len() - 1)]
+    }
+    #[inline]
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+    #[inline]
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+    /// Computes the power spectrum of the sample into `left` and `right`.
+    /// See `SpectrumAnalyzer::process()`.
+    #[inline]
+    pub fn spectrogram(
+        &self,
+        analyzer: &mut SpectrumAnalyzer,
+        left: &mut [f32],
+        right: &mut [f32],
+    ) {
+        analyzer.process(self, left, right)
     }
 }
 
-pub(crate) const WEIGHT: [f32; 256] = [
-    0.0, -20.45, -14.43, -10.92, -8.43, -6.50, -4.93, -3.61, -2.47, -1.47, -0.58, 0.22, 0.95, 1.61,
-    2.22, 2.79, 3.31, 3.80, 4.26, 4.68, 5.09, 5.47, 5.83, 6.17, 6.49, 6.80, 7.09, 7.37, 7.64, 7.89,
-    8.14, 8.37, 8.60, 8.81, 9.02, 9.22, 9.41, 9.59, 9.77, 9.93, 10.10, 10.25, 10.40, 10.54, 10.68,
-    10.81, 10.94, 11.06, 11.17, 11.27, 11.38, 11.47, 11.56, 11.64, 11.72, 11.79, 11.85, 11.91,
-    11.97, 12.01, 12.05, 12.09, 12.12, 12.14, 12.16, 12.17, 12.18, 12.18, 12.17, 12.16, 12.15,
-    12.13, 12.10, 12.07, 12.04, 12.00, 11.95, 11.91, 11.85, 11.79, 11.73, 11.67, 11.60, 11.52,
-    11.44, 11.36, 11.27, 11.18, 11.08, 10.98, 10.87, 10.76, 10.64, 10.51, 10.38, 10.24, 10.10,
-    9.95, 9.79, 9.63, 9.45, 9.27, 9.08, 8.89, 8.68, 8.47, 8.25, 8.02, 7.78, 7.54, 7.28, 7.02, 6.75,
-    6.48, 6.20, 5.91, 5.61, 5.31, 5.01, 4.70, 4.38, 4.06, 3.74, 3.41, 3.09, 2.76, 2.42, 2.09, 1.75,
-    1.41, 1.08, 0.74, 0.40, 0.06, -0.28, -0.62, -0.96, -1.29, -1.63, -1.97, -2.30, -2.63, -2.97,
-    -3.30, -3.63, -3.95, -4.28, -4.60, -4.93, -5.25, -5.57, -5.88, -6.20, -6.51, -6.83, -7.13,
-    -7.44, -7.75, -8.05, -8.35, -8.65, -8.95, -9.25, -9.54, -9.84, -10.13, -10.42, -10.70, -10.99,
-    -11.27, -11.55, -11.83, -12.11, -12.38, -12.66, -12.93, -13.20, -13.47, -13.74, -14.00, -14.27,
-    -14.53, -14.79, -15.05, -15.31, -15.56, -15.82, -16.07, -16.32, -16.57, -16.82, -17.06, -17.31,
-    -17.55, -17.79, -18.04, -18.27, -18.51, -18.75, -18.98, -19.22, -19.45, -19.68, -19.91, -20.14,
-    -20.37, -20.59, -20.82, -21.04, -21.27, -21.49, -21.71, -21.93, -22.14, -22.36, -22.58, -22.79,
-    -23.00, -23.21, -23.43, -23.64, -23.84, -24.05, -24.26, -24.46, -24.67, -24.87, -25.08, -25.28,
-    -25.48, -25.68, -25.88, -26.07, -26.27, -26.47, -26.66, -26.86, -27.05, -27.24, -27.43, -27.62,
-    -27.81, -28.00, -28.19, -28.38, -28.56, -28.75, -28.93, -29.12, -29.30, -29.48, -29.66, -29.84,
-    -30.02, -30.20, -30.38,
-];
+/// Mixes samples with any number of channels down to stereo.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Downmix {
+    gains: Vec<(f32, f32)>,
+}
+impl Downmix {
+    /// Creates a downmix from the gain of each input channel into the left and right channels.
+    pub fn new(gains: Vec<(f32, f32)>) -> Self {
+        Downmix { gains }
+    }
+    /// The usual downmix for samples with `channels` channels.
+    ///
+    /// Mono is played on both sides and stereo is unchanged. 5.1 in the order
+    /// L, R, C, LFE, Ls, Rs uses the ITU-R BS.775 coefficients, leaving out the LFE.
+    /// Other layouts alternate their channels between left and right.
+    pub fn for_channels(channels: usize) -> Self {
+        const M3DB: f32 = std::f32::consts::FRAC_1_SQRT_2;
+        let gains = match channels {
+            1 => vec![(1.0, 1.0)],
+            6 => vec![
+                (1.0, 0.0),
+                (0.0, 1.0),
+                (M3DB, M3DB),
+                (0.0, 0.0),
+                (M3DB, 0.0),
+                (0.0, M3DB),
+            ],
+            _ => {
+                let left = ((channels + 1) / 2) as f32;
+                let right = (channels / 2).max(1) as f32;
+                (0..channels)
+                    .map(|i| {
+                        if i % 2 == 0 {
+                            (1.0 / left, 0.0)
+                        } else {
+                            (0.0, 1.0 / right)
+                        }
+                    })
+                    .collect()
+            }
+        };
+        Downmix { gains }
+    }
+    #[inline]
+    pub fn gains(&self) -> &[(f32, f32)] {
+        &self.gains
+    }
+    /// Writes the stereo mix of `input` into `out`, replacing its contents.
+    ///
+    /// Channels without a gain are left out of the mix.
+    pub fn apply(&self, input: &Sample, out: &mut Sample) {
+        assert_eq!(out.channels.len(), 2);
+        let len = input.len();
+        out.sample_size = len;
+        out.rate = input.rate;
+        out.time = input.time;
+        for ch in out.channels.iter_mut() {
+            ch.clear();
+            ch.resize(len, 0.0);
+        }
+        let (left, right) = out.channels.split_at_mut(1);
+        for (data, (l_gain, r_gain)) in input.channels.iter().zip(self.gains.iter()) {
+            for ((l, r), v) in left[0].iter_mut().zip(right[0].iter_mut()).zip(data) {
+                *l += v * l_gain;
+                *r += v * r_gain;
+            }
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::cmp::Ordering;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// How the crossover frequencies between bands are spaced.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Spacing {
+    /// Bands of equal width in Hz.
+    Linear,
+    /// Bands of equal width on a logarithmic scale.
+    Log,
+    /// Standard octave bands centered on 1 kHz. The number of bands is set by the frequency range.
+    Octave,
+    /// Standard third-octave bands centered on 1 kHz. The number of bands is set by the frequency range.
+    ThirdOctave,
+    /// Bands of equal width on the mel scale.
+    Mel,
+    /// Bands of equal width on the Bark scale.
+    Bark,
+}
+impl FromStr for Spacing {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Spacing::Linear),
+            "log" => Ok(Spacing::Log),
+            "octave" => Ok(Spacing::Octave),
+            "third-octave" => Ok(Spacing::ThirdOctave),
+            "mel" => Ok(Spacing::Mel),
+            "bark" => Ok(Spacing::Bark),
+            _ => Err(format!("Unknown band spacing: {}", s)),
+        }
+    }
+}
+impl Spacing {
+    /// Maps a frequency in Hz onto the scale the bands are evenly spaced on.
+    fn warp(self, freq: f32) -> f32 {
+        match self {
+            Spacing::Linear => freq,
+            Spacing::Log | Spacing::Octave | Spacing::ThirdOctave => freq.ln(),
+            Spacing::Mel => 2595.0 * (1.0 + freq / 700.0).log10(),
+            // Traunmüller's approximation
+            Spacing::Bark => 26.81 * freq / (1960.0 + freq) - 0.53,
+        }
+    }
+    fn unwarp(self, v: f32) -> f32 {
+        match self {
+            Spacing::Linear => v,
+            Spacing::Log | Spacing::Octave | Spacing::ThirdOctave => v.exp(),
+            Spacing::Mel => 700.0 * (10f32.powf(v / 2595.0) - 1.0),
+            Spacing::Bark => 1960.0 * (v + 0.53) / (26.28 - v),
+        }
+    }
+    /// Computes the edges, in Hz, of `count` bands between `min` and `max`.
+    ///
+    /// For octave and third-octave spacing `count` is ignored and every standard band
+    /// whose center lies within the range is used instead.
+    pub fn edges(&self, count: usize, min: f32, max: f32) -> Vec<f32> {
+        let fraction = match self {
+            Spacing::Octave => 1.0,
+            Spacing::ThirdOctave => 3.0,
+            _ => {
+                let (lo, hi) = (self.warp(min), self.warp(max));
+                return (0..=count)
+                    .map(|i| self.unwarp(lo + (hi - lo) * i as f32 / count as f32))
+                    .collect();
+            }
+        };
+        let first = (fraction * (min / 1000.0).log2()).ceil() as i32;
+        let last = (fraction * (max / 1000.0).log2()).floor() as i32;
+        let half = 2f32.powf(0.5 / fraction);
+        let mut edges: Vec<f32> = (first..=last)
+            .map(|k| 1000.0 * 2f32.powf(k as f32 / fraction) / half)
+            .collect();
+        if let Some(upper) = edges.last().map(|e| e * half * half) {
+            edges.push(upper);
+        }
+        edges
+    }
+}
+
+/// A set of adjacent frequency bands.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bands {
+    edges: Vec<f32>,
+}
+impl Bands {
+    /// Creates `count` bands between `min` and `max` Hz.
+    pub fn new(spacing: Spacing, count: usize, min: f32, max: f32) -> Result<Self, String> {
+        if count == 0 {
+            return Err("There must be at least one band".to_string());
+        }
+        if !(min > 0.0 && max > min) {
+            return Err(format!("Invalid band range: {} to {} Hz", min, max));
+        }
+        Self::from_edges(spacing.edges(count, min, max))
+    }
+    /// Creates bands from the edges in Hz, which includes the lower edge of the first
+    /// band, the crossovers and the upper edge of the last band.
+    pub fn from_edges(edges: Vec<f32>) -> Result<Self, String> {
+        if edges.len() < 2 {
+            return Err("There must be at least one band".to_string());
+        }
+        if edges[0] < 0.0
+            || edges
+                .windows(2)
+                .any(|w| w[1].partial_cmp(&w[0]) != Some(Ordering::Greater))
+        {
+            return Err(format!("Band edges must be increasing: {:?}", edges));
+        }
+        Ok(Bands { edges })
+    }
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.edges.len() - 1
+    }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// The edges of the bands in Hz.
+    #[inline]
+    pub fn edges(&self) -> &[f32] {
+        &self.edges
+    }
+    /// Maps the bands onto the `fft_size / 2 + 1` bins of a spectrum at `rate`.
+    ///
+    /// Every band is at least one bin wide, so narrow bands at low frequencies are
+    /// widened and may overlap the frequencies of their neighbours. The DC bin is never used.
+    pub fn bin_ranges(&self, fft_size: usize, rate: u32) -> Result<Vec<Range<usize>>, String> {
+        let bins = fft_size / 2 + 1;
+        let n = self.len();
+        if n > bins - 1 {
+            return Err(format!(
+                "{} bands cannot fit in the {} bins of a {} point FFT",
+                n, bins, fft_size
+            ));
+        }
+        let scale = fft_size as f32 / rate as f32;
+        let mut edges: Vec<usize> = self
+            .edges
+            .iter()
+            .map(|f| ((f * scale).round() as usize).max(1).min(bins))
+            .collect();
+        for i in 1..edges.len() {
+            edges[i] = edges[i].max(edges[i - 1] + 1);
+        }
+        // bands beyond the Nyquist frequency are squeezed into the last bins
+        let last = edges.len() - 1;
+        edges[last] = edges[last].min(bins);
+        for i in (0..last).rev() {
+            edges[i] = edges[i].min(edges[i + 1] - 1);
+        }
+        Ok(edges.windows(2).map(|w| w[0]..w[1]).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(ranges: &[Range<usize>], count: usize, bins: usize) {
+        assert_eq!(ranges.len(), count);
+        assert!(ranges[0].start >= 1, "{:?}", ranges);
+        assert!(ranges[count - 1].end <= bins, "{:?}", ranges);
+        assert!(ranges.iter().all(|r| r.start < r.end), "{:?}", ranges);
+        assert!(
+            ranges.windows(2).all(|w| w[0].end == w[1].start),
+            "{:?}",
+            ranges
+        );
+    }
+
+    #[test]
+    fn bin_ranges() {
+        let bands = Bands::from_edges(vec![0.0, 375.0, 750.0, 1500.0, 24000.0]).unwrap();
+        let ranges = bands.bin_ranges(256, 48000).unwrap();
+        assert_eq!(ranges, vec![1..2, 2..4, 4..8, 8..128]);
+
+        for &spacing in &[
+            Spacing::Linear,
+            Spacing::Log,
+            Spacing::Octave,
+            Spacing::ThirdOctave,
+            Spacing::Mel,
+            Spacing::Bark,
+        ] {
+            let bands = Bands::new(spacing, 16, 20.0, 20000.0).unwrap();
+            for &fft_size in &[64, 256, 4096] {
+                let bins = fft_size / 2 + 1;
+                check(
+                    &bands.bin_ranges(fft_size, 44100).unwrap(),
+                    bands.len(),
+                    bins,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bin_ranges_squeeze_narrow_bands() {
+        // below the first bin and above the Nyquist frequency
+        let bands = Bands::new(Spacing::Log, 8, 1.0, 1e6).unwrap();
+        check(&bands.bin_ranges(32, 48000).unwrap(), 8, 17);
+        let bands = Bands::new(Spacing::Linear, 16, 1.0, 2.0).unwrap();
+        check(&bands.bin_ranges(32, 48000).unwrap(), 16, 17);
+        assert!(bands.bin_ranges(16, 48000).is_err());
+    }
+
+    #[test]
+    fn standard_octaves() {
+        let bands = Bands::new(Spacing::Octave, 1, 20.0, 20000.0).unwrap();
+        // the ten octaves centered on 31.5 Hz to 16 kHz
+        assert_eq!(bands.len(), 10);
+        let centers: Vec<f32> = bands
+            .edges()
+            .windows(2)
+            .map(|w| (w[0] * w[1]).sqrt())
+            .collect();
+        assert!((centers[5] - 1000.0).abs() < 0.1, "{:?}", centers);
+        assert!(Bands::new(Spacing::Log, 0, 20.0, 20000.0).is_err());
+        assert!(Bands::new(Spacing::Log, 4, 200.0, 20.0).is_err());
+        assert!(Bands::from_edges(vec![100.0, 50.0]).is_err());
+    }
+}
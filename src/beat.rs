@@ -0,0 +1,375 @@
+use crate::audio::StereoSample;
+use crate::spectrum::SpectrumAnalyzer;
+use crate::Error;
+use std::collections::VecDeque;
+
+/// Options controlling onset detection and tempo estimation.
+///
+/// Times are in microseconds, matching `StereoSample::time()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BeatOptions {
+    /// Bins quieter than this, in dB, are raised to it so noise in quiet passages
+    /// does not register as flux.
+    pub floor: f32,
+    /// The number of standard deviations above the recent mean flux that an onset must exceed.
+    pub threshold: f32,
+    /// The minimum flux, in dB, that can be considered an onset.
+    pub min_flux: f32,
+    /// How far back the flux history used for the adaptive threshold goes.
+    pub threshold_window: u64,
+    /// The shortest time allowed between two onsets.
+    pub min_interval: u64,
+    /// How far back onsets are used for estimating the tempo.
+    pub tempo_window: u64,
+    pub min_bpm: f32,
+    pub max_bpm: f32,
+}
+impl Default for BeatOptions {
+    fn default() -> Self {
+        BeatOptions {
+            floor: -80.0,
+            threshold: 1.5,
+            min_flux: 1.0,
+            threshold_window: 1_000_000,
+            min_interval: 100_000,
+            tempo_window: 8_000_000,
+            min_bpm: 70.0,
+            max_bpm: 180.0,
+        }
+    }
+}
+impl BeatOptions {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.min_bpm <= 0.0 || self.max_bpm < self.min_bpm * 2.0 {
+            return Err(Error::Unrecoverable(
+                "The BPM range must be positive and span at least one octave".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The result of analysing a single spectrum with a `BeatTracker`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BeatInfo {
+    /// The time of the analysed spectrum in microseconds.
+    pub time: u64,
+    /// True if an onset was detected in this spectrum.
+    pub onset: bool,
+    /// The spectral flux, the average increase in dB across all bins.
+    pub flux: f32,
+    /// The flux that had to be exceeded for an onset to be detected.
+    pub threshold: f32,
+    /// True if a predicted beat occured since the previous spectrum.
+    pub beat: bool,
+    /// The position within the current beat in the range [0.0, 1.0).
+    pub phase: f32,
+    /// The estimated tempo, or 0.0 if it is not yet known.
+    pub bpm: f32,
+    /// How consistent the recent onsets are with `bpm`, in the range [0.0, 1.0].
+    pub confidence: f32,
+}
+
+/// Detects onsets using spectral flux with an adaptive threshold and tracks the beat
+/// from the intervals between them.
+///
+/// Spectra do not need to be evenly spaced, because the timestamps of the samples are
+/// used for all timing. This allows the tracker to keep up when samples are skipped.
+pub struct BeatTracker {
+    options: BeatOptions,
+    prev: Vec<f32>,
+    has_prev: bool,
+    history: VecDeque<(u64, f32)>,
+    onsets: VecDeque<u64>,
+    histogram: Vec<f32>,
+    /// The time of a beat, in microseconds, that the phase is measured from.
+    anchor: Option<f64>,
+    info: BeatInfo,
+    l_spec: Vec<f32>,
+    r_spec: Vec<f32>,
+}
+impl BeatTracker {
+    pub fn new(options: BeatOptions) -> Result<Self, Error> {
+        options.validate()?;
+        let mut tracker = BeatTracker {
+            options,
+            prev: Vec::new(),
+            has_prev: false,
+            history: VecDeque::with_capacity(256),
+            onsets: VecDeque::with_capacity(64),
+            histogram: Vec::new(),
+            anchor: None,
+            info: BeatInfo::default(),
+            l_spec: Vec::new(),
+            r_spec: Vec::new(),
+        };
+        tracker.reset();
+        Ok(tracker)
+    }
+    #[inline]
+    pub fn options(&self) -> &BeatOptions {
+        &self.options
+    }
+    pub fn set_options(&mut self, options: BeatOptions) -> Result<(), Error> {
+        options.validate()?;
+        self.options = options;
+        self.reset();
+        Ok(())
+    }
+    /// The result of the most recent spectrum.
+    #[inline]
+    pub fn info(&self) -> BeatInfo {
+        self.info
+    }
+    /// Forget all history, such as when the audio source changes.
+    pub fn reset(&mut self) {
+        self.has_prev = false;
+        self.history.clear();
+        self.onsets.clear();
+        let len = (self.options.max_bpm - self.options.min_bpm).ceil() as usize + 1;
+        self.histogram.clear();
+        self.histogram.resize(len, 0.0);
+        self.anchor = None;
+        self.info = BeatInfo::default();
+    }
+    /// Computes the spectra of `ss` with `analyzer` and analyses them.
+    pub fn process_sample(
+        &mut self,
+        ss: &StereoSample,
+        analyzer: &mut SpectrumAnalyzer,
+    ) -> BeatInfo {
+        let bins = analyzer.bins();
+        let mut l_spec = std::mem::take(&mut self.l_spec);
+        let mut r_spec = std::mem::take(&mut self.r_spec);
+        l_spec.resize(bins, 0.0);
+        r_spec.resize(bins, 0.0);
+        ss.spectrogram(analyzer, &mut l_spec, &mut r_spec);
+        let info = self.process(&l_spec, &r_spec, ss.time());
+        self.l_spec = l_spec;
+        self.r_spec = r_spec;
+        info
+    }
+    /// Analyses the power spectra, in dB, of both channels taken at `time`.
+    pub fn process(&mut self, left: &[f32], right: &[f32], time: u64) -> BeatInfo {
+        let flux = self.flux(left, right);
+        let threshold = self.threshold(time);
+        let onset = match flux {
+            Some(flux) => {
+                flux > threshold
+                    && self.history.len() > 1
+                    && self.onsets.back().map_or(true, |o| {
+                        time.saturating_sub(*o) >= self.options.min_interval
+                    })
+            }
+            None => false,
+        };
+        let flux = flux.unwrap_or(0.0);
+        self.history.push_back((time, flux));
+        if onset {
+            self.onsets.push_back(time);
+            while let Some(o) = self.onsets.front() {
+                if time.saturating_sub(*o) <= self.options.tempo_window {
+                    break;
+                }
+                self.onsets.pop_front();
+            }
+            self.estimate_tempo();
+        }
+        let prev_time = self.info.time;
+        self.info.time = time;
+        self.info.onset = onset;
+        self.info.flux = flux;
+        self.info.threshold = threshold;
+        self.track_phase(prev_time, time, onset);
+        self.info
+    }
+    /// The average positive change in dB of each bin since the previous spectra.
+    fn flux(&mut self, left: &[f32], right: &[f32]) -> Option<f32> {
+        let len = left.len() + right.len();
+        if self.prev.len() != len {
+            self.prev.clear();
+            self.prev.resize(len, 0.0);
+            self.has_prev = false;
+        }
+        let floor = self.options.floor;
+        let mut sum = 0.0;
+        for (p, v) in self.prev.iter_mut().zip(left.iter().chain(right.iter())) {
+            let v = v.max(floor);
+            if v > *p {
+                sum += v - *p;
+            }
+            *p = v;
+        }
+        let has_prev = self.has_prev;
+        self.has_prev = true;
+        if has_prev && len > 0 {
+            Some(sum / len as f32)
+        } else {
+            None
+        }
+    }
+    /// The mean plus a number of standard deviations of the recent flux.
+    fn threshold(&mut self, time: u64) -> f32 {
+        while let Some((t, _)) = self.history.front() {
+            if time.saturating_sub(*t) <= self.options.threshold_window {
+                break;
+            }
+            self.history.pop_front();
+        }
+        let n = self.history.len() as f32;
+        if n == 0.0 {
+            return self.options.min_flux;
+        }
+        let mean = self.history.iter().map(|(_, f)| f).sum::<f32>() / n;
+        let var = self
+            .history
+            .iter()
+            .map(|(_, f)| (f - mean) * (f - mean))
+            .sum::<f32>()
+            / n;
+        (mean + self.options.threshold * var.sqrt()).max(self.options.min_flux)
+    }
+    /// Builds a histogram of the intervals between recent onsets, folded into the BPM range,
+    /// and picks its peak.
+    fn estimate_tempo(&mut self) {
+        let (min_bpm, max_bpm) = (self.options.min_bpm, self.options.max_bpm);
+        for h in self.histogram.iter_mut() {
+            *h = 0.0;
+        }
+        let mut total = 0.0;
+        for (i, a) in self.onsets.iter().enumerate() {
+            for (j, b) in self.onsets.iter().enumerate().skip(i + 1) {
+                if b <= a {
+                    continue;
+                }
+                let mut bpm = 60e6 / (b - a) as f32;
+                while bpm > max_bpm {
+                    bpm /= 2.0;
+                }
+                while bpm < min_bpm {
+                    bpm *= 2.0;
+                }
+                if bpm > max_bpm {
+                    continue;
+                }
+                // intervals spanning fewer onsets are more likely to be a single beat
+                let weight = 1.0 / (j - i) as f32;
+                let pos = bpm - min_bpm;
+                let idx = pos.round() as usize;
+                for (k, w) in [(idx.wrapping_sub(1), 0.5), (idx, 1.0), (idx + 1, 0.5)].iter() {
+                    if let Some(h) = self.histogram.get_mut(*k) {
+                        *h += weight * w;
+                        total += weight * w;
+                    }
+                }
+            }
+        }
+        if total == 0.0 {
+            return;
+        }
+        let (peak, _) = self
+            .histogram
+            .iter()
+            .enumerate()
+            .fold(
+                (0, 0.0),
+                |acc, (i, h)| if *h > acc.1 { (i, *h) } else { acc },
+            );
+        // refine the peak with the centroid of it and its neighbours
+        let lo = peak.saturating_sub(1);
+        let hi = (peak + 2).min(self.histogram.len());
+        let (mut sum, mut weighted) = (0.0, 0.0);
+        for (i, h) in self.histogram[lo..hi].iter().enumerate() {
+            sum += h;
+            weighted += h * (lo + i) as f32;
+        }
+        self.info.bpm = min_bpm + weighted / sum;
+        self.info.confidence = (sum / total).min(1.0);
+    }
+    /// Advances the beat phase, pulling it towards onsets that land near a predicted beat.
+    fn track_phase(&mut self, prev_time: u64, time: u64, onset: bool) {
+        self.info.beat = false;
+        if self.info.bpm <= 0.0 {
+            self.info.phase = 0.0;
+            return;
+        }
+        let period = 60e6 / self.info.bpm as f64;
+        let t = time as f64;
+        let beats_at = |anchor: f64, t: f64| ((t - anchor) / period).floor();
+        if let Some(anchor) = self.anchor {
+            if onset {
+                let expected = anchor + ((t - anchor) / period).round() * period;
+                let err = t - expected;
+                if err.abs() < period * 0.25 {
+                    self.anchor = Some(expected + err * 0.5);
+                } else if self.info.confidence < 0.5 {
+                    self.anchor = Some(t);
+                }
+            }
+            let anchor = self.anchor.unwrap();
+            self.info.beat = beats_at(anchor, t) > beats_at(anchor, prev_time as f64);
+        } else if onset {
+            self.anchor = Some(t);
+            self.info.beat = true;
+        }
+        if let Some(anchor) = self.anchor {
+            self.info.phase = (((t - anchor) / period).rem_euclid(1.0)) as f32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{ActiveAudioSource, AudioSourceOptions, InactiveAudioSource};
+    use crate::gen_src::{Signal, SignalGenerator};
+    use crate::spectrum::SpectrumOptions;
+
+    /// Tracks `secs` of `signal` on both channels, returning the result of every sample.
+    fn track(signal: Signal, secs: u64) -> Vec<BeatInfo> {
+        let mut gen = SignalGenerator::new(signal, signal);
+        gen.realtime = false;
+        let options = AudioSourceOptions {
+            sample_size: 256,
+            ..AudioSourceOptions::default()
+        };
+        let mut src = gen.activate(options).unwrap();
+        let mut analyzer = SpectrumAnalyzer::new(256, 256, &SpectrumOptions::default());
+        let mut tracker = BeatTracker::new(BeatOptions::default()).unwrap();
+        (0..secs * 48000 / 256)
+            .map(|_| tracker.process_sample(&src.recv().unwrap(), &mut analyzer))
+            .collect()
+    }
+
+    #[test]
+    fn follows_impulses() {
+        let infos = track(
+            Signal::Impulse {
+                freq: 2.0,
+                amp: 0.8,
+            },
+            6,
+        );
+        let onsets: Vec<u64> = infos.iter().filter(|i| i.onset).map(|i| i.time).collect();
+        // every impulse after the first, which has nothing to compare to, is an onset
+        // detected in the sample that contains it
+        assert!(onsets.len() >= 10, "{:?}", onsets);
+        for time in onsets.iter() {
+            let until_impulse = (500_000 - time % 500_000) % 500_000;
+            assert!(until_impulse < 256 * 1_000_000 / 48000, "{:?}", onsets);
+        }
+        let last = infos.last().unwrap();
+        assert!((last.bpm - 120.0).abs() < 1.0, "{:?}", last);
+        assert!(last.confidence > 0.5, "{:?}", last);
+        let beats = infos[infos.len() / 2..].iter().filter(|i| i.beat).count();
+        assert!((5..=7).contains(&beats), "{} beats", beats);
+    }
+
+    #[test]
+    fn silence_has_no_beat() {
+        let infos = track(Signal::Silence, 2);
+        assert!(infos.iter().all(|i| !i.onset && !i.beat));
+        let last = infos.last().unwrap();
+        assert_eq!((last.bpm, last.confidence), (0.0, 0.0));
+    }
+}
@@ -1,17 +1,30 @@
 use async_std::task::block_on;
 
-use clap::{App, Arg, ArgMatches};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use gpio_cdev::Chip;
 use lecp::{channel, Sender};
 
 use spidev::Spidev;
-use std::num::{NonZeroU16, NonZeroU8};
+use std::num::{NonZeroU16, NonZeroU8, NonZeroUsize};
 use std::str::FromStr;
 use std::thread::Builder;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use synesthesia;
 use synesthesia::audio::{AudioSourceOptions, InactiveAudioSource};
-use synesthesia::control::{Algorithm, AudioVisualizer, Effect};
+use synesthesia::control::AudioVisualizer;
+use synesthesia::effect::{Algorithm, EffectRegistry};
+#[cfg(any(feature = "hound", feature = "claxon"))]
+use synesthesia::file_src::AudioFile;
+use synesthesia::gen_src::{Signal, SignalGenerator};
+use synesthesia::midi::InactiveMidiSource;
+use synesthesia::midi_map::{MidiMap, Param};
+use synesthesia::net_src::{NetFormat, NetStream};
+use synesthesia::pcm_src::{PcmFormat, PcmStream};
+use synesthesia::record::{Recorder, Recording};
+use synesthesia::replay::Player;
+use synesthesia::smf_src::MidiFile;
+use synesthesia::spectrum::{Normalization, SpectrumOptions, Window};
+use synesthesia::weighting::Weighting;
 
 #[cfg(feature = "bluetooth")]
 use lecp::bluetooth::BluetoothSender;
@@ -34,6 +47,25 @@ use ham::IntoPacketSender;
 pub fn main() {
     let parser = parser();
     let args = parser.get_matches();
+    if let Some(replay) = args.subcommand_matches("replay") {
+        let recording = Recording::open(replay.value_of("recording").unwrap()).unwrap();
+        let mut player = Player::new(recording);
+        player.set_speed(f64::from_str(replay.value_of("speed").unwrap()).unwrap());
+        player.looping = replay.is_present("loop");
+        if let Some(secs) = replay.value_of("seek") {
+            player.seek((f64::from_str(secs).unwrap() * 1e6) as u64);
+        }
+        with_sender(args, |args, sender| {
+            let mut senders = vec![sender];
+            if let Some(path) = args.value_of("record") {
+                senders.push(Box::new(Recorder::create(path).unwrap()));
+            }
+            if let Err(e) = player.play(&mut senders) {
+                panic!("Replay failed: {:?}", e);
+            }
+        });
+        return;
+    }
     match args.value_of("source").unwrap() {
         //"jack" =>  jack::Client::new(args.value_of("flatstack").unwrap(), jack::ClientOptions::NO_START_SERVER).unwrap().0),
         "jack" => {
@@ -51,17 +83,69 @@ pub fn main() {
                 panic!("Jack support was not enabled at compile time.");
             }
         }
+        "file" => {
+            #[cfg(any(feature = "hound", feature = "claxon"))]
+            {
+                let path = args
+                    .value_of("file")
+                    .expect("--file FILE is required for the file source!");
+                let mut src = AudioFile::new(path);
+                src.realtime = !args.is_present("fast");
+                start_sender(args, src)
+            }
+            if !cfg!(any(feature = "hound", feature = "claxon")) {
+                panic!("Audio file support was not enabled at compile time.");
+            }
+        }
+        "stdin" | "pipe" => {
+            let format = PcmFormat::from_str(args.value_of("pcm-format").unwrap()).unwrap();
+            let channels = u16::from_str(args.value_of("channels").unwrap()).unwrap();
+            let rate = u32::from_str(args.value_of("rate").unwrap()).unwrap();
+            if args.value_of("source").unwrap() == "stdin" {
+                start_sender(args, PcmStream::stdin(format, channels, rate))
+            } else {
+                let path = args
+                    .value_of("file")
+                    .expect("--file FILE is required for the pipe source!");
+                let src = PcmStream::open(path, format, channels, rate).unwrap();
+                start_sender(args, src)
+            }
+        }
+        "gen" => {
+            let left = Signal::from_str(args.value_of("signal").unwrap()).unwrap();
+            let right = match args.value_of("signal-right") {
+                Some(s) => Signal::from_str(s).unwrap(),
+                None => left,
+            };
+            let mut src = SignalGenerator::new(left, right);
+            src.rate = u32::from_str(args.value_of("rate").unwrap()).unwrap();
+            src.realtime = !args.is_present("fast");
+            start_sender(args, src)
+        }
+        "net" => {
+            let pcm = PcmFormat::from_str(args.value_of("pcm-format").unwrap()).unwrap();
+            let format = match args.value_of("net-format").unwrap() {
+                "framed" => NetFormat::Framed(pcm),
+                "rtp-l16" => NetFormat::RtpL16,
+                "rtp-l24" => NetFormat::RtpL24,
+                _ => unreachable!(),
+            };
+            let mut src = NetStream::bind(args.value_of("listen").unwrap(), format).unwrap();
+            src.channels = u16::from_str(args.value_of("channels").unwrap()).unwrap();
+            src.rate = u32::from_str(args.value_of("rate").unwrap()).unwrap();
+            src.latency =
+                Duration::from_millis(u64::from_str(args.value_of("latency").unwrap()).unwrap());
+            start_sender(args, src)
+        }
         _ => unimplemented!(),
     }
 }
 fn start_sender<T: InactiveAudioSource>(args: ArgMatches, src: T) {
+    with_sender(args, |args, sender| start_av(args, src, sender))
+}
+/// Creates the sender of the selected mode and passes it to `run`.
+fn with_sender<F: FnOnce(&ArgMatches, Box<dyn Sender>)>(args: ArgMatches, run: F) {
     let verbose = args.occurrences_of("verbose") as u8;
-    let sendstats = if args.is_present("sendstats") {
-        u16::from_str(args.value_of("sendstats").unwrap()).unwrap()
-    } else {
-        0
-    };
-    let aso = AudioSourceOptions { stats: sendstats };
     match args.value_of("mode").unwrap() {
         "local" => {
             #[cfg(feature = "rpi")]
@@ -101,7 +185,7 @@ fn start_sender<T: InactiveAudioSource>(args: ArgMatches, src: T) {
                         );
                     })
                     .unwrap();
-                start_av(verbose, src, sender);
+                run(&args, Box::new(sender));
             }
             if !cfg!(feature = "rpi") {
                 panic!("Local rendering on an RPi was not enabled at compile time.");
@@ -126,7 +210,7 @@ fn start_sender<T: InactiveAudioSource>(args: ArgMatches, src: T) {
                 let mut sender = rfm.into_packet_sender(1).unwrap();
                 sender.set_verbose(verbose).unwrap();
                 unimplemented!();
-                //start_av(verbose, src, sender, aso);
+                //run(&args, Box::new(sender));
             }
             if !cfg!(feature = "ham") {
                 panic!("Sending using HamSender was not enabled at compile time.");
@@ -141,7 +225,7 @@ fn start_sender<T: InactiveAudioSource>(args: ArgMatches, src: T) {
                     .expect("--mac MAC is require for bluetooth!");
                 let mac = MAC::from_str(mac_arg).expect("MAC argument was invalid!");
                 let bt_sender = block_on(BluetoothSender::new(bt_dev, mac)).unwrap();
-                start_av(verbose, src, bt_sender, aso);
+                run(&args, Box::new(bt_sender));
             }
             if !cfg!(feature = "bluetooth") {
                 panic!("Sending using bluetooth was not enabled at compile time.");
@@ -151,24 +235,95 @@ fn start_sender<T: InactiveAudioSource>(args: ArgMatches, src: T) {
     }
 }
 
-fn start_av<S: InactiveAudioSource, T: Sender + 'static>(
-    verbose: u8,
-    src: S,
-    sender: T,
-    aso: AudioSourceOptions,
-) {
-    let mut av = AudioVisualizer::new(
-        src,
-        Effect::Stereo4FlatStack(Algorithm::Quadratic, false),
-        aso,
-    )
-    .unwrap();
-    av.senders.push(Box::new(sender));
-    av.verbose = verbose;
+fn start_av<S: InactiveAudioSource>(args: &ArgMatches, src: S, sender: Box<dyn Sender>) {
+    let sendstats = if args.is_present("sendstats") {
+        u16::from_str(args.value_of("sendstats").unwrap()).unwrap()
+    } else {
+        0
+    };
+    let aso = AudioSourceOptions {
+        stats: sendstats,
+        sample_size: usize::from_str(args.value_of("sample-size").unwrap()).unwrap(),
+        fft_size: usize::from_str(args.value_of("fft-size").unwrap()).unwrap(),
+        hop_size: usize::from_str(args.value_of("hop-size").unwrap()).unwrap(),
+        channels: usize::from_str(args.value_of("channels").unwrap()).unwrap(),
+    };
+    let mut effect = args.value_of("effect").unwrap().to_string();
+    if effect == "flatstack" {
+        effect = format!("flatstack:{}", args.value_of("value").unwrap());
+    }
+    let effect = EffectRegistry::default().create(&effect).unwrap();
+    let mut av = AudioVisualizer::new(src, effect, aso).unwrap();
+    av.senders.push(sender);
+    if let Some(path) = args.value_of("record") {
+        av.recorder = Some(Recorder::create(path).unwrap());
+    }
+    av.verbose = args.occurrences_of("verbose") as u8;
+    match args.value_of("midi") {
+        Some("jack") => {
+            #[cfg(feature = "jack")]
+            {
+                let name = format!("{}_midi", args.value_of("clientname").unwrap());
+                let client = jack::Client::new(&name, jack::ClientOptions::NO_START_SERVER)
+                    .unwrap()
+                    .0;
+                av.set_midi_source(InactiveMidiSource::activate(client).unwrap());
+            }
+            if !cfg!(feature = "jack") {
+                panic!("Jack support was not enabled at compile time.");
+            }
+        }
+        Some(path) => av.set_midi_source(MidiFile::new(path).activate().unwrap()),
+        None => (),
+    }
+    if args.is_present("midi-out") {
+        #[cfg(feature = "jack")]
+        {
+            let name = format!("{}_midi_out", args.value_of("clientname").unwrap());
+            let client = jack::Client::new(&name, jack::ClientOptions::NO_START_SERVER)
+                .unwrap()
+                .0;
+            av.midi_sinks.push(Box::new(
+                synesthesia::jack_midi::JackMidiSink::new(client).unwrap(),
+            ));
+        }
+        if !cfg!(feature = "jack") {
+            panic!("Jack support was not enabled at compile time.");
+        }
+    }
+    av.clock_sync = !args.is_present("no-clock-sync");
+    if let Some(presets) = args.values_of("preset") {
+        let registry = EffectRegistry::default();
+        av.set_presets(presets.map(|p| registry.create(p).unwrap()).collect());
+    }
+    if let Some(path) = args.value_of("midi-map") {
+        av.midi_map = MidiMap::load(path).unwrap();
+    }
+    if let Some(param) = args.value_of("midi-learn") {
+        av.midi_map.learn(Param::from_str(param).unwrap());
+    }
+    av.set_weighting(Weighting::from_str(args.value_of("weighting").unwrap()).unwrap());
+    av.set_spectrum_options(SpectrumOptions {
+        window: Window::from_str(args.value_of("window").unwrap()).unwrap(),
+        normalization: Normalization::from_str(args.value_of("normalize").unwrap()).unwrap(),
+        overlap: args
+            .value_of("overlap")
+            .map(|overlap| f32::from_str(overlap).unwrap() / 100.0),
+    });
     panic!("Audio processing failed: {:?}", av.process_loop())
 }
 
 fn parser<'a, 'b>() -> App<'a, 'b> {
+    let mut effect_help = "Sets the effect used to drive the lights:".to_string();
+    for (name, desc) in EffectRegistry::default().effects() {
+        effect_help += &format!("\n  {}: {}", name, desc);
+    }
+    effect_help += "\nALGORITHM is linear, quadratic, agc[,OPTIONS] or a response curve \
+                    (linear|quadratic|power|log|sigmoid|table)[,min=DB,max=DB,low=N,high=N,...]";
+    effect_help += "\nSMOOTHING is any of attack=S, decay=S, hold=S and fall=DB_PER_S, \
+                    each of which may be a list of values for each band.";
+    // the parser lives for the whole program, so leaking the help text is harmless
+    let effect_help: &'b str = Box::leak(effect_help.into_boxed_str());
     let default_mode = if cfg!(feature = "rpi") {
         "local"
     } else if cfg!(feature = "bluetooth") {
@@ -186,18 +341,180 @@ fn parser<'a, 'b>() -> App<'a, 'b> {
                 .short("s")
                 .long("src")
                 .value_name("SOURCE")
-                .possible_value("jack")
+                .possible_values(&["jack", "file", "stdin", "pipe", "gen", "net"])
                 .help("Sets the audio source")
                 .takes_value(true)
                 .default_value("jack"),
         )
+        .arg(
+            Arg::with_name("file")
+                .short("f")
+                .long("file")
+                .value_name("FILE")
+                .help(
+                    "Sets the WAV/FLAC file or named pipe to be used by the file and pipe sources",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fast")
+                .long("fast")
+                .help("Reads the audio file or generates the signal as fast as possible instead of in realtime"),
+        )
+        .arg(
+            Arg::with_name("signal")
+                .long("signal")
+                .value_name("SIGNAL")
+                .help("Sets the test signal of the gen source, e.g. sine:440, sweep:20:20000:10, white, pink, impulse:2 or silence")
+                .takes_value(true)
+                .validator(|s| Signal::from_str(&s).map(|_| ()))
+                .default_value("sweep"),
+        )
+        .arg(
+            Arg::with_name("signal-right")
+                .long("signal-right")
+                .value_name("SIGNAL")
+                .help("Sets a different test signal for the right channel of the gen source")
+                .takes_value(true)
+                .validator(|s| Signal::from_str(&s).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("listen")
+                .long("listen")
+                .value_name("ADDR")
+                .help("Sets the UDP address the net source listens on")
+                .takes_value(true)
+                .default_value("0.0.0.0:5004"),
+        )
+        .arg(
+            Arg::with_name("net-format")
+                .long("net-format")
+                .value_name("FORMAT")
+                .possible_values(&["framed", "rtp-l16", "rtp-l24"])
+                .help("Sets the packet format received by the net source")
+                .takes_value(true)
+                .default_value("framed"),
+        )
+        .arg(
+            Arg::with_name("latency")
+                .long("latency")
+                .value_name("MS")
+                .help("Sets the length of the net source's jitter buffer")
+                .takes_value(true)
+                .validator(|s| {
+                    u64::from_str(&s)
+                        .map_err(|e| format!("{:?}", e))
+                        .map(|_| ())
+                })
+                .default_value("40"),
+        )
+        .arg(
+            Arg::with_name("pcm-format")
+                .long("pcm-format")
+                .value_name("FORMAT")
+                .possible_values(&["s16le", "s32le", "f32le"])
+                .help("Sets the sample format of raw PCM read by the stdin and pipe sources")
+                .takes_value(true)
+                .default_value("s16le"),
+        )
+        .arg(
+            Arg::with_name("channels")
+                .long("channels")
+                .value_name("CHANNELS")
+                .help("Sets the number of interleaved channels of raw PCM and RTP streams, and the number of JACK ports")
+                .takes_value(true)
+                .validator(|s| {
+                    NonZeroU16::from_str(&s)
+                        .map(|_| ())
+                        .map_err(|e| format!("{:?}", e))
+                })
+                .default_value("2"),
+        )
+        .arg(
+            Arg::with_name("rate")
+                .long("rate")
+                .value_name("HZ")
+                .help("Sets the sample rate of raw PCM, RTP streams and generated test signals")
+                .takes_value(true)
+                .validator(|s| {
+                    u32::from_str(&s)
+                        .map_err(|e| format!("{:?}", e))
+                        .and_then(|r| {
+                            if r > 0 {
+                                Ok(())
+                            } else {
+                                Err("Rate must be non-zero.".to_string())
+                            }
+                        })
+                })
+                .default_value("48000"),
+        )
+        .arg(
+            Arg::with_name("effect")
+                .long("effect")
+                .value_name("NAME[:PARAMS]")
+                .help(effect_help)
+                .takes_value(true)
+                .validator(|s| EffectRegistry::default().create(&s).map(|_| ()))
+                .default_value("flatstack"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .value_name("FILE")
+                .help("Records the messages sent to the lights to FILE, as JSON lines if it ends in .jsonl.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("midi")
+                .long("midi")
+                .value_name("jack|FILE")
+                .help("Passes MIDI from a JACK MIDI port or a Standard MIDI File to the effect.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("midi-out")
+                .long("midi-out")
+                .help("Sends the band levels as MIDI CCs, onsets as notes and the tempo as MIDI clock to a JACK MIDI port."),
+        )
+        .arg(
+            Arg::with_name("no-clock-sync")
+                .long("no-clock-sync")
+                .help("Follows the tempo of the audio even when a MIDI clock is received."),
+        )
+        .arg(
+            Arg::with_name("preset")
+                .long("preset")
+                .value_name("NAME[:PARAMS]")
+                .help("Adds an effect that can be selected with the effect MIDI control, after the --effect effect.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| EffectRegistry::default().create(&s).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("midi-map")
+                .long("midi-map")
+                .value_name("FILE")
+                .help("Loads the MIDI controls from FILE, which is updated when a control is learned.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("midi-learn")
+                .long("midi-learn")
+                .value_name("PARAM")
+                .help("Binds the next MIDI control or program change received to PARAM.")
+                .possible_values(&["effect", "gain", "alg", "invert", "brightness", "attack", "decay"])
+                .requires("midi-map")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("value")
                 .short("a")
                 .long("alg")
                 .value_name("ALGORITHM")
-                .possible_values(&["linear", "quadratic"])
-                .help("Sets the algorithm used to scale the light bars.")
+                .validator(|s| Algorithm::from_str(&s).map(|_| ()))
+                .help("Sets the algorithm used to scale the light bars of the flatstack effect.")
                 .default_value("quadratic")
                 .takes_value(true),
         )
@@ -331,6 +648,87 @@ fn parser<'a, 'b>() -> App<'a, 'b> {
                 .takes_value(true)
                 .default_value("/org/bluez/hci0"),
         )
+        .arg(
+            Arg::with_name("sample-size")
+                .long("sample-size")
+                .value_name("FRAMES")
+                .help("Sets the number of audio frames analyzed for each update of the lights")
+                .takes_value(true)
+                .validator(|s| {
+                    NonZeroUsize::from_str(&s)
+                        .map(|_| ())
+                        .map_err(|e| format!("{:?}", e))
+                })
+                .default_value("768"),
+        )
+        .arg(
+            Arg::with_name("fft-size")
+                .long("fft-size")
+                .value_name("FRAMES")
+                .help("Sets the length of each FFT window, trading latency for frequency resolution")
+                .takes_value(true)
+                .validator(|s| {
+                    NonZeroUsize::from_str(&s)
+                        .map(|_| ())
+                        .map_err(|e| format!("{:?}", e))
+                })
+                .default_value("256"),
+        )
+        .arg(
+            Arg::with_name("hop-size")
+                .long("hop-size")
+                .value_name("FRAMES")
+                .help("Sets the number of frames between the start of successive FFT windows")
+                .takes_value(true)
+                .validator(|s| {
+                    NonZeroUsize::from_str(&s)
+                        .map(|_| ())
+                        .map_err(|e| format!("{:?}", e))
+                })
+                .default_value("256"),
+        )
+        .arg(
+            Arg::with_name("overlap")
+                .long("overlap")
+                .value_name("PERCENT")
+                .help("Overrides the hop size so successive FFT windows overlap by PERCENT")
+                .takes_value(true)
+                .validator(|s| {
+                    let overlap = f32::from_str(&s).map_err(|e| format!("{:?}", e))?;
+                    if 0.0 <= overlap && overlap < 100.0 {
+                        Ok(())
+                    } else {
+                        Err("Overlap must be between [0,100).".to_string())
+                    }
+                }),
+        )
+        .arg(
+            Arg::with_name("window")
+                .long("window")
+                .value_name("WINDOW")
+                .help("Sets the window function: rect, hann, hamming, blackman-harris, kaiser[:BETA] or flat-top")
+                .takes_value(true)
+                .validator(|s| Window::from_str(&s).map(|_| ()))
+                .default_value("rect"),
+        )
+        .arg(
+            Arg::with_name("normalize")
+                .long("normalize")
+                .value_name("MODE")
+                .possible_values(&["none", "coherent", "amplitude"])
+                .help("Sets how the spectrum is normalized for the window")
+                .takes_value(true)
+                .default_value("none"),
+        )
+        .arg(
+            Arg::with_name("weighting")
+                .long("weighting")
+                .value_name("WEIGHTING")
+                .help("Sets the frequency weighting: flatstack, a, c, z or iso226[:PHON]")
+                .takes_value(true)
+                .validator(|s| Weighting::from_str(&s).map(|_| ()))
+                .default_value("flatstack"),
+        )
         .arg(
             Arg::with_name("sendstats")
                 .long("sendstats")
@@ -343,4 +741,42 @@ fn parser<'a, 'b>() -> App<'a, 'b> {
                 })
                 .default_value("60"),
         )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Plays a recording made with --record using the sending options given before replay.")
+                .arg(
+                    Arg::with_name("recording")
+                        .value_name("FILE")
+                        .help("The recording to play, in either format.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("speed")
+                        .long("speed")
+                        .value_name("SPEED")
+                        .help("Sets how fast the recording is played, or 0 for as fast as possible.")
+                        .takes_value(true)
+                        .validator(|s| match f64::from_str(&s) {
+                            Ok(speed) if speed >= 0.0 => Ok(()),
+                            _ => Err(format!("Invalid speed: {}", s)),
+                        })
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::with_name("loop")
+                        .long("loop")
+                        .help("Starts again from the beginning at the end of the recording."),
+                )
+                .arg(
+                    Arg::with_name("seek")
+                        .long("seek")
+                        .value_name("SECS")
+                        .help("Starts playing SECS into the recording.")
+                        .takes_value(true)
+                        .validator(|s| match f64::from_str(&s) {
+                            Ok(secs) if secs >= 0.0 => Ok(()),
+                            _ => Err(format!("Invalid position: {}", s)),
+                        }),
+                ),
+        )
 }
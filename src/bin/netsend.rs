@@ -0,0 +1,190 @@
+use clap::{App, Arg, ArgMatches};
+use std::str::FromStr;
+use synesthesia::audio::{ActiveAudioSource, AudioSourceOptions, InactiveAudioSource};
+#[cfg(any(feature = "hound", feature = "claxon"))]
+use synesthesia::file_src::AudioFile;
+use synesthesia::gen_src::{Signal, SignalGenerator};
+use synesthesia::net_src::{NetFormat, NetSender};
+use synesthesia::pcm_src::{PcmFormat, PcmStream};
+
+#[cfg(feature = "jack")]
+use jack;
+
+pub fn main() {
+    let args = parser().get_matches();
+    let pcm = PcmFormat::from_str(args.value_of("pcm-format").unwrap()).unwrap();
+    let format = match args.value_of("net-format").unwrap() {
+        "framed" => NetFormat::Framed(pcm),
+        "rtp-l16" => NetFormat::RtpL16,
+        "rtp-l24" => NetFormat::RtpL24,
+        _ => unreachable!(),
+    };
+    let mut sender = NetSender::connect(
+        args.value_of("bind").unwrap(),
+        args.value_of("target").unwrap(),
+        format,
+    )
+    .unwrap();
+    sender.frames_per_packet = usize::from_str(args.value_of("frames").unwrap()).unwrap();
+    let rate = u32::from_str(args.value_of("rate").unwrap()).unwrap();
+    match args.value_of("source").unwrap() {
+        "jack" => {
+            #[cfg(feature = "jack")]
+            {
+                let src = jack::Client::new(
+                    args.value_of("clientname").unwrap(),
+                    jack::ClientOptions::NO_START_SERVER,
+                )
+                .unwrap()
+                .0;
+                stream(&args, src, sender)
+            }
+            if !cfg!(feature = "jack") {
+                panic!("Jack support was not enabled at compile time.");
+            }
+        }
+        "file" => {
+            #[cfg(any(feature = "hound", feature = "claxon"))]
+            {
+                let path = args
+                    .value_of("file")
+                    .expect("--file FILE is required for the file source!");
+                stream(&args, AudioFile::new(path), sender)
+            }
+            if !cfg!(any(feature = "hound", feature = "claxon")) {
+                panic!("Audio file support was not enabled at compile time.");
+            }
+        }
+        "stdin" => {
+            let channels = u16::from_str(args.value_of("channels").unwrap()).unwrap();
+            stream(&args, PcmStream::stdin(pcm, channels, rate), sender)
+        }
+        "gen" => {
+            let signal = Signal::from_str(args.value_of("signal").unwrap()).unwrap();
+            let mut src = SignalGenerator::new(signal, signal);
+            src.rate = rate;
+            stream(&args, src, sender)
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn stream<T: InactiveAudioSource>(args: &ArgMatches, src: T, mut sender: NetSender) {
+    let verbose = args.occurrences_of("verbose");
+    let mut active = src.activate(AudioSourceOptions::default()).unwrap();
+    loop {
+        let ss = match active.recv() {
+            Ok(ss) => ss,
+            Err(e) => panic!("Audio source failed: {:?}", e),
+        };
+        if verbose >= 2 {
+            eprintln!("Sending sample at {} us", ss.time());
+        }
+        sender.send(&ss).unwrap();
+    }
+}
+
+fn parser<'a, 'b>() -> App<'a, 'b> {
+    App::new("Net Send")
+        .version("0.1")
+        .author("Curtis Maves <curtismaves@gmail.com")
+        .about("Streams audio from a local source to a synesthesia net source")
+        .arg(
+            Arg::with_name("target")
+                .value_name("ADDR")
+                .help("Sets the address of the receiving net source")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("source")
+                .short("s")
+                .long("src")
+                .value_name("SOURCE")
+                .possible_values(&["jack", "file", "stdin", "gen"])
+                .help("Sets the audio source")
+                .takes_value(true)
+                .default_value("jack"),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .long("bind")
+                .value_name("ADDR")
+                .help("Sets the local UDP address to send from")
+                .takes_value(true)
+                .default_value("0.0.0.0:0"),
+        )
+        .arg(
+            Arg::with_name("net-format")
+                .long("net-format")
+                .value_name("FORMAT")
+                .possible_values(&["framed", "rtp-l16", "rtp-l24"])
+                .takes_value(true)
+                .default_value("framed"),
+        )
+        .arg(
+            Arg::with_name("frames")
+                .long("frames")
+                .value_name("FRAMES")
+                .help("Sets the maximum number of frames in each packet")
+                .takes_value(true)
+                .validator(|s| {
+                    usize::from_str(&s)
+                        .map_err(|e| format!("{:?}", e))
+                        .map(|_| ())
+                })
+                .default_value("256"),
+        )
+        .arg(
+            Arg::with_name("clientname")
+                .long("clientname")
+                .short("n")
+                .value_name("NAME")
+                .default_value("netsend")
+                .help("Sets the name to be used by the audio client")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("file")
+                .short("f")
+                .long("file")
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("signal")
+                .long("signal")
+                .value_name("SIGNAL")
+                .takes_value(true)
+                .validator(|s| Signal::from_str(&s).map(|_| ()))
+                .default_value("sweep"),
+        )
+        .arg(
+            Arg::with_name("pcm-format")
+                .long("pcm-format")
+                .value_name("FORMAT")
+                .possible_values(&["s16le", "s32le", "f32le"])
+                .help("Sets the sample format of stdin and of framed packets")
+                .takes_value(true)
+                .default_value("s16le"),
+        )
+        .arg(
+            Arg::with_name("channels")
+                .long("channels")
+                .value_name("CHANNELS")
+                .takes_value(true)
+                .default_value("2"),
+        )
+        .arg(
+            Arg::with_name("rate")
+                .long("rate")
+                .value_name("HZ")
+                .takes_value(true)
+                .default_value("48000"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .multiple(true),
+        )
+}
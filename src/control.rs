@@ -1,68 +1,406 @@
-use crate::audio::{ActiveAudioSource, AudioSourceOptions, InactiveAudioSource, WEIGHT};
+use crate::audio::{ActiveAudioSource, AudioSourceOptions, Downmix, InactiveAudioSource, Sample};
+use crate::beat::{BeatInfo, BeatOptions, BeatTracker};
+use crate::effect::Frame;
+pub use crate::effect::{Algorithm, Effect};
+use crate::midi::{ActiveMidiSource, MidiMessage, MidiSink};
+use crate::midi_clock::{ClockInfo, MidiClock};
+use crate::midi_map::{Binding, MidiMap, Param};
+use crate::midi_out::AnalysisMidi;
+use crate::record::Recorder;
+use crate::spectrum::{SpectrumAnalyzer, SpectrumOptions};
+use crate::weighting::Weighting;
 use crate::Error;
 use lecp::{Command, LedMsg, Sender};
-use rustfft::algorithm::Radix4;
-use std::cmp::Ordering;
+use std::fs::File;
+use std::io::BufWriter;
+use std::str::FromStr;
 
-#[derive(Clone, Copy)]
-pub enum Algorithm {
-    Linear,
-    Quadratic,
+/// The parts of an `ActiveMidiSource` used by the `AudioVisualizer`.
+trait MidiInput: Send {
+    fn try_recv(&mut self) -> Option<MidiMessage>;
+    fn cur_time(&self) -> u64;
 }
-#[derive(Clone, Copy)]
-pub enum Effect {
-    Stereo4FlatStack(Algorithm, bool),
+impl<M: ActiveMidiSource + Send> MidiInput for M {
+    #[inline]
+    fn try_recv(&mut self) -> Option<MidiMessage> {
+        ActiveMidiSource::try_recv(self)
+    }
+    #[inline]
+    fn cur_time(&self) -> u64 {
+        ActiveMidiSource::cur_time(self)
+    }
 }
+
 pub struct AudioVisualizer<T: ActiveAudioSource> {
     active: T,
     pub senders: Vec<Box<dyn Sender>>,
-    pub effect: Effect,
-    radix: Radix4<f32>,
+    /// Records the messages sent, timed by the audio they were made from.
+    pub recorder: Option<Recorder<BufWriter<File>>>,
+    pub effect: Box<dyn Effect>,
+    msgs: Vec<LedMsg>,
+    analyzer: SpectrumAnalyzer,
+    l_spec: Vec<f32>,
+    r_spec: Vec<f32>,
+    downmix: Option<Downmix>,
+    auto_downmix: Downmix,
+    mixed: Sample,
+    ch_specs: Vec<Vec<f32>>,
+    spare_spec: Vec<f32>,
+    options: AudioSourceOptions,
+    spectrum: SpectrumOptions,
+    weighting: Weighting,
+    weights: Vec<f32>,
+    weights_rate: u32,
+    beat: BeatTracker,
+    midi: Option<Box<dyn MidiInput>>,
+    midi_msgs: Vec<MidiMessage>,
+    clock: MidiClock,
+    /// Receive the analysis of each frame as MIDI from `midi_out`.
+    pub midi_sinks: Vec<Box<dyn MidiSink>>,
+    pub midi_out: AnalysisMidi,
+    out_msgs: Vec<MidiMessage>,
+    /// Use the tempo and beat of a running MIDI clock instead of those estimated from the audio.
+    pub clock_sync: bool,
+    /// The controls bound to the MIDI messages received.
+    pub midi_map: MidiMap,
+    presets: Vec<Option<Box<dyn Effect>>>,
+    preset: Option<usize>,
+    /// Added to the spectra, in dB.
+    pub gain: f32,
+    /// Scales the brightness of every message, from 0.0 to 1.0.
+    pub brightness: f32,
     pub verbose: u8,
+    /// The overruns of the source when they were last reported.
+    overruns: usize,
 }
 impl<T: ActiveAudioSource> AudioVisualizer<T> {
     #[inline]
-    pub fn new<I>(inactive: I, effect: Effect, options: AudioSourceOptions) -> Result<Self, Error>
+    pub fn new<I>(
+        inactive: I,
+        effect: Box<dyn Effect>,
+        options: AudioSourceOptions,
+    ) -> Result<Self, Error>
     where
         I: InactiveAudioSource<ActiveType = T>,
     {
+        options.validate()?;
+        let fft_size = options.fft_size;
+        if fft_size < 8 {
+            return Err(Error::Unrecoverable(
+                "FFT size must be at least 8".to_string(),
+            ));
+        }
+        let spectrum = SpectrumOptions::default();
+        let analyzer = SpectrumAnalyzer::new(fft_size, options.hop_size, &spectrum);
         let active = inactive.activate(options)?;
         Ok(AudioVisualizer {
             active,
             effect,
+            msgs: Vec::new(),
             senders: Vec::new(),
-            radix: Radix4::new(256, false),
+            recorder: None,
+            l_spec: vec![0.0; analyzer.bins()],
+            r_spec: vec![0.0; analyzer.bins()],
+            downmix: None,
+            auto_downmix: Downmix::for_channels(2),
+            mixed: Sample::new(options.sample_size, 0, 0),
+            ch_specs: Vec::new(),
+            spare_spec: vec![0.0; analyzer.bins()],
+            analyzer,
+            options,
+            spectrum,
+            weighting: Weighting::default(),
+            weights: Vec::new(),
+            weights_rate: 0,
+            beat: BeatTracker::new(BeatOptions::default())?,
+            midi: None,
+            midi_msgs: Vec::new(),
+            clock: MidiClock::new(),
+            midi_sinks: Vec::new(),
+            midi_out: AnalysisMidi::default(),
+            out_msgs: Vec::new(),
+            clock_sync: true,
+            midi_map: MidiMap::new(),
+            presets: Vec::new(),
+            preset: None,
+            gain: 0.0,
+            brightness: 1.0,
             verbose: 0,
+            overruns: 0,
         })
     }
+    #[inline]
+    pub fn options(&self) -> &AudioSourceOptions {
+        &self.options
+    }
+    #[inline]
+    pub fn spectrum_options(&self) -> SpectrumOptions {
+        self.spectrum
+    }
+    pub fn set_spectrum_options(&mut self, spectrum: SpectrumOptions) {
+        self.spectrum = spectrum;
+        self.analyzer.set_options(&spectrum);
+    }
+    #[inline]
+    pub fn weighting(&self) -> Weighting {
+        self.weighting
+    }
+    pub fn set_weighting(&mut self, weighting: Weighting) {
+        self.weighting = weighting;
+        self.weights_rate = 0; // force the weights to be recomputed
+    }
+    /// The downmix used for samples with more than two channels,
+    /// or `None` if the usual downmix for their channel count is used.
+    #[inline]
+    pub fn downmix(&self) -> Option<&Downmix> {
+        self.downmix.as_ref()
+    }
+    /// Sets the downmix used to turn samples into the left and right spectra passed to the effect.
+    /// A downmix that is set is also applied to mono and stereo samples.
+    pub fn set_downmix(&mut self, downmix: Option<Downmix>) {
+        self.downmix = downmix;
+    }
+    /// The onsets, beat phase and tempo of the most recently processed sample.
+    #[inline]
+    pub fn beat(&self) -> BeatInfo {
+        self.beat.info()
+    }
+    #[inline]
+    pub fn beat_options(&self) -> &BeatOptions {
+        self.beat.options()
+    }
+    pub fn set_beat_options(&mut self, options: BeatOptions) -> Result<(), Error> {
+        self.beat.set_options(options)
+    }
+    /// The state of the MIDI clock received from the MIDI source.
+    #[inline]
+    pub fn clock(&self) -> ClockInfo {
+        self.clock.info()
+    }
+    /// Passes the messages from `midi` to the effect with each frame.
+    pub fn set_midi_source<M>(&mut self, midi: M)
+    where
+        M: ActiveMidiSource + Send + 'static,
+    {
+        self.midi = Some(Box::new(midi));
+    }
+    pub fn remove_midi_source(&mut self) {
+        self.midi = None;
+    }
+    /// Sets the effects that `Param::Effect` selects between.
+    /// The current effect is kept as preset 0, followed by `presets`.
+    pub fn set_presets(&mut self, presets: Vec<Box<dyn Effect>>) {
+        self.presets = std::iter::once(None)
+            .chain(presets.into_iter().map(Some))
+            .collect();
+        self.preset = Some(0);
+    }
+    /// Switches to preset `index`. The effect replaced is kept if it was a preset.
+    pub fn select_preset(&mut self, index: usize) {
+        if self.preset == Some(index) {
+            return;
+        }
+        let effect = match self.presets.get_mut(index).and_then(Option::take) {
+            Some(effect) => effect,
+            None => return,
+        };
+        let old = std::mem::replace(&mut self.effect, effect);
+        if let Some(prev) = self.preset {
+            self.presets[prev] = Some(old);
+        }
+        self.preset = Some(index);
+    }
+    /// Sets `param` from a MIDI `value` received through `binding`.
+    fn apply_control(&mut self, param: Param, binding: Binding, value: u8) {
+        // the algorithms that `Param::Algorithm` selects between
+        const ALGORITHMS: [&str; 5] = ["linear", "quadratic", "log", "sigmoid", "agc"];
+        let result = match param {
+            Param::Effect => {
+                self.select_preset(binding.select(value, self.presets.len()));
+                Ok(())
+            }
+            Param::Gain => {
+                self.gain = (value as f32 - 64.0) * 0.5;
+                Ok(())
+            }
+            Param::Algorithm => {
+                let alg = ALGORITHMS[binding.select(value, ALGORITHMS.len())];
+                self.effect.set_param("alg", alg)
+            }
+            Param::Invert => self.effect.set_param("invert", &(value >= 64).to_string()),
+            Param::Brightness => {
+                self.brightness = value as f32 / 127.0;
+                Ok(())
+            }
+            Param::Attack | Param::Decay => {
+                let secs = (value as f32 / 127.0).powi(2) * 2.0;
+                self.effect.set_param(param.name(), &secs.to_string())
+            }
+        };
+        match result {
+            Ok(()) if self.verbose >= 2 => eprintln!("MIDI set {} to {}", param, value),
+            Ok(()) => (),
+            Err(e) if self.verbose >= 1 => eprintln!("MIDI could not set {}: {}", param, e),
+            Err(_) => (),
+        }
+    }
     pub fn process(&mut self) -> Result<(), Error> {
-        let ss = if let Some(ss) = self.active.try_iter().last() {
-            ss
+        let mut ss = self.active.recv()?;
+        // skip to the most recent sample, giving the older ones back to the source
+        while let Ok(newer) = self.active.try_recv() {
+            self.active.recycle(std::mem::replace(&mut ss, newer));
+        }
+        let overruns = self.active.overruns();
+        if overruns != self.overruns {
+            if self.verbose >= 1 {
+                let new = overruns.wrapping_sub(self.overruns);
+                eprintln!("Audio overrun: {} samples dropped, {} total", new, overruns);
+            }
+            self.overruns = overruns;
+        }
+        if self.senders.len() == 0 && self.recorder.is_none() {
+            self.active.recycle(ss);
+            return Ok(());
+        }
+        self.midi_msgs.clear();
+        if let Some(midi) = &mut self.midi {
+            while let Some(msg) = midi.try_recv() {
+                self.midi_msgs.push(msg);
+            }
+        }
+        for i in 0..self.midi_msgs.len() {
+            self.clock.process(&self.midi_msgs[i]);
+            if let Some((param, binding, value)) = self.midi_map.handle(&self.midi_msgs[i].event) {
+                self.apply_control(param, binding, value);
+            }
+        }
+        if ss.rate() != self.weights_rate {
+            self.weights = self.weighting.weights(self.options.fft_size, ss.rate());
+            self.weights_rate = ss.rate();
+        }
+        let channels = ss.channels();
+        let downmix = match &self.downmix {
+            Some(downmix) => Some(downmix),
+            None if channels > 2 => {
+                if self.auto_downmix.gains().len() != channels {
+                    self.auto_downmix = Downmix::for_channels(channels);
+                }
+                Some(&self.auto_downmix)
+            }
+            None => None,
+        };
+        match downmix {
+            Some(downmix) => {
+                downmix.apply(&ss, &mut self.mixed);
+                let mixed = &self.mixed;
+                mixed.spectrogram(&mut self.analyzer, &mut self.l_spec, &mut self.r_spec);
+            }
+            None => ss.spectrogram(&mut self.analyzer, &mut self.l_spec, &mut self.r_spec),
+        }
+        let wants_channels = self.effect.wants_channels();
+        if wants_channels {
+            self.channel_spectra(&ss);
+        }
+        let mut beat = self.beat.process(&self.l_spec, &self.r_spec, ss.time());
+        // the clock is timed by the MIDI source, which may not share the clock of the audio
+        let midi_time = self.midi.as_ref().map(|midi| midi.cur_time());
+        let clock = self.clock.take_beat(midi_time.unwrap_or_else(|| ss.time()));
+        if self.clock_sync && clock.running && clock.bpm > 0.0 {
+            beat.beat = clock.beat;
+            beat.phase = clock.phase;
+            beat.bpm = clock.bpm;
+            beat.confidence = 1.0;
+        }
+        self.active.recycle(ss);
+        if self.verbose >= 3 && beat.onset {
+            eprintln!(
+                "Onset: flux {:.1} dB, {:.1} bpm ({:.0}% confidence)",
+                beat.flux,
+                beat.bpm,
+                beat.confidence * 100.0
+            );
+        }
+        // apply weightings and gain to the spectra, which are already averaged over each window
+        let gain = self.gain;
+        for (s, w) in self.l_spec.iter_mut().zip(self.weights.iter()) {
+            *s += w + gain;
+        }
+        for (s, w) in self.r_spec.iter_mut().zip(self.weights.iter()) {
+            *s += w + gain;
+        }
+        let ch_specs = if wants_channels {
+            &mut self.ch_specs[..channels]
         } else {
-            self.active.recv()?
+            &mut []
         };
-        if self.senders.len() == 0 {
-            return Ok(());
+        for spec in ch_specs.iter_mut() {
+            for (s, w) in spec.iter_mut().zip(self.weights.iter()) {
+                *s += w + gain;
+            }
+        }
+        let frame = Frame {
+            left: &self.l_spec,
+            right: &self.r_spec,
+            channels: ch_specs,
+            rate: self.weights_rate,
+            fft_size: self.options.fft_size,
+            time: beat.time,
+            beat,
+            midi: &self.midi_msgs,
+        };
+        if !self.midi_sinks.is_empty() {
+            self.out_msgs.clear();
+            self.midi_out.process(&frame, &mut self.out_msgs);
+            let bpm = self.midi_out.tempo(&frame);
+            for sink in self.midi_sinks.iter_mut() {
+                sink.set_tempo(bpm);
+                sink.send(&self.out_msgs)?;
+            }
         }
-        let (left, right) = ss.spectrogram(&self.radix);
-        let mut msgs;
-        match self.effect {
-            Effect::Stereo4FlatStack(alg, invert) => {
-                msgs = self.process_s4fs(left, right, alg, invert)
+        let time = frame.time;
+        self.msgs.clear();
+        self.effect.process(&frame, &mut self.msgs);
+        if self.brightness < 1.0 {
+            for msg in self.msgs.iter_mut() {
+                if let Command::FlatStack(v) = &mut msg.cmd {
+                    *v = (*v as f32 * self.brightness).round() as u8;
+                }
             }
         }
         if cfg!(debug_assertions) && self.verbose >= 4 {
-            eprintln!("Messages to be send: {:?}", msgs);
+            eprintln!("Messages to be send: {:?}", self.msgs);
         }
         for sender in self.senders.iter_mut() {
             let cur_time = sender.get_time();
-            for msg in msgs.iter_mut() {
+            for msg in self.msgs.iter_mut() {
                 msg.time = cur_time;
             }
-            sender.send(&msgs)?;
+            sender.send(&self.msgs)?;
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.send_at(time, &self.msgs)?;
         }
         Ok(())
     }
+    /// Computes the spectrum of every channel of `ss` into `ch_specs`, two channels at a time.
+    fn channel_spectra(&mut self, ss: &Sample) {
+        let channels = ss.channels();
+        let bins = self.analyzer.bins();
+        if self.ch_specs.len() < channels {
+            self.ch_specs.resize(channels, vec![0.0; bins]);
+        }
+        for a in (0..channels).step_by(2) {
+            let b = (a + 1).min(channels - 1);
+            let (lo, hi) = self.ch_specs.split_at_mut(a + 1);
+            let out_b = if b > a {
+                &mut hi[0]
+            } else {
+                &mut self.spare_spec
+            };
+            self.analyzer
+                .process_pair(ss.channel(a), ss.channel(b), &mut lo[a], out_b);
+        }
+    }
     #[inline]
     pub fn process_loop(&mut self) -> Error {
         loop {
@@ -71,89 +409,323 @@ impl<T: ActiveAudioSource> AudioVisualizer<T> {
             }
         }
     }
+}
+
+/// Time constants for smoothing a level over time. Times are in seconds and levels are in dB.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SmoothingOptions {
+    /// The time constant used while the level is rising. Zero follows the level immediately.
+    pub attack: f32,
+    /// The time constant used while the level is falling.
+    pub decay: f32,
+    /// How long the peak is held before it starts to fall.
+    pub hold: f32,
+    /// How quickly the peak falls once it is no longer held, in dB per second.
+    pub fall: f32,
+}
+impl Default for SmoothingOptions {
+    /// No smoothing, with peaks held for half a second.
+    fn default() -> Self {
+        SmoothingOptions {
+            attack: 0.0,
+            decay: 0.0,
+            hold: 0.5,
+            fall: 20.0,
+        }
+    }
+}
 
-    fn process_s4fs(
-        &mut self,
-        left: Vec<f32>,
-        right: Vec<f32>,
-        alg: Algorithm,
-        invert: bool,
-    ) -> [LedMsg; 9] {
-        let n_windows = left.len() / 256;
-        let n_win = left.len() / 256;
-        // average channels
-        let mut l_avg = [0.0; 256];
-        let mut r_avg = [0.0; 256];
-        for i in 0..256 {
-            let mut l_sum = 0.0;
-            let mut r_sum = 0.0;
-            for n in 0..n_windows {
-                l_sum += left[i + n * 256];
-                r_sum += right[i + n * 256];
-            }
-            // average and apply weightins
-            l_avg[i] = (l_sum / n_win as f32) + WEIGHT[i];
-            r_avg[i] = (r_sum / n_win as f32) + WEIGHT[i];
-        }
-        let f32_max = |s: &[f32]| {
-            *s.iter()
-                .max_by(|l, r| l.partial_cmp(r).unwrap_or(Ordering::Equal))
-                .unwrap()
+/// Smooths levels over time and tracks their peaks, so that effects do not flicker.
+///
+/// Time is taken from the timestamps of the samples, so the behaviour does not depend
+/// on how often levels are processed. Each level can have its own `SmoothingOptions`.
+#[derive(Clone, Debug)]
+pub struct Smoother {
+    options: Vec<SmoothingOptions>,
+    levels: Vec<f32>,
+    peaks: Vec<f32>,
+    peak_times: Vec<u64>,
+    time: Option<u64>,
+}
+impl Default for Smoother {
+    fn default() -> Self {
+        Self::new(SmoothingOptions::default())
+    }
+}
+impl Smoother {
+    pub fn new(options: SmoothingOptions) -> Self {
+        Smoother {
+            options: vec![options],
+            levels: Vec::new(),
+            peaks: Vec::new(),
+            peak_times: Vec::new(),
+            time: None,
+        }
+    }
+    /// The options used for the level at `index`.
+    ///
+    /// The options repeat when there are more levels than options, so that
+    /// one set applies to every level and `n` sets apply to both channels of `n` bands.
+    #[inline]
+    pub fn options(&self, index: usize) -> &SmoothingOptions {
+        &self.options[index % self.options.len()]
+    }
+    pub fn set_options(&mut self, options: Vec<SmoothingOptions>) {
+        assert!(!options.is_empty());
+        self.options = options;
+    }
+    /// Sets one of `attack`, `decay`, `hold` or `fall` from a `,` separated list of values,
+    /// which repeat like the options. Returns false if `key` is not one of them.
+    pub fn set_param(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        let field: fn(&mut SmoothingOptions) -> &mut f32 = match key {
+            "attack" => |o| &mut o.attack,
+            "decay" => |o| &mut o.decay,
+            "hold" => |o| &mut o.hold,
+            "fall" => |o| &mut o.fall,
+            _ => return Ok(false),
         };
-        let mut l_bins = [0.0; 4];
-        l_bins[0] = f32_max(&l_avg[1..3]); // Subwoofer
-        l_bins[1] = f32_max(&l_avg[3..6]); // Woofer
-        l_bins[2] = f32_max(&l_avg[6..21]); // Midrange
-        l_bins[3] = f32_max(&l_avg[21..256]); // Tweeter
-
-        let mut r_bins = [0.0; 4];
-        r_bins[0] = f32_max(&r_avg[1..3]);
-        r_bins[1] = f32_max(&r_avg[3..6]);
-        r_bins[2] = f32_max(&r_avg[6..21]);
-        r_bins[3] = f32_max(&r_avg[21..256]);
-
-        if invert {
-            std::mem::swap(&mut l_bins, &mut r_bins);
-        }
-
-        let mut left = [LedMsg::default(); 4];
-        let mut right = [LedMsg::default(); 4];
-        let left_i = left.iter_mut().zip(l_bins.iter());
-        let right_i = right.iter_mut().zip(r_bins.iter()).rev();
-        let iter = left_i.chain(right_i);
-        let mut sum = 0;
-        // scale to range of [0, 32] u8 and keep track of total sum
-        //eprintln!("l_bin: {:?}\nr_bin: {:?}", l_bins, r_bins);
-        match alg {
-            Algorithm::Linear => {
-                for (r, f) in iter {
-                    let val = ((f + 35.0) * 2.0 * 0.31).min(31.0).max(0.0).round() as u8;
-                    sum += val + 1;
-                    r.cmd = Command::FlatStack(val);
-                }
+        let values = value
+            .split(',')
+            .map(|v| {
+                f32::from_str(v)
+                    .map_err(|e| format!("Invalid {} {}: {:?}", key, v, e))
+                    .and_then(|v| {
+                        if v >= 0.0 {
+                            Ok(v)
+                        } else {
+                            Err(format!("{} cannot be negative", key))
+                        }
+                    })
+            })
+            .collect::<Result<Vec<f32>, String>>()?;
+        let len = self.options.len().max(values.len());
+        self.options = (0..len)
+            .map(|i| {
+                let mut o = *self.options(i);
+                *field(&mut o) = values[i % values.len()];
+                o
+            })
+            .collect();
+        Ok(true)
+    }
+    /// The held peak of each level, in dB, as of the last call to `process()`.
+    #[inline]
+    pub fn peaks(&self) -> &[f32] {
+        &self.peaks
+    }
+    /// Forget the previous levels and peaks.
+    pub fn reset(&mut self) {
+        self.levels.clear();
+        self.peaks.clear();
+        self.peak_times.clear();
+        self.time = None;
+    }
+    /// Smooths `levels`, in dB, measured at `time`, in microseconds, in place.
+    pub fn process(&mut self, levels: &mut [f32], time: u64) {
+        if self.levels.len() != levels.len() {
+            self.reset();
+        }
+        let dt = match self.time {
+            Some(prev) => time.saturating_sub(prev) as f32 / 1_000_000.0,
+            None => {
+                self.levels.extend_from_slice(levels);
+                self.peaks.extend_from_slice(levels);
+                self.peak_times.resize(levels.len(), time);
+                self.time = Some(time);
+                return;
             }
-            Algorithm::Quadratic => {
-                for (r, f) in iter {
-                    let val = ((f + 35.0) / 5.0).max(0.0);
-                    let val = (val * val * 0.31).min(31.0).round() as u8;
-                    sum += val + 1;
-                    r.cmd = Command::FlatStack(val);
-                }
+        };
+        self.time = Some(time);
+        for (i, level) in levels.iter_mut().enumerate() {
+            let options = self.options[i % self.options.len()];
+            let prev = self.levels[i];
+            let constant = if *level > prev {
+                options.attack
+            } else {
+                options.decay
+            };
+            if constant > 0.0 && prev.is_finite() {
+                *level = prev + (*level - prev) * (1.0 - (-dt / constant).exp());
             }
+            self.levels[i] = *level;
+
+            let peak = &mut self.peaks[i];
+            if *level >= *peak || !peak.is_finite() {
+                *peak = *level;
+                self.peak_times[i] = time;
+            } else if time.saturating_sub(self.peak_times[i]) > (options.hold * 1e6) as u64 {
+                *peak = (*peak - options.fall * dt).max(*level);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beat::BeatInfo;
+    use crate::gen_src::{Signal, SignalGenerator};
+    use crate::midi::InactiveMidiSource;
+    use crate::smf_src::MidiFile;
+    use std::sync::{Arc, Mutex};
+
+    /// An effect that lights a single element, identifying which effect is running.
+    struct Tag(u8);
+    impl Effect for Tag {
+        fn process(&mut self, _frame: &Frame, msgs: &mut Vec<LedMsg>) {
+            msgs.push(LedMsg {
+                element: self.0,
+                ..LedMsg::default()
+            });
+        }
+    }
+
+    /// Collects the beat of every frame.
+    struct Beats(Arc<Mutex<Vec<BeatInfo>>>);
+    impl Effect for Beats {
+        fn process(&mut self, frame: &Frame, _msgs: &mut Vec<LedMsg>) {
+            self.0.lock().unwrap().push(frame.beat);
+        }
+    }
+
+    /// Collects the elements of the messages sent.
+    struct Elements(Arc<Mutex<Vec<u8>>>);
+    impl Sender for Elements {
+        fn send(&mut self, msgs: &[LedMsg]) -> Result<(), lecp::Error> {
+            let mut elements = self.0.lock().unwrap();
+            elements.extend(msgs.iter().map(|m| m.element));
+            Ok(())
+        }
+        fn get_time(&self) -> u16 {
+            0
+        }
+    }
+
+    #[test]
+    fn presets_keep_the_original_effect() {
+        let mut gen = SignalGenerator::new(Signal::Silence, Signal::Silence);
+        gen.realtime = false;
+        let options = AudioSourceOptions::default();
+        let mut av = AudioVisualizer::new(gen, Box::new(Tag(0)), options).unwrap();
+        let elements = Arc::new(Mutex::new(Vec::new()));
+        av.senders.push(Box::new(Elements(elements.clone())));
+        av.set_presets(vec![Box::new(Tag(1)), Box::new(Tag(2))]);
+        for &index in &[0, 2, 1, 0, 3, 2] {
+            av.select_preset(index);
+            av.process().unwrap();
+        }
+        assert_eq!(*elements.lock().unwrap(), [0, 2, 1, 0, 0, 2]);
+    }
+
+    #[test]
+    fn smoother_time_constants() {
+        let mut smoother = Smoother::default();
+        smoother.set_param("attack", "0,0.1").unwrap();
+        smoother.set_param("decay", "1").unwrap();
+        assert_eq!(smoother.options(0).attack, 0.0);
+        assert_eq!(smoother.options(1).attack, 0.1);
+        assert_eq!(smoother.options(3).decay, 1.0);
+        assert!(!smoother.set_param("gain", "1").unwrap());
+        assert!(smoother.set_param("hold", "-1").is_err());
+
+        let mut levels = [-40.0; 2];
+        smoother.process(&mut levels, 0);
+        assert_eq!(levels, [-40.0; 2]);
+        // one time constant moves 63% of the way, whatever the frame rate
+        for i in 1..=10 {
+            levels = [0.0; 2];
+            smoother.process(&mut levels, i * 10_000);
         }
-        let mut ret = [LedMsg::default(); 9];
-        ret[0..4].copy_from_slice(&left);
-        ret[5..9].copy_from_slice(&right);
-        ret[4].cmd = Command::FlatStack(255 - sum);
-        for (i, r) in ret.iter_mut().enumerate() {
-            r.element = i as u8;
+        assert_eq!(levels[0], 0.0);
+        let expected = -40.0 * (-1.0_f32).exp();
+        assert!((levels[1] - expected).abs() < 0.01, "{:?}", levels);
+        levels = [-40.0; 2];
+        smoother.process(&mut levels, 1_100_000);
+        let expected = -40.0 * (1.0 - (-1.0_f32).exp());
+        assert!((levels[0] - expected).abs() < 0.01, "{:?}", levels);
+    }
+
+    #[test]
+    fn smoother_holds_peaks() {
+        let mut smoother = Smoother::default();
+        let mut levels = [0.0];
+        smoother.process(&mut levels, 0);
+        for i in 1..=5 {
+            levels = [-60.0];
+            smoother.process(&mut levels, i * 100_000);
+            assert_eq!(smoother.peaks(), &[0.0]);
+        }
+        // after the half second hold the peak falls at 20 dB per second
+        levels = [-60.0];
+        smoother.process(&mut levels, 600_000);
+        assert!((smoother.peaks()[0] + 2.0).abs() < 0.001);
+        levels = [-60.0];
+        smoother.process(&mut levels, 5_000_000);
+        assert_eq!(smoother.peaks(), &[-60.0]);
+        // and a new peak is held again
+        levels = [-10.0];
+        smoother.process(&mut levels, 5_100_000);
+        assert_eq!(smoother.peaks(), &[-10.0]);
+    }
+
+    #[test]
+    fn smoother_resets_on_new_levels() {
+        let mut smoother = Smoother::new(SmoothingOptions {
+            attack: 1.0,
+            ..SmoothingOptions::default()
+        });
+        let mut levels = [f32::NEG_INFINITY];
+        smoother.process(&mut levels, 0);
+        // silence is left immediately rather than smoothed from minus infinity
+        levels = [-20.0];
+        smoother.process(&mut levels, 10_000);
+        assert_eq!(levels, [-20.0]);
+        let mut more = [-30.0, -30.0];
+        smoother.process(&mut more, 20_000);
+        assert_eq!(more, [-30.0, -30.0]);
+        assert_eq!(smoother.peaks(), &[-30.0, -30.0]);
+    }
+
+    #[test]
+    fn clock_sync_with_a_midi_file() {
+        // a start and two beats of clock at 120 bpm, with a tick per clock
+        let mut track = vec![0, 0xF7, 1, 0xFA];
+        for _ in 0..48 {
+            track.extend_from_slice(&[1, 0xF7, 1, 0xF8]);
+        }
+        track.extend_from_slice(&[0, 0xFF, 0x2F, 0]);
+        let mut bytes = b"MThd\0\0\0\x06\0\0\0\x01\0\x18MTrk".to_vec();
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&track);
+        let path =
+            std::env::temp_dir().join(format!("synesthesia-{}-clock.mid", std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        let mut file = MidiFile::new(&path);
+        file.speed = 0.0;
+        let midi = file.activate().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut gen = SignalGenerator::new(Signal::Silence, Signal::Silence);
+        gen.realtime = false;
+        let options = AudioSourceOptions::default();
+        let beats = Arc::new(Mutex::new(Vec::new()));
+        let mut av = AudioVisualizer::new(gen, Box::new(Beats(beats.clone())), options).unwrap();
+        // the audio is five seconds in, long after the last clock in the file
+        while av.active.cur_time() < 5_000_000 {
+            av.process().unwrap();
         }
-        for (i, r) in ret[0..4].iter_mut().enumerate() {
-            r.color = i as u8 + 1;
+        av.senders
+            .push(Box::new(Elements(Arc::new(Mutex::new(Vec::new())))));
+        av.set_midi_source(midi);
+        for _ in 0..3 {
+            av.process().unwrap();
         }
-        for (i, r) in ret[5..9].iter_mut().rev().enumerate() {
-            r.color = i as u8 + 1;
+        let beats = beats.lock().unwrap();
+        assert_eq!(beats.len(), 3);
+        for beat in beats.iter() {
+            assert!((beat.bpm - 120.0).abs() < 0.1, "{:?}", beat);
+            assert_eq!(beat.confidence, 1.0);
         }
-        ret
+        assert!(beats[0].beat && !beats[1].beat);
     }
 }
@@ -0,0 +1,269 @@
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/// The shape of a response curve, which maps a level from 0.0 at the bottom of the input
+/// range to 1.0 at the top onto the same range of output.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shape {
+    /// Raises the level to the exponent. An exponent of 1.0 is linear and 2.0 is quadratic.
+    Power(f32),
+    /// Brightens quiet levels. Larger scales bend the curve further.
+    Log(f32),
+    /// An S curve with the given steepness centered on the given level.
+    Sigmoid { steepness: f32, center: f32 },
+    /// Interpolates linearly between `(input, output)` points sorted by input.
+    Table(Vec<(f32, f32)>),
+}
+impl Shape {
+    /// Loads a table from lines of whitespace separated `INPUT OUTPUT` pairs,
+    /// both from 0.0 to 1.0. Blank lines and lines starting with `#` are ignored.
+    pub fn parse_table(s: &str) -> Result<Self, String> {
+        let mut points = Vec::new();
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values = line
+                .split_whitespace()
+                .map(|v| f32::from_str(v).map_err(|e| format!("Invalid point {}: {:?}", line, e)))
+                .collect::<Result<Vec<f32>, String>>()?;
+            match values[..] {
+                [x, y] => points.push((x, y)),
+                _ => return Err(format!("Expected INPUT OUTPUT, found: {}", line)),
+            }
+        }
+        if points.is_empty() {
+            return Err("The response table is empty".to_string());
+        }
+        if points
+            .windows(2)
+            .any(|w| w[1].0.partial_cmp(&w[0].0) != Some(Ordering::Greater))
+        {
+            return Err("The inputs of the response table must be increasing".to_string());
+        }
+        Ok(Shape::Table(points))
+    }
+    /// Maps `x` in [0.0, 1.0] onto the curve.
+    pub fn apply(&self, x: f32) -> f32 {
+        match self {
+            Shape::Power(exp) => x.powf(*exp),
+            Shape::Log(scale) => (1.0 + scale * x).ln() / (1.0 + scale).ln(),
+            Shape::Sigmoid { steepness, center } => {
+                let f = |x: f32| 1.0 / (1.0 + (-steepness * (x - center)).exp());
+                let (lo, hi) = (f(0.0), f(1.0));
+                (f(x) - lo) / (hi - lo)
+            }
+            Shape::Table(points) => {
+                let i = points.iter().position(|(px, _)| *px > x);
+                match i {
+                    Some(0) => points[0].1,
+                    Some(i) => {
+                        let ((x0, y0), (x1, y1)) = (points[i - 1], points[i]);
+                        y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+                    }
+                    None => points[points.len() - 1].1,
+                }
+            }
+        }
+    }
+}
+
+/// A response curve that maps levels in dB to the brightness of a fixture.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Curve {
+    pub shape: Shape,
+    /// The levels, in dB, shown as `low` and `high`.
+    pub min: f32,
+    pub max: f32,
+    /// The range of the output, up to 31.
+    pub low: u8,
+    pub high: u8,
+}
+impl Curve {
+    /// A curve over the range of levels used by `Algorithm::Linear` and `Algorithm::Quadratic`.
+    pub fn new(shape: Shape) -> Self {
+        Curve {
+            shape,
+            min: -35.0,
+            max: 15.0,
+            low: 0,
+            high: 31,
+        }
+    }
+    /// Maps `db` onto the output range.
+    pub fn apply(&self, db: f32) -> u8 {
+        let x = ((db - self.min) / (self.max - self.min)).min(1.0).max(0.0);
+        let y = self.shape.apply(x).min(1.0).max(0.0);
+        (self.low as f32 + y * (self.high - self.low) as f32).round() as u8
+    }
+}
+impl FromStr for Curve {
+    type Err = String;
+    /// Parses `SHAPE[,KEY=VALUE...]`, where the shape is `linear`, `quadratic`, `power`,
+    /// `log`, `sigmoid` or `table`.
+    ///
+    /// Every shape accepts `min` and `max`, the input range in dB, and `low` and `high`,
+    /// the output range. `power` takes `exp`, `log` takes `scale`, `sigmoid` takes
+    /// `steepness` and `center`, and `table` requires `file`, the path of a table
+    /// in the format read by `Shape::parse_table()`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut params = s.split(',');
+        let name = params.next().unwrap();
+        let mut curve = Curve::new(match name {
+            "linear" => Shape::Power(1.0),
+            "quadratic" | "power" => Shape::Power(2.0),
+            "log" => Shape::Log(9.0),
+            "sigmoid" => Shape::Sigmoid {
+                steepness: 10.0,
+                center: 0.5,
+            },
+            "table" => Shape::Table(Vec::new()),
+            _ => return Err(format!("Unknown response curve: {}", name)),
+        });
+        for param in params.filter(|p| !p.is_empty()) {
+            let mut kv = param.splitn(2, '=');
+            let key = kv.next().unwrap();
+            let value = kv
+                .next()
+                .ok_or_else(|| format!("Curve option {} is missing a value", key))?;
+            let float = || {
+                f32::from_str(value).map_err(|e| format!("Invalid curve option {}: {:?}", param, e))
+            };
+            let byte = || {
+                u8::from_str(value).map_err(|e| format!("Invalid curve option {}: {:?}", param, e))
+            };
+            match (&mut curve.shape, key) {
+                (_, "min") => curve.min = float()?,
+                (_, "max") => curve.max = float()?,
+                (_, "low") => curve.low = byte()?,
+                (_, "high") => curve.high = byte()?,
+                (Shape::Power(exp), "exp") if name == "power" => *exp = float()?,
+                (Shape::Log(scale), "scale") => *scale = float()?,
+                (Shape::Sigmoid { steepness, .. }, "steepness") => *steepness = float()?,
+                (Shape::Sigmoid { center, .. }, "center") => *center = float()?,
+                (Shape::Table(_), "file") => {
+                    let table = std::fs::read_to_string(value)
+                        .map_err(|e| format!("Could not read {}: {}", value, e))?;
+                    curve.shape = Shape::parse_table(&table)?;
+                }
+                _ => return Err(format!("Unknown {} curve option: {}", name, key)),
+            }
+        }
+        if let Shape::Table(points) = &curve.shape {
+            if points.is_empty() {
+                return Err("The table curve requires file=PATH".to_string());
+            }
+        }
+        if curve.max.partial_cmp(&curve.min) != Some(Ordering::Greater) {
+            return Err(format!(
+                "Invalid curve range: {} to {} dB",
+                curve.min, curve.max
+            ));
+        }
+        if curve.low > curve.high || curve.high > 31 {
+            return Err(format!(
+                "Invalid curve output: {} to {}, which must be within 0 to 31",
+                curve.low, curve.high
+            ));
+        }
+        let positive = |v: f32| v.partial_cmp(&0.0) == Some(Ordering::Greater);
+        match curve.shape {
+            Shape::Power(exp) if !positive(exp) => Err("The exponent must be positive".to_string()),
+            Shape::Log(scale) if !positive(scale) => Err("The scale must be positive".to_string()),
+            Shape::Sigmoid { steepness, .. } if !positive(steepness) => {
+                Err("The steepness must be positive".to_string())
+            }
+            _ => Ok(curve),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shapes_span_the_range() {
+        let shapes = [
+            Shape::Power(1.0),
+            Shape::Power(2.0),
+            Shape::Log(9.0),
+            Shape::Sigmoid {
+                steepness: 10.0,
+                center: 0.5,
+            },
+            Shape::Table(vec![(0.0, 0.0), (0.5, 0.8), (1.0, 1.0)]),
+        ];
+        for shape in shapes.iter() {
+            assert!(shape.apply(0.0).abs() < 1e-6, "{:?}", shape);
+            assert!((shape.apply(1.0) - 1.0).abs() < 1e-6, "{:?}", shape);
+            let ys: Vec<f32> = (0..=100).map(|i| shape.apply(i as f32 / 100.0)).collect();
+            assert!(ys.windows(2).all(|w| w[0] <= w[1]), "{:?}", shape);
+        }
+        // log brightens and power darkens quiet levels
+        assert!(Shape::Log(9.0).apply(0.25) > 0.25);
+        assert!((Shape::Power(2.0).apply(0.5) - 0.25).abs() < 1e-6);
+        assert!(
+            (Shape::Sigmoid {
+                steepness: 10.0,
+                center: 0.5
+            }
+            .apply(0.5)
+                - 0.5)
+                .abs()
+                < 1e-6
+        );
+    }
+
+    #[test]
+    fn tables_interpolate() {
+        let table = Shape::parse_table("# input output\n0.2 0.1\n\n0.6 0.9\n").unwrap();
+        assert_eq!(table, Shape::Table(vec![(0.2, 0.1), (0.6, 0.9)]));
+        assert_eq!(table.apply(0.0), 0.1);
+        assert!((table.apply(0.4) - 0.5).abs() < 1e-6);
+        assert_eq!(table.apply(1.0), 0.9);
+        assert!(Shape::parse_table("").is_err());
+        assert!(Shape::parse_table("0.5").is_err());
+        assert!(Shape::parse_table("0.5 0.1\n0.5 0.2").is_err());
+    }
+
+    #[test]
+    fn maps_levels_to_output() {
+        let curve = Curve::from_str("linear,min=-40,max=0,low=4,high=20").unwrap();
+        assert_eq!(curve.apply(-100.0), 4);
+        assert_eq!(curve.apply(-20.0), 12);
+        assert_eq!(curve.apply(10.0), 20);
+        assert_eq!(curve.apply(f32::NEG_INFINITY), 4);
+        let curve = Curve::from_str("power,exp=3").unwrap();
+        assert_eq!(curve.shape, Shape::Power(3.0));
+        assert_eq!(
+            (curve.min, curve.max, curve.low, curve.high),
+            (-35.0, 15.0, 0, 31)
+        );
+    }
+
+    #[test]
+    fn parses() {
+        let path = std::env::temp_dir().join(format!("curve-{}.txt", std::process::id()));
+        std::fs::write(&path, "0 0\n1 0.5\n").unwrap();
+        let curve = Curve::from_str(&format!("table,file={}", path.display())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(curve.shape, Shape::Table(vec![(0.0, 0.0), (1.0, 0.5)]));
+        assert_eq!(curve.apply(15.0), 16);
+
+        for bad in &[
+            "cubic",
+            "table",
+            "linear,exp=2",
+            "quadratic,exp=3",
+            "log,scale=0",
+            "sigmoid,steepness=-1",
+            "power,exp",
+            "linear,min=0,max=0",
+            "linear,low=10,high=5",
+            "linear,high=32",
+        ] {
+            assert!(Curve::from_str(bad).is_err(), "{}", bad);
+        }
+    }
+}
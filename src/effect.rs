@@ -0,0 +1,996 @@
+use crate::agc::{Agc, AgcOptions};
+use crate::bands::{Bands, Spacing};
+use crate::beat::BeatInfo;
+use crate::control::Smoother;
+use crate::curve::Curve;
+use crate::midi::{MidiEvent, MidiMessage};
+use lecp::{Command, LedMsg};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::{Range, RangeInclusive};
+use std::str::FromStr;
+
+/// The edges of the subwoofer, woofer, midrange and tweeter bands for a 256 bin FFT.
+const S4FS_EDGES: [usize; 5] = [1, 3, 6, 21, 129];
+
+/// The analysis of a single `StereoSample` that is passed to an `Effect`.
+pub struct Frame<'a> {
+    /// The weighted power spectrum, in dB, of the left channel from DC to the Nyquist frequency.
+    pub left: &'a [f32],
+    pub right: &'a [f32],
+    /// The weighted power spectrum of every channel of the sample before it was mixed down
+    /// to `left` and `right`. This is empty unless `Effect::wants_channels()` returns true.
+    pub channels: &'a [Vec<f32>],
+    pub rate: u32,
+    pub fft_size: usize,
+    /// The time of the sample in microseconds.
+    pub time: u64,
+    /// The beat estimated from the audio, or followed from a running MIDI clock.
+    pub beat: BeatInfo,
+    /// The MIDI messages received since the previous frame, oldest first.
+    pub midi: &'a [MidiMessage],
+}
+impl Frame<'_> {
+    /// The center frequency of `bin` in Hz.
+    #[inline]
+    pub fn bin_freq(&self, bin: usize) -> f32 {
+        bin as f32 * self.rate as f32 / self.fft_size as f32
+    }
+    /// The bin closest to `freq` in Hz, limited to the bins of the spectrum.
+    #[inline]
+    pub fn freq_bin(&self, freq: f32) -> usize {
+        let bin = (freq * self.fft_size as f32 / self.rate as f32)
+            .round()
+            .max(0.0) as usize;
+        bin.min(self.left.len() - 1)
+    }
+}
+
+/// Turns analysed audio into messages for the lights.
+pub trait Effect: Send {
+    /// Writes the messages for `frame` into `msgs`, which is empty when this is called.
+    ///
+    /// The `time` of each message is filled in by the `AudioVisualizer` for each sender.
+    fn process(&mut self, frame: &Frame, msgs: &mut Vec<LedMsg>);
+    /// Whether the effect uses the spectrum of each channel in `Frame::channels`.
+    #[inline]
+    fn wants_channels(&self) -> bool {
+        false
+    }
+    /// Changes one of the parameters the effect was created with while it is running,
+    /// such as from a MIDI controller. Flags take `true` or `false`.
+    fn set_param(&mut self, key: &str, _value: &str) -> Result<(), String> {
+        Err(format!("Unknown parameter: {}", key))
+    }
+}
+
+/// Parses the value of a flag passed to `Effect::set_param()`.
+fn flag(value: &str) -> Result<bool, String> {
+    bool::from_str(value).map_err(|_| format!("Invalid flag: {}", value))
+}
+
+/// Creates an effect from the parameters following its name, which may be empty.
+pub type EffectFactory = Box<dyn Fn(&str) -> Result<Box<dyn Effect>, String> + Send + Sync>;
+
+/// A collection of effects that can be created by name.
+pub struct EffectRegistry {
+    effects: BTreeMap<String, (String, EffectFactory)>,
+}
+impl Default for EffectRegistry {
+    /// A registry containing the effects built into this crate.
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register(
+            "flatstack",
+            "Four bands per channel on a stack of flat panels. Parameters: [ALGORITHM][:invert][:SMOOTHING]",
+            |params| Ok(Box::new(FlatStack::from_str(params)?)),
+        );
+        registry.register(
+            "bands",
+            "One bar per band per channel. Parameters: [spacing=linear|log|octave|third-octave|mel|bark][:count=N][:min=HZ][:max=HZ][:edges=HZ,HZ,...][:alg=ALGORITHM][:mirror][:peaks][:SMOOTHING]",
+            |params| Ok(Box::new(SpectrumBars::from_str(params)?)),
+        );
+        registry.register(
+            "zones",
+            "One element per input channel. Parameters: [map=CH,CH,...][:min=HZ][:max=HZ][:alg=ALGORITHM][:SMOOTHING]",
+            |params| Ok(Box::new(ChannelZones::from_str(params)?)),
+        );
+        registry.register(
+            "notes",
+            "Lights elements with the notes received over MIDI. Parameters: [count=N][:min=NOTE][:max=NOTE][:ranges=NOTE-NOTE,...][:attack=S][:decay=S][:sustain=LEVEL][:release=S][:colors=C,C,...]",
+            |params| Ok(Box::new(MidiNotes::from_str(params)?)),
+        );
+        registry
+    }
+}
+impl EffectRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        EffectRegistry {
+            effects: BTreeMap::new(),
+        }
+    }
+    /// Adds an effect, replacing any existing effect with the same name.
+    pub fn register<F>(&mut self, name: &str, description: &str, factory: F)
+    where
+        F: Fn(&str) -> Result<Box<dyn Effect>, String> + Send + Sync + 'static,
+    {
+        self.effects.insert(
+            name.to_string(),
+            (description.to_string(), Box::new(factory)),
+        );
+    }
+    /// Creates an effect from a string of the form `NAME[:PARAMS]`.
+    pub fn create(&self, spec: &str) -> Result<Box<dyn Effect>, String> {
+        let mut parts = spec.splitn(2, ':');
+        let name = parts.next().unwrap();
+        match self.effects.get(name) {
+            Some((_, factory)) => factory(parts.next().unwrap_or("")),
+            None => Err(format!("Unknown effect: {}", name)),
+        }
+    }
+    /// The names and descriptions of the registered effects.
+    pub fn effects(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.effects
+            .iter()
+            .map(|(name, (desc, _))| (name.as_str(), desc.as_str()))
+    }
+}
+
+/// How the levels of an effect are scaled to the brightness of the lights.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Algorithm {
+    Linear,
+    Quadratic,
+    /// Scales relative to the recently tracked peak and noise floor of each level.
+    Agc(AgcOptions),
+    /// Maps levels onto a configurable response curve.
+    Curve(Curve),
+}
+impl FromStr for Algorithm {
+    type Err = String;
+    /// Parses `linear`, `quadratic`, `agc[,OPTIONS]` or a response curve.
+    /// See `AgcOptions::from_str()` and `Curve::from_str()`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ',');
+        match (parts.next().unwrap(), parts.next()) {
+            ("linear", None) => Ok(Algorithm::Linear),
+            ("quadratic", None) => Ok(Algorithm::Quadratic),
+            ("agc", options) => Ok(Algorithm::Agc(AgcOptions::from_str(options.unwrap_or(""))?)),
+            _ => Ok(Algorithm::Curve(Curve::from_str(s)?)),
+        }
+    }
+}
+
+/// Scales levels in dB to the range [0, 31] used by `Command::FlatStack`,
+/// keeping the state needed by `Algorithm::Agc`.
+#[derive(Clone, Debug, Default)]
+pub struct Scaler {
+    agc: Agc,
+    normalized: Vec<f32>,
+}
+impl Scaler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Scales `levels` measured at `time`, in microseconds, into `out`, replacing its contents.
+    pub fn scale(&mut self, alg: &Algorithm, levels: &[f32], time: u64, out: &mut Vec<u8>) {
+        out.clear();
+        match alg {
+            Algorithm::Linear => out.extend(
+                levels
+                    .iter()
+                    .map(|db| ((db + 35.0) * 2.0 * 0.31).min(31.0).max(0.0).round() as u8),
+            ),
+            Algorithm::Quadratic => out.extend(levels.iter().map(|db| {
+                let val = ((db + 35.0) / 5.0).max(0.0);
+                (val * val * 0.31).min(31.0).round() as u8
+            })),
+            Algorithm::Agc(options) => {
+                self.normalized.resize(levels.len(), 0.0);
+                self.agc
+                    .process(options, levels, time, &mut self.normalized);
+                out.extend(self.normalized.iter().map(|v| (v * 31.0).round() as u8));
+            }
+            Algorithm::Curve(curve) => out.extend(levels.iter().map(|db| curve.apply(*db))),
+        }
+    }
+}
+
+/// Shows the subwoofer, woofer, midrange and tweeter levels of each channel on a
+/// stack of nine flat panels, with the left channel on the first four and the right
+/// channel mirrored on the last four. The middle panel receives the remaining brightness.
+pub struct FlatStack {
+    pub alg: Algorithm,
+    /// Swaps the left and right channels.
+    pub invert: bool,
+    pub smoother: Smoother,
+    edges: [usize; 5],
+    bins: usize,
+    fft_size: usize,
+    scaler: Scaler,
+    values: Vec<u8>,
+}
+impl FlatStack {
+    pub fn new(alg: Algorithm, invert: bool) -> Self {
+        FlatStack {
+            alg,
+            invert,
+            smoother: Smoother::default(),
+            edges: S4FS_EDGES,
+            bins: 0,
+            fft_size: 0,
+            scaler: Scaler::new(),
+            values: Vec::with_capacity(8),
+        }
+    }
+    /// Scale the bands to the number of bins of an FFT of `fft_size`, keeping each band
+    /// at least one bin wide. Spectra with fewer bins than there are edges cannot be split
+    /// and are ignored.
+    fn set_bins(&mut self, bins: usize, fft_size: usize) {
+        self.bins = bins;
+        self.fft_size = fft_size;
+        if bins < S4FS_EDGES.len() {
+            return;
+        }
+        for (i, e) in S4FS_EDGES.iter().enumerate() {
+            let min = if i == 0 { 1 } else { self.edges[i - 1] + 1 };
+            self.edges[i] = ((e * fft_size + 128) / 256).max(min);
+        }
+        self.edges[4] = bins;
+    }
+}
+impl FromStr for FlatStack {
+    type Err = String;
+    /// Parses `[ALGORITHM][:invert]`, where the algorithm defaults to quadratic,
+    /// followed by any smoothing parameters. See `Smoother::set_param()`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fs = FlatStack::new(Algorithm::Quadratic, false);
+        for param in s.split(':').filter(|p| !p.is_empty()) {
+            let mut kv = param.splitn(2, '=');
+            match (kv.next().unwrap(), kv.next()) {
+                ("invert", None) => fs.invert = true,
+                (key, Some(v)) if fs.smoother.set_param(key, v)? => {}
+                _ => fs.alg = Algorithm::from_str(param)?,
+            }
+        }
+        Ok(fs)
+    }
+}
+impl Effect for FlatStack {
+    fn process(&mut self, frame: &Frame, msgs: &mut Vec<LedMsg>) {
+        if frame.left.len() != self.bins || frame.fft_size != self.fft_size {
+            self.set_bins(frame.left.len(), frame.fft_size);
+        }
+        if self.bins < S4FS_EDGES.len() {
+            return;
+        }
+        let (l_avg, r_avg) = (frame.left, frame.right);
+        let f32_max = |s: &[f32]| {
+            *s.iter()
+                .max_by(|l, r| l.partial_cmp(r).unwrap_or(Ordering::Equal))
+                .unwrap()
+        };
+        let e = self.edges;
+        let mut l_bins = [0.0; 4];
+        l_bins[0] = f32_max(&l_avg[e[0]..e[1]]); // Subwoofer
+        l_bins[1] = f32_max(&l_avg[e[1]..e[2]]); // Woofer
+        l_bins[2] = f32_max(&l_avg[e[2]..e[3]]); // Midrange
+        l_bins[3] = f32_max(&l_avg[e[3]..e[4]]); // Tweeter
+
+        let mut r_bins = [0.0; 4];
+        r_bins[0] = f32_max(&r_avg[e[0]..e[1]]);
+        r_bins[1] = f32_max(&r_avg[e[1]..e[2]]);
+        r_bins[2] = f32_max(&r_avg[e[2]..e[3]]);
+        r_bins[3] = f32_max(&r_avg[e[3]..e[4]]);
+
+        if self.invert {
+            std::mem::swap(&mut l_bins, &mut r_bins);
+        }
+
+        let mut levels = [0.0; 8];
+        levels[0..4].copy_from_slice(&l_bins);
+        levels[4..8].copy_from_slice(&r_bins);
+        self.smoother.process(&mut levels, frame.time);
+        self.scaler
+            .scale(&self.alg, &levels, frame.time, &mut self.values);
+
+        let mut left = [LedMsg::default(); 4];
+        let mut right = [LedMsg::default(); 4];
+        let iter = left.iter_mut().chain(right.iter_mut());
+        let mut sum: u16 = 0;
+        // scale to range of [0, 32] u8 and keep track of total sum
+        for (r, val) in iter.zip(self.values.iter()) {
+            sum += *val as u16 + 1;
+            r.cmd = Command::FlatStack(*val);
+        }
+        let mut ret = [LedMsg::default(); 9];
+        ret[0..4].copy_from_slice(&left);
+        ret[5..9].copy_from_slice(&right);
+        ret[4].cmd = Command::FlatStack(255u16.saturating_sub(sum) as u8);
+        for (i, r) in ret.iter_mut().enumerate() {
+            r.element = i as u8;
+        }
+        for (i, r) in ret[0..4].iter_mut().enumerate() {
+            r.color = i as u8 + 1;
+        }
+        for (i, r) in ret[5..9].iter_mut().rev().enumerate() {
+            r.color = i as u8 + 1;
+        }
+        msgs.extend_from_slice(&ret);
+    }
+    fn set_param(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "alg" => self.alg = Algorithm::from_str(value)?,
+            "invert" => self.invert = flag(value)?,
+            _ if self.smoother.set_param(key, value)? => {}
+            _ => return Err(format!("Unknown flatstack parameter: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+/// The loudest bin within each range of `spec`.
+pub(crate) fn band_levels<'a>(
+    spec: &'a [f32],
+    ranges: &'a [Range<usize>],
+) -> impl Iterator<Item = f32> + 'a {
+    ranges.iter().map(move |r| {
+        spec[r.clone()]
+            .iter()
+            .copied()
+            .fold(f32::NEG_INFINITY, f32::max)
+    })
+}
+
+/// Shows the level of any number of frequency bands of each channel, one element per band.
+///
+/// The left channel's bands are sent to the first elements from the lowest to highest band,
+/// followed by the right channel's bands, which are in reverse order when `mirror` is set.
+/// When `peaks` is set, the held peak of each band follows on the same number of elements
+/// in the same order.
+pub struct SpectrumBars {
+    bands: Bands,
+    pub alg: Algorithm,
+    pub mirror: bool,
+    pub peaks: bool,
+    pub smoother: Smoother,
+    ranges: Vec<Range<usize>>,
+    fft_size: usize,
+    rate: u32,
+    scaler: Scaler,
+    levels: Vec<f32>,
+    values: Vec<u8>,
+}
+impl SpectrumBars {
+    pub fn new(bands: Bands, alg: Algorithm, mirror: bool) -> Self {
+        SpectrumBars {
+            bands,
+            alg,
+            mirror,
+            peaks: false,
+            smoother: Smoother::default(),
+            ranges: Vec::new(),
+            fft_size: 0,
+            rate: 0,
+            scaler: Scaler::new(),
+            levels: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+    #[inline]
+    pub fn bands(&self) -> &Bands {
+        &self.bands
+    }
+    pub fn set_bands(&mut self, bands: Bands) {
+        self.bands = bands;
+        self.fft_size = 0; // force the bins to be recomputed
+    }
+}
+impl FromStr for SpectrumBars {
+    type Err = String;
+    /// Parses `:` separated `KEY=VALUE` parameters. Without any parameters there are
+    /// eight log spaced bands from 40 Hz to 16 kHz. `edges` overrides the other band parameters.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut spacing, mut count, mut min, mut max) = (Spacing::Log, 8, 40.0, 16000.0);
+        let mut edges = None;
+        let mut alg = Algorithm::Quadratic;
+        let mut mirror = false;
+        let mut peaks = false;
+        let mut smoother = Smoother::default();
+        let float =
+            |v: &str| f32::from_str(v).map_err(|e| format!("Invalid frequency {}: {:?}", v, e));
+        for param in s.split(':').filter(|p| !p.is_empty()) {
+            let mut kv = param.splitn(2, '=');
+            match (kv.next().unwrap(), kv.next()) {
+                ("spacing", Some(v)) => spacing = Spacing::from_str(v)?,
+                ("count", Some(v)) => {
+                    count = usize::from_str(v)
+                        .map_err(|e| format!("Invalid band count {}: {:?}", v, e))?
+                }
+                ("min", Some(v)) => min = float(v)?,
+                ("max", Some(v)) => max = float(v)?,
+                ("edges", Some(v)) => {
+                    edges = Some(v.split(',').map(float).collect::<Result<Vec<_>, _>>()?)
+                }
+                ("alg", Some(v)) => alg = Algorithm::from_str(v)?,
+                ("mirror", None) => mirror = true,
+                ("peaks", None) => peaks = true,
+                (key, Some(v)) if smoother.set_param(key, v)? => {}
+                _ => return Err(format!("Unknown band parameter: {}", param)),
+            }
+        }
+        let bands = match edges {
+            Some(edges) => Bands::from_edges(edges)?,
+            None => Bands::new(spacing, count, min, max)?,
+        };
+        let elements = bands.len() * if peaks { 4 } else { 2 };
+        if elements > 256 {
+            return Err(format!(
+                "{} bands need {} elements, but there are only 256",
+                bands.len(),
+                elements
+            ));
+        }
+        let mut bars = SpectrumBars::new(bands, alg, mirror);
+        bars.peaks = peaks;
+        bars.smoother = smoother;
+        Ok(bars)
+    }
+}
+impl Effect for SpectrumBars {
+    fn process(&mut self, frame: &Frame, msgs: &mut Vec<LedMsg>) {
+        if frame.fft_size != self.fft_size || frame.rate != self.rate {
+            self.fft_size = frame.fft_size;
+            self.rate = frame.rate;
+            self.ranges = match self.bands.bin_ranges(frame.fft_size, frame.rate) {
+                Ok(ranges) => ranges,
+                Err(e) => {
+                    eprintln!("Spectrum bars are disabled: {}", e);
+                    Vec::new()
+                }
+            };
+        }
+        let n = self.ranges.len();
+        self.levels.clear();
+        self.levels.extend(band_levels(frame.left, &self.ranges));
+        self.levels.extend(band_levels(frame.right, &self.ranges));
+        self.smoother.process(&mut self.levels, frame.time);
+        if self.peaks {
+            self.levels.extend_from_slice(self.smoother.peaks());
+        }
+        self.scaler
+            .scale(&self.alg, &self.levels, frame.time, &mut self.values);
+        let start = msgs.len();
+        // only the bands of both channels that fit within the 256 elements are shown,
+        // which also keeps every color within a u8
+        let shown = if n > 0 { 256 / (2 * n) * 2 * n } else { 0 };
+        for (i, val) in self.values.iter().take(shown).enumerate() {
+            let (offset, i) = (i / (2 * n) * 2 * n, i % (2 * n));
+            let (band, element) = match i.checked_sub(n) {
+                None => (i, i),
+                Some(band) if self.mirror => (band, 2 * n - 1 - band),
+                Some(band) => (band, i),
+            };
+            msgs.push(LedMsg {
+                element: (offset + element) as u8,
+                color: (band + 1) as u8,
+                cmd: Command::FlatStack(*val),
+                ..LedMsg::default()
+            });
+        }
+        if self.mirror && n > 0 {
+            for chunk in msgs[start..].chunks_mut(2 * n) {
+                chunk[n..].reverse();
+            }
+        }
+    }
+    fn set_param(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "alg" => self.alg = Algorithm::from_str(value)?,
+            "mirror" => self.mirror = flag(value)?,
+            "peaks" => self.peaks = flag(value)?,
+            _ if self.smoother.set_param(key, value)? => {}
+            _ => return Err(format!("Unknown band parameter: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Shows the level of each input channel on its own element, such as one zone of lights
+/// per speaker of a surround system or a single zone for a microphone.
+pub struct ChannelZones {
+    /// The channel shown by each zone. When empty, every channel is shown on its own zone.
+    pub map: Vec<usize>,
+    pub alg: Algorithm,
+    /// The frequencies, in Hz, included in the level of each channel.
+    pub min: f32,
+    pub max: f32,
+    pub smoother: Smoother,
+    scaler: Scaler,
+    levels: Vec<f32>,
+    values: Vec<u8>,
+}
+impl ChannelZones {
+    pub fn new(map: Vec<usize>, alg: Algorithm) -> Self {
+        ChannelZones {
+            map,
+            alg,
+            min: 20.0,
+            max: 20000.0,
+            smoother: Smoother::default(),
+            scaler: Scaler::new(),
+            levels: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+}
+impl FromStr for ChannelZones {
+    type Err = String;
+    /// Parses `:` separated `KEY=VALUE` parameters.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut zones = ChannelZones::new(Vec::new(), Algorithm::Quadratic);
+        let float =
+            |v: &str| f32::from_str(v).map_err(|e| format!("Invalid frequency {}: {:?}", v, e));
+        for param in s.split(':').filter(|p| !p.is_empty()) {
+            let mut kv = param.splitn(2, '=');
+            match (kv.next().unwrap(), kv.next()) {
+                ("map", Some(v)) => {
+                    zones.map = v
+                        .split(',')
+                        .map(|c| {
+                            usize::from_str(c)
+                                .map_err(|e| format!("Invalid channel {}: {:?}", c, e))
+                        })
+                        .collect::<Result<_, _>>()?
+                }
+                ("min", Some(v)) => zones.min = float(v)?,
+                ("max", Some(v)) => zones.max = float(v)?,
+                ("alg", Some(v)) => zones.alg = Algorithm::from_str(v)?,
+                (key, Some(v)) if zones.smoother.set_param(key, v)? => {}
+                _ => return Err(format!("Unknown zone parameter: {}", param)),
+            }
+        }
+        if zones.max.partial_cmp(&zones.min) != Some(Ordering::Greater) {
+            return Err(format!(
+                "Invalid zone range: {} to {} Hz",
+                zones.min, zones.max
+            ));
+        }
+        Ok(zones)
+    }
+}
+impl Effect for ChannelZones {
+    fn process(&mut self, frame: &Frame, msgs: &mut Vec<LedMsg>) {
+        let start = frame.freq_bin(self.min).max(1);
+        let range = start..(frame.freq_bin(self.max) + 1).max(start + 1);
+        let level = |ch: usize| match frame.channels.get(ch) {
+            Some(spec) => spec[range.clone()]
+                .iter()
+                .copied()
+                .fold(f32::NEG_INFINITY, f32::max),
+            None => f32::NEG_INFINITY,
+        };
+        let zones = if self.map.is_empty() {
+            frame.channels.len()
+        } else {
+            self.map.len()
+        };
+        self.levels.clear();
+        for zone in 0..zones {
+            let ch = self.map.get(zone).copied().unwrap_or(zone);
+            self.levels.push(level(ch));
+        }
+        self.smoother.process(&mut self.levels, frame.time);
+        self.scaler
+            .scale(&self.alg, &self.levels, frame.time, &mut self.values);
+        for (zone, val) in self.values.iter().enumerate() {
+            msgs.push(LedMsg {
+                element: zone as u8,
+                color: zone as u8 + 1,
+                cmd: Command::FlatStack(*val),
+                ..LedMsg::default()
+            });
+        }
+    }
+    #[inline]
+    fn wants_channels(&self) -> bool {
+        true
+    }
+    fn set_param(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "alg" => self.alg = Algorithm::from_str(value)?,
+            _ if self.smoother.set_param(key, value)? => {}
+            _ => return Err(format!("Unknown zone parameter: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+/// An envelope with attack, decay and release times in seconds and a sustain level
+/// from 0.0 to 1.0.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Adsr {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+impl Default for Adsr {
+    fn default() -> Self {
+        Adsr {
+            attack: 0.01,
+            decay: 0.2,
+            sustain: 0.7,
+            release: 0.5,
+        }
+    }
+}
+impl Adsr {
+    /// The level `t` seconds after a note started while it is still held.
+    pub fn held(&self, t: f32) -> f32 {
+        if t < self.attack {
+            return t / self.attack;
+        }
+        let t = t - self.attack;
+        if t < self.decay {
+            1.0 - (1.0 - self.sustain) * t / self.decay
+        } else {
+            self.sustain
+        }
+    }
+    /// The level `t` seconds after a note at `level` was released.
+    pub fn released(&self, level: f32, t: f32) -> f32 {
+        if t < self.release {
+            level * (1.0 - t / self.release)
+        } else {
+            0.0
+        }
+    }
+}
+
+/// A note that is sounding or being released.
+struct Voice {
+    channel: u8,
+    note: u8,
+    velocity: f32,
+    on: u64,
+    /// The time the note was released and its level at that time.
+    off: Option<(u64, f32)>,
+    /// The note was released while the sustain pedal was down.
+    sustained: bool,
+}
+
+/// Lights elements with the notes played on a MIDI instrument. Each element shows a range
+/// of notes, at a brightness set by their velocity and envelope, in the color of the channel
+/// of the loudest one.
+///
+/// The channel volume (CC 7), sustain pedal (CC 64) and all sound and notes off (CC 120 and 123)
+/// controllers are followed. Notes start and end at the frame in which they were received.
+pub struct MidiNotes {
+    /// The notes shown by each element.
+    pub ranges: Vec<RangeInclusive<u8>>,
+    pub envelope: Adsr,
+    /// The color of each MIDI channel. Channels without one use their number plus one.
+    pub colors: Vec<u8>,
+    voices: Vec<Voice>,
+    volume: [f32; 16],
+    pedal: [bool; 16],
+}
+impl MidiNotes {
+    pub fn new(ranges: Vec<RangeInclusive<u8>>, envelope: Adsr) -> Self {
+        MidiNotes {
+            ranges,
+            envelope,
+            colors: Vec::new(),
+            voices: Vec::new(),
+            volume: [1.0; 16],
+            pedal: [false; 16],
+        }
+    }
+    /// Splits the notes from `min` to `max` into `count` ranges of about the same size.
+    pub fn split(count: usize, min: u8, max: u8) -> Vec<RangeInclusive<u8>> {
+        let width = (max as usize + 1 - min as usize) as f32 / count as f32;
+        (0..count)
+            .map(|i| {
+                let lo = min + (i as f32 * width) as u8;
+                let hi = min + ((i + 1) as f32 * width) as u8 - 1;
+                lo..=hi
+            })
+            .collect()
+    }
+    fn level(&self, voice: &Voice, time: u64) -> f32 {
+        let secs = |since: u64| time.saturating_sub(since) as f32 / 1_000_000.0;
+        match voice.off {
+            None => self.envelope.held(secs(voice.on)),
+            Some((off, level)) => self.envelope.released(level, secs(off)),
+        }
+    }
+    fn release(&mut self, channel: u8, note: Option<u8>, time: u64) {
+        let pedal = self.pedal[channel as usize & 0x0F];
+        for i in 0..self.voices.len() {
+            let v = &self.voices[i];
+            if v.channel != channel || v.off.is_some() || matches!(note, Some(n) if n != v.note) {
+                continue;
+            }
+            if pedal && note.is_some() {
+                self.voices[i].sustained = true;
+            } else {
+                let level = self.level(&self.voices[i], time);
+                self.voices[i].off = Some((time, level));
+            }
+        }
+    }
+    fn handle(&mut self, event: &MidiEvent, time: u64) {
+        match *event {
+            MidiEvent::NoteOn(channel, note, velocity) if velocity > 0 => {
+                self.voices
+                    .retain(|v| v.channel != channel || v.note != note);
+                self.voices.push(Voice {
+                    channel,
+                    note,
+                    velocity: velocity as f32 / 127.0,
+                    on: time,
+                    off: None,
+                    sustained: false,
+                });
+            }
+            MidiEvent::NoteOn(channel, note, _) | MidiEvent::NoteOff(channel, note, _) => {
+                self.release(channel, Some(note), time)
+            }
+            MidiEvent::CtrlChg(channel, 7, value) => {
+                self.volume[channel as usize & 0x0F] = value as f32 / 127.0
+            }
+            MidiEvent::CtrlChg(channel, 64, value) => {
+                let down = value >= 64;
+                self.pedal[channel as usize & 0x0F] = down;
+                if !down {
+                    for i in 0..self.voices.len() {
+                        if self.voices[i].channel == channel && self.voices[i].sustained {
+                            let level = self.level(&self.voices[i], time);
+                            self.voices[i].sustained = false;
+                            self.voices[i].off = Some((time, level));
+                        }
+                    }
+                }
+            }
+            MidiEvent::CtrlChg(channel, 120, _) => self.voices.retain(|v| v.channel != channel),
+            MidiEvent::CtrlChg(channel, 123, _) => self.release(channel, None, time),
+            _ => (),
+        }
+    }
+}
+impl FromStr for MidiNotes {
+    type Err = String;
+    /// Parses `:` separated `KEY=VALUE` parameters. Without any parameters the 88 keys
+    /// of a piano are split over nine elements. `ranges` overrides the other note parameters.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut count, mut min, mut max) = (9, 21, 108);
+        let mut ranges = None;
+        let mut envelope = Adsr::default();
+        let mut colors = Vec::new();
+        let note = |v: &str| match u8::from_str(v) {
+            Ok(n) if n < 128 => Ok(n),
+            _ => Err(format!("Invalid note: {}", v)),
+        };
+        let secs = |v: &str| match f32::from_str(v) {
+            Ok(t) if t >= 0.0 => Ok(t),
+            _ => Err(format!("Invalid time: {}", v)),
+        };
+        for param in s.split(':').filter(|p| !p.is_empty()) {
+            let mut kv = param.splitn(2, '=');
+            match (kv.next().unwrap(), kv.next()) {
+                ("count", Some(v)) => {
+                    count = usize::from_str(v)
+                        .map_err(|e| format!("Invalid element count {}: {:?}", v, e))?
+                }
+                ("min", Some(v)) => min = note(v)?,
+                ("max", Some(v)) => max = note(v)?,
+                ("ranges", Some(v)) => {
+                    ranges = Some(
+                        v.split(',')
+                            .map(|r| {
+                                let mut ends = r.splitn(2, '-');
+                                let lo = note(ends.next().unwrap())?;
+                                let hi = ends.next().map_or(Ok(lo), note)?;
+                                if hi < lo {
+                                    return Err(format!("Invalid note range: {}", r));
+                                }
+                                Ok(lo..=hi)
+                            })
+                            .collect::<Result<Vec<_>, String>>()?,
+                    )
+                }
+                ("attack", Some(v)) => envelope.attack = secs(v)?,
+                ("decay", Some(v)) => envelope.decay = secs(v)?,
+                ("release", Some(v)) => envelope.release = secs(v)?,
+                ("sustain", Some(v)) => {
+                    envelope.sustain = match f32::from_str(v) {
+                        Ok(l) if (0.0..=1.0).contains(&l) => l,
+                        _ => return Err(format!("Invalid sustain level: {}", v)),
+                    }
+                }
+                ("colors", Some(v)) => {
+                    colors = v
+                        .split(',')
+                        .map(|c| {
+                            u8::from_str(c).map_err(|e| format!("Invalid color {}: {:?}", c, e))
+                        })
+                        .collect::<Result<_, _>>()?
+                }
+                _ => return Err(format!("Unknown notes parameter: {}", param)),
+            }
+        }
+        let ranges = match ranges {
+            Some(ranges) if ranges.len() > 256 => {
+                return Err(format!(
+                    "{} note ranges need more than the 256 elements",
+                    ranges.len()
+                ))
+            }
+            Some(ranges) => ranges,
+            None if count > 0 && max >= min && count <= (max - min) as usize + 1 => {
+                MidiNotes::split(count, min, max)
+            }
+            None => {
+                return Err(format!(
+                    "Cannot split notes {} to {} over {} elements",
+                    min, max, count
+                ))
+            }
+        };
+        let mut notes = MidiNotes::new(ranges, envelope);
+        notes.colors = colors;
+        Ok(notes)
+    }
+}
+impl Effect for MidiNotes {
+    fn process(&mut self, frame: &Frame, msgs: &mut Vec<LedMsg>) {
+        let time = frame.time;
+        for msg in frame.midi {
+            self.handle(&msg.event, time);
+        }
+        let envelope = self.envelope;
+        self.voices.retain(|v| match v.off {
+            None => true,
+            Some((off, level)) => {
+                envelope.released(level, time.saturating_sub(off) as f32 / 1_000_000.0) > 0.0
+            }
+        });
+        // ranges beyond the last element are not shown
+        for (element, range) in (0..=u8::MAX).zip(self.ranges.iter()) {
+            let (mut level, mut channel) = (0.0, None);
+            for v in self.voices.iter().filter(|v| range.contains(&v.note)) {
+                let l = self.level(v, time) * v.velocity * self.volume[v.channel as usize & 0x0F];
+                if l > level {
+                    level = l;
+                    channel = Some(v.channel);
+                }
+            }
+            let color =
+                channel.map_or(0, |c| self.colors.get(c as usize).copied().unwrap_or(c + 1));
+            msgs.push(LedMsg {
+                element,
+                color,
+                cmd: Command::FlatStack((level.min(1.0) * 31.0).round() as u8),
+                ..LedMsg::default()
+            });
+        }
+    }
+    fn set_param(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = f32::from_str(value).map_err(|e| format!("Invalid {}: {:?}", key, e))?;
+        if value < 0.0 || (key == "sustain" && value > 1.0) {
+            return Err(format!("Invalid {}: {}", key, value));
+        }
+        match key {
+            "attack" => self.envelope.attack = value,
+            "decay" => self.envelope.decay = value,
+            "sustain" => self.envelope.sustain = value,
+            "release" => self.envelope.release = value,
+            _ => return Err(format!("Unknown notes parameter: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame<'a>(left: &'a [f32], right: &'a [f32]) -> Frame<'a> {
+        Frame {
+            left,
+            right,
+            channels: &[],
+            rate: 48000,
+            fft_size: (left.len().max(1) - 1) * 2,
+            time: 0,
+            beat: BeatInfo::default(),
+            midi: &[],
+        }
+    }
+
+    #[test]
+    fn flat_stack_ignores_tiny_spectra() {
+        let mut fs = FlatStack::new(Algorithm::Quadratic, false);
+        let mut msgs = Vec::new();
+        for bins in 0..S4FS_EDGES.len() {
+            let spec = vec![0.0; bins];
+            fs.process(&frame(&spec, &spec), &mut msgs);
+            assert!(msgs.is_empty(), "{} bins", bins);
+        }
+        for &bins in &[5, 6, 9, 129, 1025] {
+            let spec = vec![0.0; bins];
+            fs.process(&frame(&spec, &spec), &mut msgs);
+            assert_eq!(msgs.len(), 9, "{} bins", bins);
+            assert!(fs.edges.windows(2).all(|e| e[0] < e[1]));
+            assert_eq!(fs.edges[4], bins);
+            msgs.clear();
+        }
+    }
+
+    #[test]
+    fn flat_stack_uses_the_fft_size() {
+        let mut fs = FlatStack::new(Algorithm::Quadratic, false);
+        let mut msgs = Vec::new();
+        // FFTs of 140 and 141 frames both have 71 bins
+        let spec = vec![0.0; 71];
+        let mut frame = frame(&spec, &spec);
+        fs.process(&frame, &mut msgs);
+        assert_eq!(fs.edges, [1, 2, 3, 11, 71]);
+        frame.fft_size = 141;
+        fs.process(&frame, &mut msgs);
+        assert_eq!(fs.edges, [1, 2, 3, 12, 71]);
+    }
+
+    #[test]
+    fn midi_notes_fit_in_elements() {
+        let ranges: Vec<String> = (0..257).map(|i| (i % 128).to_string()).collect();
+        let s = format!("ranges={}", ranges.join(","));
+        assert!(MidiNotes::from_str(&s).is_err());
+        assert!(MidiNotes::from_str(&s[..s.rfind(',').unwrap()]).is_ok());
+        assert!(MidiNotes::from_str("ranges=60-50").is_err());
+
+        let mut notes = MidiNotes::new(vec![60..=60; 300], Adsr::default());
+        let midi = [MidiMessage {
+            time: 0,
+            event: MidiEvent::NoteOn(2, 60, 127),
+        }];
+        let mut frame = frame(&[], &[]);
+        frame.midi = &midi;
+        let mut msgs = Vec::new();
+        notes.process(&frame, &mut msgs);
+        assert_eq!(msgs.len(), 256);
+        msgs.clear();
+        frame.midi = &[];
+        frame.time = 100_000;
+        notes.process(&frame, &mut msgs);
+        assert_eq!(msgs.len(), 256);
+        assert_eq!(msgs[255].element, 255);
+        assert!(msgs.iter().all(|m| m.color == 3));
+    }
+
+    #[test]
+    fn spectrum_bars_fit_in_elements() {
+        assert!(SpectrumBars::from_str("count=128").is_ok());
+        assert!(SpectrumBars::from_str("count=129").is_err());
+        assert!(SpectrumBars::from_str("count=65:peaks").is_err());
+
+        let mut bars = SpectrumBars::from_str("spacing=linear:count=100:min=20:max=20000").unwrap();
+        bars.set_param("peaks", "true").unwrap();
+        bars.set_param("mirror", "true").unwrap();
+        let spec = vec![0.0; 2049];
+        let mut msgs = vec![LedMsg::default()];
+        bars.process(&frame(&spec, &spec), &mut msgs);
+        // only the levels of both channels fit, without the peaks
+        assert_eq!(msgs.len(), 1 + 200);
+        let mut elements: Vec<u8> = msgs[1..].iter().map(|m| m.element).collect();
+        elements.sort();
+        assert_eq!(elements, (0..200).map(|e| e as u8).collect::<Vec<_>>());
+        assert!(msgs[1..].iter().all(|m| 1 <= m.color && m.color <= 100));
+        assert_eq!(msgs[1].element, 0);
+        // the right channel is mirrored, so its highest band is next to the left channel's
+        assert_eq!(msgs[101].element, 100);
+        assert_eq!(msgs[101].color, 100);
+    }
+}
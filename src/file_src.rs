@@ -0,0 +1,383 @@
+use crate::audio::{
+    ActiveAudioSource, AudioSourceOptions, InactiveAudioSource, Sample, StereoSample,
+};
+use crate::Error;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "claxon")]
+use claxon::{frame::Block, FlacReader};
+#[cfg(feature = "hound")]
+use hound::{SampleFormat, WavReader};
+
+/// An audio file that has not started playing yet.
+///
+/// The format is picked from the extension of the path: `.wav` files are
+/// decoded with `hound` and `.flac` files with `claxon`.
+pub struct AudioFile {
+    path: PathBuf,
+    /// When true, samples are released at the rate they would be played back at.
+    /// When false, the file is read as fast as the consumer can process it.
+    pub realtime: bool,
+}
+impl AudioFile {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        AudioFile {
+            path: path.as_ref().to_path_buf(),
+            realtime: true,
+        }
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    fn open(&self) -> Result<Box<dyn Decoder>, Error> {
+        let ext = self
+            .path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match ext.as_deref() {
+            #[cfg(feature = "hound")]
+            Some("wav") | Some("wave") => Ok(Box::new(WavDecoder::open(&self.path)?)),
+            #[cfg(feature = "claxon")]
+            Some("flac") => Ok(Box::new(FlacDecoder::open(&self.path)?)),
+            _ => Err(Error::Unrecoverable(format!(
+                "Unsupported audio file format: {}",
+                self.path.display()
+            ))),
+        }
+    }
+}
+impl InactiveAudioSource for AudioFile {
+    type ActiveType = FileSource;
+    fn activate(self, options: AudioSourceOptions) -> Result<Self::ActiveType, Error> {
+        let decoder = self.open()?;
+        let channels = decoder.channels();
+        if channels == 0 {
+            return Err(Error::Unrecoverable(
+                "Audio file has no channels".to_string(),
+            ));
+        }
+        Ok(FileSource {
+            frame: vec![0.0; channels],
+            buf: vec![Vec::with_capacity(options.sample_size); channels],
+            sample_size: options.sample_size,
+            rate: decoder.rate(),
+            decoder,
+            file: self,
+            position: 0,
+            start: Instant::now(),
+            finished: false,
+        })
+    }
+}
+
+/// Reads frames of interleaved samples from an audio file, normalized to [-1.0, 1.0].
+trait Decoder {
+    fn channels(&self) -> usize;
+    fn rate(&self) -> u32;
+    /// Fill `frame` with one sample per channel.
+    /// Returns false if the end of the file has been reached.
+    fn next_frame(&mut self, frame: &mut [f32]) -> Result<bool, Error>;
+}
+
+#[cfg(feature = "hound")]
+struct WavDecoder {
+    samples: Box<dyn Iterator<Item = hound::Result<f32>>>,
+    channels: usize,
+    rate: u32,
+}
+#[cfg(feature = "hound")]
+impl WavDecoder {
+    fn open(path: &Path) -> Result<Self, Error> {
+        let reader = WavReader::open(path)?;
+        let spec = reader.spec();
+        let samples: Box<dyn Iterator<Item = hound::Result<f32>>> = match spec.sample_format {
+            SampleFormat::Float => Box::new(reader.into_samples::<f32>()),
+            SampleFormat::Int => {
+                let scale = (1_u64 << (spec.bits_per_sample - 1)) as f32;
+                Box::new(
+                    reader
+                        .into_samples::<i32>()
+                        .map(move |s| s.map(|s| s as f32 / scale)),
+                )
+            }
+        };
+        Ok(WavDecoder {
+            samples,
+            channels: spec.channels as usize,
+            rate: spec.sample_rate,
+        })
+    }
+}
+#[cfg(feature = "hound")]
+impl Decoder for WavDecoder {
+    fn channels(&self) -> usize {
+        self.channels
+    }
+    fn rate(&self) -> u32 {
+        self.rate
+    }
+    fn next_frame(&mut self, frame: &mut [f32]) -> Result<bool, Error> {
+        for (i, s) in frame.iter_mut().enumerate() {
+            match self.samples.next() {
+                Some(v) => *s = v?,
+                None if i == 0 => return Ok(false),
+                None => {
+                    return Err(Error::Unrecoverable(
+                        "Wav file ended in the middle of a frame".to_string(),
+                    ))
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(feature = "claxon")]
+struct FlacDecoder {
+    reader: FlacReader<std::fs::File>,
+    block: Block,
+    pos: u32,
+    channels: usize,
+    rate: u32,
+    scale: f32,
+}
+#[cfg(feature = "claxon")]
+impl FlacDecoder {
+    fn open(path: &Path) -> Result<Self, Error> {
+        let reader = FlacReader::open(path)?;
+        let info = reader.streaminfo();
+        Ok(FlacDecoder {
+            reader,
+            block: Block::empty(),
+            pos: 0,
+            channels: info.channels as usize,
+            rate: info.sample_rate,
+            scale: (1_u64 << (info.bits_per_sample - 1)) as f32,
+        })
+    }
+}
+#[cfg(feature = "claxon")]
+impl Decoder for FlacDecoder {
+    fn channels(&self) -> usize {
+        self.channels
+    }
+    fn rate(&self) -> u32 {
+        self.rate
+    }
+    fn next_frame(&mut self, frame: &mut [f32]) -> Result<bool, Error> {
+        while self.pos >= self.block.duration() {
+            // reuse the buffer of the previous block to avoid reallocating
+            let buf = std::mem::replace(&mut self.block, Block::empty()).into_buffer();
+            match self.reader.blocks().read_next_or_eof(buf)? {
+                Some(block) => self.block = block,
+                None => return Ok(false),
+            }
+            self.pos = 0;
+        }
+        for (ch, s) in frame.iter_mut().enumerate() {
+            *s = self.block.sample(ch as u32, self.pos) as f32 / self.scale;
+        }
+        self.pos += 1;
+        Ok(true)
+    }
+}
+
+/// An audio file that is being played back.
+///
+/// Samples have every channel of the file, which are mixed down by the consumer.
+pub struct FileSource {
+    file: AudioFile,
+    decoder: Box<dyn Decoder>,
+    frame: Vec<f32>,
+    /// The frames of each channel of the sample being read.
+    buf: Vec<Vec<f32>>,
+    rate: u32,
+    sample_size: usize,
+    position: u64,
+    start: Instant,
+    finished: bool,
+}
+impl FileSource {
+    #[inline]
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+    #[inline]
+    fn frames_to_time(&self, frames: u64) -> u64 {
+        frames * 1_000_000 / self.rate as u64
+    }
+    /// The instant the sample that is currently being read would be complete during playback.
+    fn deadline(&self) -> Instant {
+        let end = self.position + self.sample_size as u64;
+        self.start + Duration::from_micros(self.frames_to_time(end))
+    }
+    fn read_sample(&mut self) -> Result<StereoSample, Error> {
+        if self.finished {
+            return Err(Error::Unrecoverable("Audio file has ended".to_string()));
+        }
+        let sample_size = self.sample_size;
+        let mut ss = Sample::with_channels(self.buf.len(), sample_size, self.rate, self.cur_time());
+        for ch in self.buf.iter_mut() {
+            ch.clear();
+        }
+        let mut frames = 0;
+        while frames < sample_size {
+            if !self.decoder.next_frame(&mut self.frame)? {
+                self.finished = true;
+                break;
+            }
+            for (ch, v) in self.buf.iter_mut().zip(self.frame.iter()) {
+                ch.push(*v);
+            }
+            frames += 1;
+        }
+        if frames == 0 {
+            return Err(Error::Unrecoverable("Audio file has ended".to_string()));
+        }
+        self.position += frames as u64;
+        // pad the end of the file with silence so the sample is always full
+        for ch in self.buf.iter_mut() {
+            ch.resize(sample_size, 0.0);
+        }
+        ss.extend_channels(self.buf.iter().map(|ch| ch.as_slice()));
+        Ok(ss)
+    }
+}
+impl ActiveAudioSource for FileSource {
+    type InactiveType = AudioFile;
+    #[inline]
+    fn deactivate(self) -> Result<Self::InactiveType, Error> {
+        Ok(self.file)
+    }
+    #[inline]
+    fn cur_time(&self) -> u64 {
+        self.frames_to_time(self.position)
+    }
+    fn recv(&mut self) -> Result<StereoSample, Error> {
+        if self.file.realtime {
+            let deadline = self.deadline();
+            let now = Instant::now();
+            if deadline > now {
+                sleep(deadline - now);
+            }
+        }
+        self.read_sample()
+    }
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<StereoSample, Error> {
+        if !self.file.realtime && timeout == Duration::from_secs(0) {
+            /* When not running in realtime, samples are only decoded on demand,
+               so there is never a backlog of samples waiting to be received.
+               Without this try_iter() would consume the entire file.
+            */
+            return Err(Error::Timeout("Audio timed out".to_string()));
+        }
+        if self.file.realtime {
+            let deadline = self.deadline();
+            let now = Instant::now();
+            if deadline > now + timeout {
+                sleep(timeout);
+                return Err(Error::Timeout("Audio timed out".to_string()));
+            }
+            if deadline > now {
+                sleep(deadline - now);
+            }
+        }
+        self.read_sample()
+    }
+}
+
+#[cfg(all(test, feature = "hound"))]
+mod tests {
+    use super::*;
+    use hound::{WavSpec, WavWriter};
+
+    /// Writes a 16 bit WAV file to the temporary directory with `frames` of `channels`,
+    /// where each sample is its frame number, negated on odd channels.
+    fn write_wav(name: &str, channels: u16, rate: u32, frames: i16) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("synesthesia-{}-{}.wav", std::process::id(), name));
+        let spec = WavSpec {
+            channels,
+            sample_rate: rate,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for i in 0..frames {
+            for ch in 0..channels {
+                writer
+                    .write_sample(if ch % 2 == 0 { i } else { -i })
+                    .unwrap();
+            }
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    fn options(sample_size: usize) -> AudioSourceOptions {
+        AudioSourceOptions {
+            sample_size,
+            fft_size: sample_size.min(256),
+            ..AudioSourceOptions::default()
+        }
+    }
+
+    #[test]
+    fn reads_wav() {
+        let path = write_wav("stereo", 2, 8000, 1000);
+        let mut file = AudioFile::new(&path);
+        file.realtime = false;
+        let mut src = file.activate(options(256)).unwrap();
+        assert_eq!(src.rate(), 8000);
+        let mut samples = Vec::new();
+        while let Ok(ss) = src.recv() {
+            samples.push(ss);
+        }
+        std::fs::remove_file(&path).unwrap();
+        let times: Vec<u64> = samples.iter().map(|ss| ss.time()).collect();
+        assert_eq!(times, [0, 32_000, 64_000, 96_000]);
+        assert_eq!(src.cur_time(), 125_000);
+        for (i, ss) in samples.iter().enumerate() {
+            assert_eq!((ss.channels(), ss.len(), ss.rate()), (2, 256, 8000));
+            let frame = i * 256 + 10;
+            assert_eq!(ss.left()[10], frame as f32 / 32768.0);
+            assert_eq!(ss.right()[10], -(frame as f32) / 32768.0);
+        }
+        // the last sample is padded with silence
+        let last = &samples[3];
+        assert_eq!(last.left()[999 - 768], 999.0 / 32768.0);
+        assert!(last.left()[1000 - 768..].iter().all(|v| *v == 0.0));
+        assert!(src.recv().is_err());
+        assert!(src.try_recv().is_err());
+    }
+
+    #[test]
+    fn keeps_every_channel() {
+        let path = write_wav("channels", 6, 48000, 100);
+        let mut file = AudioFile::new(&path);
+        file.realtime = false;
+        let mut src = file.activate(options(64)).unwrap();
+        let first = src.recv().unwrap();
+        let second = src.recv().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(first.channels(), 6);
+        assert_eq!(first.channel(4)[1], 1.0 / 32768.0);
+        assert_eq!(first.channel(5)[1], -1.0 / 32768.0);
+        assert_eq!(second.time(), 64 * 1_000_000 / 48000);
+        assert_eq!(second.channel(0)[35], 99.0 / 32768.0);
+        assert_eq!(second.channel(0)[36], 0.0);
+        assert!(src.recv().is_err());
+    }
+
+    #[test]
+    fn rejects_missing_and_unknown_files() {
+        let dir = std::env::temp_dir();
+        let missing = AudioFile::new(dir.join("synesthesia-missing.wav"));
+        assert!(missing.activate(options(256)).is_err());
+        let unknown = AudioFile::new(dir.join("synesthesia-unknown.mp3"));
+        assert!(unknown.activate(options(256)).is_err());
+    }
+}
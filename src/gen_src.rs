@@ -0,0 +1,377 @@
+use crate::audio::{ActiveAudioSource, AudioSourceOptions, InactiveAudioSource, StereoSample};
+use crate::Error;
+use std::f64::consts::PI;
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// A test signal produced by a `SignalGenerator` for a single channel.
+///
+/// Frequencies are in Hz, `period` is in seconds and amplitudes are in the range [0.0, 1.0].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    Silence,
+    Sine {
+        freq: f32,
+        amp: f32,
+    },
+    /// A logarithmic sweep from `start` to `end` that repeats every `period`.
+    Sweep {
+        start: f32,
+        end: f32,
+        period: f32,
+        amp: f32,
+    },
+    WhiteNoise {
+        amp: f32,
+    },
+    PinkNoise {
+        amp: f32,
+    },
+    /// A train of single-sample impulses occuring `freq` times a second.
+    Impulse {
+        freq: f32,
+        amp: f32,
+    },
+}
+impl FromStr for Signal {
+    type Err = String;
+    /// Parses signals of the form `NAME[:PARAM...]`, such as
+    /// `sine:440`, `sweep:20:20000:10`, `pink`, or `impulse:2:1.0`.
+    /// A trailing optional parameter sets the amplitude, which otherwise defaults to 0.5.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let name = parts.next().unwrap();
+        let params = parts
+            .map(|p| {
+                f32::from_str(p).map_err(|e| format!("Invalid signal parameter {}: {:?}", p, e))
+            })
+            .collect::<Result<Vec<f32>, String>>()?;
+        let arg = |i: usize, default: Option<f32>| {
+            params
+                .get(i)
+                .copied()
+                .or(default)
+                .ok_or_else(|| format!("Signal {} is missing parameter {}", name, i + 1))
+        };
+        let sig = match name {
+            "silence" => Signal::Silence,
+            "sine" => Signal::Sine {
+                freq: arg(0, None)?,
+                amp: arg(1, Some(0.5))?,
+            },
+            "sweep" => Signal::Sweep {
+                start: arg(0, Some(20.0))?,
+                end: arg(1, Some(20000.0))?,
+                period: arg(2, Some(10.0))?,
+                amp: arg(3, Some(0.5))?,
+            },
+            "white" => Signal::WhiteNoise {
+                amp: arg(0, Some(0.5))?,
+            },
+            "pink" => Signal::PinkNoise {
+                amp: arg(0, Some(0.5))?,
+            },
+            "impulse" => Signal::Impulse {
+                freq: arg(0, Some(1.0))?,
+                amp: arg(1, Some(0.5))?,
+            },
+            _ => return Err(format!("Unknown signal: {}", name)),
+        };
+        if let Signal::Sweep { start, end, .. } = sig {
+            // the sweep is logarithmic, so it cannot start or end at 0 Hz
+            if !(start > 0.0 && end > 0.0) {
+                return Err(format!("Sweep frequencies must be above 0 Hz: {}", s));
+            }
+        }
+        Ok(sig)
+    }
+}
+
+/// Produces synthetic test signals with independent programs for the left and right channel.
+pub struct SignalGenerator {
+    pub left: Signal,
+    pub right: Signal,
+    pub rate: u32,
+    /// When true, samples are released at the rate they would be played back at.
+    /// When false, samples are generated as fast as the consumer can process them.
+    pub realtime: bool,
+}
+impl SignalGenerator {
+    pub fn new(left: Signal, right: Signal) -> Self {
+        SignalGenerator {
+            left,
+            right,
+            rate: 48000,
+            realtime: true,
+        }
+    }
+}
+impl InactiveAudioSource for SignalGenerator {
+    type ActiveType = GeneratorSource;
+    fn activate(self, options: AudioSourceOptions) -> Result<Self::ActiveType, Error> {
+        if self.rate == 0 {
+            return Err(Error::Unrecoverable(
+                "Signal generator must have a non-zero sample rate".to_string(),
+            ));
+        }
+        Ok(GeneratorSource {
+            left: Oscillator::new(self.left, 0x9E37_79B9),
+            right: Oscillator::new(self.right, 0x85EB_CA6B),
+            l_buf: vec![0.0; options.sample_size],
+            r_buf: vec![0.0; options.sample_size],
+            gen: self,
+            position: 0,
+            start: Instant::now(),
+        })
+    }
+}
+
+/// The running state of a single `Signal`.
+struct Oscillator {
+    signal: Signal,
+    phase: f64,
+    frame: u64,
+    rng: u32,
+    pink: [f32; 7],
+}
+impl Oscillator {
+    fn new(signal: Signal, seed: u32) -> Self {
+        Oscillator {
+            signal,
+            phase: 0.0,
+            frame: 0,
+            rng: seed,
+            pink: [0.0; 7],
+        }
+    }
+    /// Xorshift32 scaled to [-1.0, 1.0].
+    #[inline]
+    fn noise(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        (self.rng as f64 / u32::MAX as f64 * 2.0 - 1.0) as f32
+    }
+    fn fill(&mut self, out: &mut [f32], rate: u32) {
+        let rate = rate as f64;
+        for o in out.iter_mut() {
+            *o = match self.signal {
+                Signal::Silence => 0.0,
+                Signal::Sine { freq, amp } => {
+                    let v = (self.phase * 2.0 * PI).sin() as f32 * amp;
+                    self.phase = (self.phase + freq as f64 / rate).fract();
+                    v
+                }
+                Signal::Sweep {
+                    start,
+                    end,
+                    period,
+                    amp,
+                } => {
+                    let t = (self.frame as f64 / rate / period as f64).fract();
+                    let freq = start as f64 * (end as f64 / start as f64).powf(t);
+                    let v = (self.phase * 2.0 * PI).sin() as f32 * amp;
+                    self.phase = (self.phase + freq / rate).fract();
+                    v
+                }
+                Signal::WhiteNoise { amp } => self.noise() * amp,
+                Signal::PinkNoise { amp } => {
+                    // Paul Kellet's refined pink noise filter
+                    let w = self.noise();
+                    let b = &mut self.pink;
+                    b[0] = 0.99886 * b[0] + w * 0.0555179;
+                    b[1] = 0.99332 * b[1] + w * 0.0750759;
+                    b[2] = 0.96900 * b[2] + w * 0.1538520;
+                    b[3] = 0.86650 * b[3] + w * 0.3104856;
+                    b[4] = 0.55000 * b[4] + w * 0.5329522;
+                    b[5] = -0.7616 * b[5] - w * 0.0168980;
+                    let v = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + w * 0.5362;
+                    b[6] = w * 0.115926;
+                    v * 0.11 * amp
+                }
+                Signal::Impulse { freq, amp } => {
+                    let inc = freq as f64 / rate;
+                    let v = if self.phase < inc { amp } else { 0.0 };
+                    self.phase = (self.phase + inc).fract();
+                    v
+                }
+            };
+            self.frame += 1;
+        }
+    }
+}
+
+/// A running `SignalGenerator`.
+pub struct GeneratorSource {
+    gen: SignalGenerator,
+    left: Oscillator,
+    right: Oscillator,
+    l_buf: Vec<f32>,
+    r_buf: Vec<f32>,
+    position: u64,
+    start: Instant,
+}
+impl GeneratorSource {
+    #[inline]
+    fn frames_to_time(&self, frames: u64) -> u64 {
+        frames * 1_000_000 / self.gen.rate as u64
+    }
+    /// The instant the next sample would be complete if it was being captured live.
+    fn deadline(&self) -> Instant {
+        let end = self.position + self.l_buf.len() as u64;
+        self.start + Duration::from_micros(self.frames_to_time(end))
+    }
+    fn generate(&mut self) -> StereoSample {
+        let mut ss = StereoSample::new(self.l_buf.len(), self.gen.rate, self.cur_time());
+        self.left.fill(&mut self.l_buf, self.gen.rate);
+        self.right.fill(&mut self.r_buf, self.gen.rate);
+        ss.extend(&self.l_buf, &self.r_buf);
+        self.position += self.l_buf.len() as u64;
+        ss
+    }
+}
+impl ActiveAudioSource for GeneratorSource {
+    type InactiveType = SignalGenerator;
+    #[inline]
+    fn deactivate(self) -> Result<Self::InactiveType, Error> {
+        Ok(self.gen)
+    }
+    #[inline]
+    fn cur_time(&self) -> u64 {
+        self.frames_to_time(self.position)
+    }
+    fn recv(&mut self) -> Result<StereoSample, Error> {
+        if self.gen.realtime {
+            let deadline = self.deadline();
+            let now = Instant::now();
+            if deadline > now {
+                sleep(deadline - now);
+            }
+        }
+        Ok(self.generate())
+    }
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<StereoSample, Error> {
+        if self.gen.realtime {
+            let deadline = self.deadline();
+            let now = Instant::now();
+            if deadline > now + timeout {
+                sleep(timeout);
+                return Err(Error::Timeout("Audio timed out".to_string()));
+            }
+            if deadline > now {
+                sleep(deadline - now);
+            }
+        } else if timeout == Duration::from_secs(0) {
+            // samples are generated on demand, so none are ever waiting to be received
+            return Err(Error::Timeout("Audio timed out".to_string()));
+        }
+        Ok(self.generate())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spectrum::{SpectrumAnalyzer, SpectrumOptions};
+
+    fn generate(left: Signal, sample_size: usize) -> StereoSample {
+        let mut gen = SignalGenerator::new(left, Signal::Silence);
+        gen.realtime = false;
+        let options = AudioSourceOptions {
+            sample_size,
+            fft_size: 256,
+            ..AudioSourceOptions::default()
+        };
+        gen.activate(options).unwrap().recv().unwrap()
+    }
+
+    #[test]
+    fn parses() {
+        assert_eq!(Signal::from_str("silence"), Ok(Signal::Silence));
+        assert_eq!(
+            Signal::from_str("sine:440"),
+            Ok(Signal::Sine {
+                freq: 440.0,
+                amp: 0.5
+            })
+        );
+        assert_eq!(
+            Signal::from_str("sweep:100:1000"),
+            Ok(Signal::Sweep {
+                start: 100.0,
+                end: 1000.0,
+                period: 10.0,
+                amp: 0.5
+            })
+        );
+        assert_eq!(
+            Signal::from_str("impulse:2:1"),
+            Ok(Signal::Impulse {
+                freq: 2.0,
+                amp: 1.0
+            })
+        );
+        assert_eq!(Signal::from_str("pink"), Ok(Signal::PinkNoise { amp: 0.5 }));
+        assert_eq!(
+            Signal::from_str("white:0.1"),
+            Ok(Signal::WhiteNoise { amp: 0.1 })
+        );
+        assert!(Signal::from_str("sine").is_err());
+        assert!(Signal::from_str("sine:loud").is_err());
+        assert!(Signal::from_str("square:440").is_err());
+        assert!(Signal::from_str("sweep:0:1000").is_err());
+        assert!(Signal::from_str("sweep:20:0").is_err());
+    }
+
+    #[test]
+    fn sine_amplitude_and_frequency() {
+        // 3750 Hz is the center of bin 20 of a 256 point FFT at 48 kHz
+        let ss = generate(
+            Signal::Sine {
+                freq: 3750.0,
+                amp: 0.25,
+            },
+            768,
+        );
+        let peak = ss.left().iter().fold(0.0_f32, |m, v| m.max(v.abs()));
+        assert!((peak - 0.25).abs() < 0.001, "{}", peak);
+        let rms = (ss.left().iter().map(|v| v * v).sum::<f32>() / 768.0).sqrt();
+        assert!((rms - 0.25 / 2.0_f32.sqrt()).abs() < 0.001, "{}", rms);
+        assert!(ss.right().iter().all(|v| *v == 0.0));
+
+        let mut analyzer = SpectrumAnalyzer::new(256, 256, &SpectrumOptions::default());
+        let (mut left, mut right) = (vec![0.0; 129], vec![0.0; 129]);
+        analyzer.process(&ss, &mut left, &mut right);
+        let loudest = (0..129)
+            .max_by(|a, b| left[*a].partial_cmp(&left[*b]).unwrap())
+            .unwrap();
+        assert_eq!(loudest, 20);
+        assert!(right[20] < left[20] - 60.0, "{} {}", left[20], right[20]);
+    }
+
+    #[test]
+    fn impulse_period() {
+        // 1 kHz at 48 kHz is an impulse every 48 frames
+        let ss = generate(
+            Signal::Impulse {
+                freq: 1000.0,
+                amp: 0.8,
+            },
+            480,
+        );
+        let impulses: Vec<usize> = (0..480).filter(|i| ss.left()[*i] != 0.0).collect();
+        assert_eq!(impulses, (0..480).step_by(48).collect::<Vec<_>>());
+        assert!(impulses.iter().all(|i| ss.left()[*i] == 0.8));
+    }
+
+    #[test]
+    fn sample_times() {
+        let mut gen = SignalGenerator::new(Signal::Silence, Signal::Silence);
+        gen.realtime = false;
+        let mut src = gen.activate(AudioSourceOptions::default()).unwrap();
+        let times: Vec<u64> = (0..3).map(|_| src.recv().unwrap().time()).collect();
+        assert_eq!(times, [0, 16_000, 32_000]);
+        assert!(src.try_recv().is_err());
+    }
+}
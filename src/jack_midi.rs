@@ -0,0 +1,290 @@
+use crate::midi::{
+    ActiveMidiSource, InactiveMidiSource, MidiEvent, MidiMessage, MidiParser, MidiSink,
+};
+use crate::ring::Ring;
+use crate::Error;
+use jack::{
+    AsyncClient, Client, Control, MidiIn, MidiOut, NotificationHandler, Port, ProcessScope, RawMidi,
+};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// The number of chunks of MIDI bytes that can be queued for the consumer.
+const QUEUE_SIZE: usize = 256;
+const CHUNK_SIZE: usize = 16;
+/// How often a waiting consumer checks for new MIDI.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Raw MIDI bytes of one event. Longer events received, such as SysEx,
+/// are split into several chunks with the same time.
+#[derive(Clone, Copy)]
+struct Chunk {
+    time: u64,
+    len: u8,
+    bytes: [u8; CHUNK_SIZE],
+}
+
+struct MidiNotifier;
+impl NotificationHandler for MidiNotifier {}
+
+struct MidiHandler {
+    port: Port<MidiIn>,
+    queue: Arc<Ring<Chunk>>,
+    overruns: Arc<AtomicUsize>,
+}
+impl jack::ProcessHandler for MidiHandler {
+    fn process(&mut self, client: &Client, ps: &ProcessScope) -> Control {
+        /* This runs on the realtime thread, so the bytes are only copied here and parsed by
+           the consumer. An event is only queued if all of its chunks fit, so the consumer
+           never sees part of one. Only this thread pushes, so the room left can only grow.
+        */
+        let start = ps.last_frame_time();
+        for raw in self.port.iter(ps) {
+            let time = client.frames_to_time(start.wrapping_add(raw.time));
+            let chunks = raw.bytes.chunks(CHUNK_SIZE).len();
+            if self.queue.capacity() - self.queue.len() < chunks {
+                self.overruns.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            for bytes in raw.bytes.chunks(CHUNK_SIZE) {
+                let mut chunk = Chunk {
+                    time,
+                    len: bytes.len() as u8,
+                    bytes: [0; CHUNK_SIZE],
+                };
+                chunk.bytes[..bytes.len()].copy_from_slice(bytes);
+                if self.queue.push(chunk).is_err() {
+                    unreachable!("there is room for every chunk of the event");
+                }
+            }
+        }
+        Control::Continue
+    }
+}
+
+impl InactiveMidiSource for Client {
+    type ActiveType = JackMidiSource;
+    fn activate(self) -> Result<Self::ActiveType, Error> {
+        let port = self.register_port("synesthesia_midi_in", MidiIn)?;
+        let queue = Arc::new(Ring::new(QUEUE_SIZE));
+        let overruns = Arc::new(AtomicUsize::new(0));
+        let handler = MidiHandler {
+            port,
+            queue: queue.clone(),
+            overruns: overruns.clone(),
+        };
+        let a_client = self.activate_async(MidiNotifier, handler)?;
+        Ok(JackMidiSource {
+            a_client,
+            queue,
+            overruns,
+            parser: MidiParser::new(),
+            events: Vec::new(),
+            pending: VecDeque::new(),
+        })
+    }
+}
+
+/// Receives MIDI from a JACK MIDI input port, timestamped with the JACK frame time
+/// in microseconds like the samples of a `JackSource`.
+///
+/// Iterating blocks until an event arrives.
+pub struct JackMidiSource {
+    a_client: AsyncClient<MidiNotifier, MidiHandler>,
+    queue: Arc<Ring<Chunk>>,
+    overruns: Arc<AtomicUsize>,
+    parser: MidiParser,
+    events: Vec<MidiEvent>,
+    pending: VecDeque<MidiMessage>,
+}
+impl JackMidiSource {
+    /// The number of MIDI events discarded because the consumer was not keeping up.
+    #[inline]
+    pub fn overruns(&self) -> usize {
+        self.overruns.load(Ordering::Relaxed)
+    }
+    fn decode(&mut self, chunk: Chunk) {
+        self.parser
+            .parse(&chunk.bytes[..chunk.len as usize], &mut self.events);
+        let time = chunk.time;
+        self.pending.extend(
+            self.events
+                .drain(..)
+                .map(|event| MidiMessage { time, event }),
+        );
+    }
+    /// Waits up to `timeout` for the next event.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<MidiMessage, Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(msg) = self.try_recv() {
+                return Ok(msg);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout("MIDI timed out".to_string()));
+            }
+            sleep(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+}
+impl Iterator for JackMidiSource {
+    type Item = MidiMessage;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(msg) = self.try_recv() {
+                return Some(msg);
+            }
+            sleep(POLL_INTERVAL);
+        }
+    }
+}
+impl ActiveMidiSource for JackMidiSource {
+    type InactiveType = Client;
+    #[inline]
+    fn deactivate(self) -> Result<Self::InactiveType, Error> {
+        Ok(self.a_client.deactivate()?.0)
+    }
+    #[inline]
+    fn cur_time(&self) -> u64 {
+        let client = self.a_client.as_client();
+        client.frames_to_time(client.frame_time())
+    }
+    fn try_recv(&mut self) -> Option<MidiMessage> {
+        while self.pending.is_empty() {
+            let chunk = self.queue.pop()?;
+            self.decode(chunk);
+        }
+        self.pending.pop_front()
+    }
+}
+
+const CLOCK: u8 = 0xF8;
+const START: u8 = 0xFA;
+const CONTINUE: u8 = 0xFB;
+const STOP: u8 = 0xFC;
+/// The fastest tempo the clock is sent at.
+const MAX_BPM: f32 = 999.0;
+
+struct MidiOutHandler {
+    port: Port<MidiOut>,
+    queue: Arc<Ring<Chunk>>,
+    /// The tempo of the clock as the bits of an `f32`.
+    bpm: Arc<AtomicU32>,
+    /// The offset of the next clock from the start of the cycle, in frames.
+    next_clock: f64,
+    clock_running: bool,
+    /// The clock has been started before, so it continues rather than starting again.
+    clock_started: bool,
+}
+impl jack::ProcessHandler for MidiOutHandler {
+    fn process(&mut self, client: &Client, ps: &ProcessScope) -> Control {
+        let mut writer = self.port.writer(ps);
+        let mut write = |time: u32, bytes: &[u8]| {
+            // a full port buffer drops the rest of the messages of this cycle
+            writer.write(&RawMidi { time, bytes }).is_ok()
+        };
+        while let Some(chunk) = self.queue.pop() {
+            write(0, &chunk.bytes[..chunk.len as usize]);
+        }
+        let bpm = f32::from_bits(self.bpm.load(Ordering::Relaxed));
+        let frames = ps.n_frames() as f64;
+        if bpm > 0.0 && bpm.is_finite() {
+            if !self.clock_running {
+                self.clock_running = true;
+                self.next_clock = 0.0;
+                let start = if self.clock_started { CONTINUE } else { START };
+                self.clock_started = true;
+                write(0, &[start]);
+            }
+            let bpm = bpm.min(MAX_BPM) as f64;
+            let interval = (client.sample_rate() as f64 * 60.0 / (bpm * 24.0)).max(1.0);
+            while self.next_clock < frames {
+                write(self.next_clock as u32, &[CLOCK]);
+                self.next_clock += interval;
+            }
+            self.next_clock -= frames;
+        } else if self.clock_running {
+            self.clock_running = false;
+            write(0, &[STOP]);
+        }
+        Control::Continue
+    }
+}
+
+/// Sends MIDI to a JACK MIDI output port, along with MIDI clock when a tempo is set.
+/// The clock is started the first time and continued after it is stopped.
+///
+/// Messages are sent at the start of the next JACK cycle. Messages longer than
+/// 16 bytes, such as long SysEx messages, cannot be sent.
+pub struct JackMidiSink {
+    a_client: AsyncClient<MidiNotifier, MidiOutHandler>,
+    queue: Arc<Ring<Chunk>>,
+    bpm: Arc<AtomicU32>,
+    overruns: usize,
+    bytes: Vec<u8>,
+}
+impl JackMidiSink {
+    pub fn new(client: Client) -> Result<Self, Error> {
+        let port = client.register_port("synesthesia_midi_out", MidiOut)?;
+        let queue = Arc::new(Ring::new(QUEUE_SIZE));
+        let bpm = Arc::new(AtomicU32::new(0.0f32.to_bits()));
+        let handler = MidiOutHandler {
+            port,
+            queue: queue.clone(),
+            bpm: bpm.clone(),
+            next_clock: 0.0,
+            clock_running: false,
+            clock_started: false,
+        };
+        let a_client = client.activate_async(MidiNotifier, handler)?;
+        Ok(JackMidiSink {
+            a_client,
+            queue,
+            bpm,
+            overruns: 0,
+            bytes: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+    /// The number of messages discarded because the JACK client was not keeping up.
+    #[inline]
+    pub fn overruns(&self) -> usize {
+        self.overruns
+    }
+    pub fn deactivate(self) -> Result<Client, Error> {
+        Ok(self.a_client.deactivate()?.0)
+    }
+}
+impl MidiSink for JackMidiSink {
+    fn send(&mut self, msgs: &[MidiMessage]) -> Result<(), Error> {
+        for msg in msgs {
+            self.bytes.clear();
+            msg.event.write(&mut self.bytes);
+            if self.bytes.len() > CHUNK_SIZE {
+                return Err(Error::Unrecoverable(format!(
+                    "MIDI message is too long to send: {} bytes",
+                    self.bytes.len()
+                )));
+            }
+            let mut chunk = Chunk {
+                time: msg.time,
+                len: self.bytes.len() as u8,
+                bytes: [0; CHUNK_SIZE],
+            };
+            chunk.bytes[..self.bytes.len()].copy_from_slice(&self.bytes);
+            if self.queue.push(chunk).is_err() {
+                self.overruns += 1;
+            }
+        }
+        Ok(())
+    }
+    #[inline]
+    fn set_tempo(&mut self, bpm: f32) {
+        // NaN is stopped as well, as `max` ignores it
+        let bpm = bpm.max(0.0).min(MAX_BPM);
+        self.bpm.store(bpm.to_bits(), Ordering::Relaxed);
+    }
+}
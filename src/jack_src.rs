@@ -1,8 +1,11 @@
-use crate::audio::{ActiveAudioSource, AudioSourceOptions, InactiveAudioSource, StereoSample};
+use crate::audio::{ActiveAudioSource, AudioSourceOptions, InactiveAudioSource, Sample};
+use crate::ring::Ring;
 use crate::Error;
 use jack::{AsyncClient, AudioIn, Client, Control, NotificationHandler};
 use std::mem;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
 use std::time::{Duration, Instant};
 
 pub struct EventHandler {
@@ -54,30 +57,62 @@ impl NotificationHandler for EventHandler {
         Control::Continue
     }
 }
+/// The number of completed samples waiting for the consumer.
+const QUEUE_SIZE: usize = 2;
+/// The number of preallocated samples shared between the process callback and the consumer:
+/// one being filled, the queued ones, one held by the consumer and one spare.
+const POOL_SIZE: usize = QUEUE_SIZE + 3;
+/// How often a waiting consumer checks for a new sample.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
 struct FrameHandler {
-    sample: StereoSample,
-    sender: mpsc::SyncSender<StereoSample>,
-    left: jack::Port<jack::AudioIn>,
-    right: jack::Port<jack::AudioIn>,
-    sample_size: usize,
+    sample: Sample,
+    queue: Arc<Ring<Sample>>,
+    pool: Arc<Ring<Sample>>,
+    spare: Option<Sample>,
+    overruns: Arc<AtomicUsize>,
+    ports: Vec<jack::Port<jack::AudioIn>>,
 }
 impl jack::ProcessHandler for FrameHandler {
     fn process(&mut self, client: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
-        let in_l_sample = self.left.as_slice(ps);
-        let in_r_sample = self.right.as_slice(ps);
-        if self.sample.extend(in_l_sample, in_r_sample) {
-            /* TODO: evaluate potential performance gains by eliminating allocation,
-               that will be dropped every 8 audio frames. This is probably
-               expensive and could in theory be replace by either some kind of
-               mutex protected circular buffer or perhaps passing allocated Vec
-               in a return mpsc-channel. Profiling needs to be done to determine
-               where this thread is actually spending time. It could be that this
-               is just a neccassaryly expensive component.
+        let input = self.ports.iter().map(|p| p.as_slice(ps));
+        if self.sample.extend_channels(input) {
+            /* This runs on the realtime thread, so it must never allocate, free or block.
+               Empty samples are taken from the pool that the consumer refills through
+               `JackSource::recycle()`. If the consumer has fallen behind, the oldest
+               queued sample is discarded and its buffers are reused for the next one.
             */
             let cur_time = client.frames_to_time(client.frame_time());
-            let mut ss = StereoSample::new(self.sample_size, client.sample_rate() as u32, cur_time);
-            mem::swap(&mut ss, &mut self.sample); // in theory this should run without issue
-            self.sender.send(ss).unwrap();
+            let rate = client.sample_rate() as u32;
+            let next = match self.spare.take().or_else(|| self.pool.pop()) {
+                Some(ss) => Some(ss),
+                None => {
+                    let oldest = self.queue.pop();
+                    if oldest.is_some() {
+                        self.overruns.fetch_add(1, Ordering::Relaxed);
+                    }
+                    oldest
+                }
+            };
+            match next {
+                Some(mut ss) => {
+                    ss.reset(rate, cur_time);
+                    mem::swap(&mut ss, &mut self.sample);
+                    if let Err(ss) = self.queue.push(ss) {
+                        self.overruns.fetch_add(1, Ordering::Relaxed);
+                        self.spare = self.queue.pop();
+                        // only this thread pushes, so there is now room
+                        if let Err(ss) = self.queue.push(ss) {
+                            self.spare = Some(ss);
+                        }
+                    }
+                }
+                None => {
+                    // the consumer holds every buffer, so there is nothing older to discard
+                    self.overruns.fetch_add(1, Ordering::Relaxed);
+                    self.sample.reset(rate, cur_time);
+                }
+            }
         }
         jack::Control::Continue
     }
@@ -85,28 +120,70 @@ impl jack::ProcessHandler for FrameHandler {
 impl InactiveAudioSource for Client {
     type ActiveType = JackSource;
     fn activate(self, options: AudioSourceOptions) -> Result<Self::ActiveType, Error> {
-        let left = self.register_port("synesthesia_left", AudioIn)?;
-        let right = self.register_port("synesthesia_right", AudioIn)?;
-        let (sender, recv) = mpsc::sync_channel(1);
+        // stereo keeps the port names used before any other channel count was supported
+        let ports = match options.channels {
+            2 => vec![
+                self.register_port("synesthesia_left", AudioIn)?,
+                self.register_port("synesthesia_right", AudioIn)?,
+            ],
+            n => (1..=n)
+                .map(|i| self.register_port(&format!("synesthesia_{}", i), AudioIn))
+                .collect::<Result<_, _>>()?,
+        };
+        let channels = options.channels;
+        let queue = Arc::new(Ring::new(QUEUE_SIZE));
+        let pool = Arc::new(Ring::new(POOL_SIZE));
+        let rate = self.sample_rate() as u32;
+        for _ in 1..POOL_SIZE {
+            let ss = Sample::with_channels(channels, options.sample_size, rate, 0);
+            if pool.push(ss).is_err() {
+                unreachable!("the pool has room for every sample");
+            }
+        }
+        let overruns = Arc::new(AtomicUsize::new(0));
         let handler = FrameHandler {
-            sample: StereoSample::new(
-                768,
-                self.sample_rate() as u32,
+            sample: Sample::with_channels(
+                channels,
+                options.sample_size,
+                rate,
                 self.frames_to_time(self.frame_time()),
             ),
-            left,
-            right,
-            sender,
-            sample_size: 768,
+            queue: queue.clone(),
+            pool: pool.clone(),
+            spare: None,
+            overruns: overruns.clone(),
+            ports,
         };
         let ev = EventHandler::new(options.stats);
         let a_client = self.activate_async(ev, handler)?;
-        Ok(JackSource { a_client, recv })
+        Ok(JackSource {
+            a_client,
+            queue,
+            pool,
+            overruns,
+            sample_size: options.sample_size,
+            channels,
+        })
     }
 }
 pub struct JackSource {
     a_client: AsyncClient<EventHandler, FrameHandler>,
-    recv: mpsc::Receiver<StereoSample>,
+    queue: Arc<Ring<Sample>>,
+    pool: Arc<Ring<Sample>>,
+    overruns: Arc<AtomicUsize>,
+    sample_size: usize,
+    channels: usize,
+}
+impl JackSource {
+    /// Consumers that drop samples instead of recycling them drain the pool,
+    /// so replacements are allocated here instead of on the realtime thread.
+    fn refill(&mut self) {
+        if self.pool.is_empty() {
+            let rate = self.a_client.as_client().sample_rate() as u32;
+            let ss = Sample::with_channels(self.channels, self.sample_size, rate, 0);
+            self.recycle(ss);
+        }
+    }
 }
 impl ActiveAudioSource for JackSource {
     type InactiveType = Client;
@@ -119,17 +196,36 @@ impl ActiveAudioSource for JackSource {
         let client = self.a_client.as_client();
         client.frames_to_time(client.frame_time())
     }
-    fn recv(&mut self) -> Result<StereoSample, Error> {
-        self.recv
-            .recv()
-            .map_err(|_| Error::Unrecoverable("Audio producer is disconnected".to_string()))
+    fn recv(&mut self) -> Result<Sample, Error> {
+        self.refill();
+        loop {
+            if let Some(ss) = self.queue.pop() {
+                return Ok(ss);
+            }
+            sleep(POLL_INTERVAL);
+        }
     }
-    fn recv_timeout(&mut self, timeout: Duration) -> Result<StereoSample, Error> {
-        self.recv.recv_timeout(timeout).map_err(|x| match x {
-            mpsc::RecvTimeoutError::Timeout => Error::Timeout("Audio timed out".to_string()),
-            mpsc::RecvTimeoutError::Disconnected => {
-                Error::Unrecoverable("Audio producer is disconnected".to_string())
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<Sample, Error> {
+        self.refill();
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(ss) = self.queue.pop() {
+                return Ok(ss);
             }
-        })
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout("Audio timed out".to_string()));
+            }
+            sleep(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+    #[inline]
+    fn recycle(&mut self, ss: Sample) {
+        // if the pool is already full the sample is simply dropped
+        let _ = self.pool.push(ss);
+    }
+    #[inline]
+    fn overruns(&self) -> usize {
+        self.overruns.load(Ordering::Relaxed)
     }
 }
@@ -1,8 +1,29 @@
+pub mod agc;
 pub mod audio;
+pub mod bands;
+pub mod beat;
 pub mod control;
+pub mod curve;
+pub mod effect;
+#[cfg(any(feature = "hound", feature = "claxon"))]
+pub mod file_src;
+pub mod gen_src;
+#[cfg(feature = "jack")]
+pub mod jack_midi;
 #[cfg(feature = "jack")]
 pub mod jack_src;
 pub mod midi;
+pub mod midi_clock;
+pub mod midi_map;
+pub mod midi_out;
+pub mod net_src;
+pub mod pcm_src;
+pub mod record;
+pub mod replay;
+pub mod ring;
+pub mod smf_src;
+pub mod spectrum;
+pub mod weighting;
 
 #[cfg(feature = "jack")]
 use jack;
@@ -13,9 +34,14 @@ use lecp;
 pub enum Error {
     Unrecoverable(String),
     Timeout(String),
+    Io(std::io::Error),
     #[cfg(feature = "jack")]
     Jack(jack::Error),
     Lecp(lecp::Error),
+    #[cfg(feature = "hound")]
+    Wav(hound::Error),
+    #[cfg(feature = "claxon")]
+    Flac(claxon::Error),
 }
 
 #[cfg(feature = "jack")]
@@ -25,12 +51,32 @@ impl From<jack::Error> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
 impl From<lecp::Error> for Error {
     fn from(err: lecp::Error) -> Self {
         Error::Lecp(err)
     }
 }
 
+#[cfg(feature = "hound")]
+impl From<hound::Error> for Error {
+    fn from(err: hound::Error) -> Self {
+        Error::Wav(err)
+    }
+}
+
+#[cfg(feature = "claxon")]
+impl From<claxon::Error> for Error {
+    fn from(err: claxon::Error) -> Self {
+        Error::Flac(err)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
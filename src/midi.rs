@@ -5,27 +5,323 @@ pub trait InactiveMidiSource {
     fn activate(self) -> Result<Self::ActiveType, Error>;
 }
 
+/// A source of MIDI messages. Iterating waits for the next message.
 pub trait ActiveMidiSource: Iterator<Item = MidiMessage> {
     type InactiveType;
     fn deactivate(self) -> Result<Self::InactiveType, Error>;
     fn cur_time(&self) -> u64;
+    /// Returns the next message if one has already arrived.
+    fn try_recv(&mut self) -> Option<MidiMessage>;
 }
 
-#[derive(Debug)]
+/// A destination for MIDI messages.
+pub trait MidiSink: Send {
+    /// Sends `msgs` as soon as possible, in order.
+    fn send(&mut self, msgs: &[MidiMessage]) -> Result<(), Error>;
+    /// Sends MIDI clock at `bpm`, or stops it when `bpm` is 0.0.
+    /// Sinks that cannot send clock ignore this.
+    #[inline]
+    fn set_tempo(&mut self, _bpm: f32) {}
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct MidiMessage {
     pub time: u64,
     pub event: MidiEvent,
 }
 
-#[derive(Debug)]
+/// A MIDI event. Channel messages start with the channel, from 0 to 15.
+#[derive(Clone, Debug, PartialEq)]
 pub enum MidiEvent {
-    NoteOn(u8, u8),
-    NoteOff(u8, u8),
-    PlyPrs(u8, u8),
-    CtrlChg(u8, u8),
-    ProgChg(u8),
-    ChnlPrs(u8),
-    PitchWheel(u16),
+    /// Channel, note and velocity. A velocity of zero is commonly used as a note off.
+    NoteOn(u8, u8, u8),
+    /// Channel, note and release velocity.
+    NoteOff(u8, u8, u8),
+    /// Channel, note and pressure.
+    PlyPrs(u8, u8, u8),
+    /// Channel, controller and value.
+    CtrlChg(u8, u8, u8),
+    /// Channel and program.
+    ProgChg(u8, u8),
+    /// Channel and pressure.
+    ChnlPrs(u8, u8),
+    /// Channel and the 14 bit position of the wheel, centered on 0x2000.
+    PitchWheel(u8, u16),
+    /// The bytes of a system exclusive message between the 0xF0 and 0xF7.
+    SysEx(Vec<u8>),
+    /// A MIDI time code quarter frame.
+    TimeCode(u8),
+    /// The 14 bit song position, in sixteenth notes.
+    SongPos(u16),
+    SongSel(u8),
+    TuneReq,
+    /// A system real time message, from 0xF8 to 0xFF.
     RealTime(u8),
     Unrecongized,
 }
+impl MidiEvent {
+    /// The channel of a channel message.
+    pub fn channel(&self) -> Option<u8> {
+        match self {
+            MidiEvent::NoteOn(c, _, _)
+            | MidiEvent::NoteOff(c, _, _)
+            | MidiEvent::PlyPrs(c, _, _)
+            | MidiEvent::CtrlChg(c, _, _)
+            | MidiEvent::ProgChg(c, _)
+            | MidiEvent::ChnlPrs(c, _)
+            | MidiEvent::PitchWheel(c, _) => Some(*c),
+            _ => None,
+        }
+    }
+    /// Appends the bytes of the event to `out`, always including the status byte.
+    ///
+    /// Values are masked to the bits available in MIDI and `Unrecongized` writes nothing.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let status = |kind: u8, c: &u8| kind | (c & 0x0F);
+        match self {
+            MidiEvent::NoteOff(c, a, b) => out.extend(&[status(0x80, c), a & 0x7F, b & 0x7F]),
+            MidiEvent::NoteOn(c, a, b) => out.extend(&[status(0x90, c), a & 0x7F, b & 0x7F]),
+            MidiEvent::PlyPrs(c, a, b) => out.extend(&[status(0xA0, c), a & 0x7F, b & 0x7F]),
+            MidiEvent::CtrlChg(c, a, b) => out.extend(&[status(0xB0, c), a & 0x7F, b & 0x7F]),
+            MidiEvent::ProgChg(c, a) => out.extend(&[status(0xC0, c), a & 0x7F]),
+            MidiEvent::ChnlPrs(c, a) => out.extend(&[status(0xD0, c), a & 0x7F]),
+            MidiEvent::PitchWheel(c, v) => {
+                out.extend(&[status(0xE0, c), (v & 0x7F) as u8, (v >> 7 & 0x7F) as u8])
+            }
+            MidiEvent::SysEx(data) => {
+                out.push(0xF0);
+                out.extend(data.iter().map(|b| b & 0x7F));
+                out.push(0xF7);
+            }
+            MidiEvent::TimeCode(v) => out.extend(&[0xF1, v & 0x7F]),
+            MidiEvent::SongPos(v) => out.extend(&[0xF2, (v & 0x7F) as u8, (v >> 7 & 0x7F) as u8]),
+            MidiEvent::SongSel(v) => out.extend(&[0xF3, v & 0x7F]),
+            MidiEvent::TuneReq => out.push(0xF6),
+            MidiEvent::RealTime(v) => out.push(v | 0xF8),
+            MidiEvent::Unrecongized => (),
+        }
+    }
+    /// The bytes of the event. See `write()`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(3);
+        self.write(&mut out);
+        out
+    }
+}
+
+/// The number of data bytes following a status byte, or `None` for SysEx.
+pub(crate) fn data_len(status: u8) -> Option<usize> {
+    match status {
+        0xC0..=0xDF | 0xF1 | 0xF3 => Some(1),
+        0x80..=0xEF | 0xF2 => Some(2),
+        0xF0 => None,
+        _ => Some(0),
+    }
+}
+
+/// Decodes a stream of MIDI bytes into events.
+///
+/// Messages may be split across calls to `push()` and running status is supported.
+/// Real time messages are decoded as soon as they arrive, even in the middle of another message.
+#[derive(Clone, Debug, Default)]
+pub struct MidiParser {
+    status: Option<u8>,
+    data: [u8; 2],
+    len: usize,
+    sysex: Option<Vec<u8>>,
+}
+impl MidiParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Forget any partial message and the running status.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+    /// Decodes all of `bytes`, appending the complete events to `out`.
+    pub fn parse(&mut self, bytes: &[u8], out: &mut Vec<MidiEvent>) {
+        for b in bytes {
+            self.push(*b, out);
+        }
+    }
+    /// Decodes one byte, appending the events it completes to `out`.
+    ///
+    /// A SysEx message is also ended by any status byte other than a real time message.
+    /// If that byte is a complete message by itself, such as a tune request,
+    /// both the SysEx message and the other event are appended.
+    pub fn push(&mut self, b: u8, out: &mut Vec<MidiEvent>) {
+        if let Some(event) = self.decode(b, out) {
+            out.push(event);
+        }
+    }
+    /// Decodes one byte, returning the event it completes other than a SysEx message
+    /// ended by a status byte, which is appended to `out`.
+    fn decode(&mut self, b: u8, out: &mut Vec<MidiEvent>) -> Option<MidiEvent> {
+        if b >= 0xF8 {
+            return Some(MidiEvent::RealTime(b));
+        }
+        if b >= 0x80 {
+            if let Some(sysex) = self.sysex.take() {
+                out.push(MidiEvent::SysEx(sysex));
+            }
+            self.len = 0;
+            return match b {
+                0xF7 => {
+                    self.status = None;
+                    None
+                }
+                0xF0 => {
+                    self.status = None;
+                    self.sysex = Some(Vec::new());
+                    None
+                }
+                0xF6 => {
+                    self.status = None;
+                    Some(MidiEvent::TuneReq)
+                }
+                0xF4 | 0xF5 => {
+                    self.status = None;
+                    Some(MidiEvent::Unrecongized)
+                }
+                _ => {
+                    self.status = Some(b);
+                    None
+                }
+            };
+        }
+        if let Some(sysex) = &mut self.sysex {
+            sysex.push(b);
+            return None;
+        }
+        let status = self.status?; // data without a status is ignored
+        self.data[self.len] = b;
+        self.len += 1;
+        if Some(self.len) != data_len(status) {
+            return None;
+        }
+        self.len = 0;
+        if status >= 0xF0 {
+            self.status = None; // system common messages cancel running status
+        }
+        let (c, a, b) = (status & 0x0F, self.data[0], self.data[1]);
+        let wide = a as u16 | (b as u16) << 7;
+        Some(match status {
+            0x80..=0x8F => MidiEvent::NoteOff(c, a, b),
+            0x90..=0x9F => MidiEvent::NoteOn(c, a, b),
+            0xA0..=0xAF => MidiEvent::PlyPrs(c, a, b),
+            0xB0..=0xBF => MidiEvent::CtrlChg(c, a, b),
+            0xC0..=0xCF => MidiEvent::ProgChg(c, a),
+            0xD0..=0xDF => MidiEvent::ChnlPrs(c, a),
+            0xE0..=0xEF => MidiEvent::PitchWheel(c, wide),
+            0xF1 => MidiEvent::TimeCode(a),
+            0xF2 => MidiEvent::SongPos(wide),
+            0xF3 => MidiEvent::SongSel(a),
+            _ => MidiEvent::Unrecongized,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MidiEvent::*;
+
+    fn parse(bytes: &[u8]) -> Vec<MidiEvent> {
+        let mut out = Vec::new();
+        MidiParser::new().parse(bytes, &mut out);
+        out
+    }
+
+    #[test]
+    fn running_status() {
+        assert_eq!(
+            parse(&[0x91, 60, 100, 62, 0, 0xB2, 7, 127, 8, 0]),
+            vec![
+                NoteOn(1, 60, 100),
+                NoteOn(1, 62, 0),
+                CtrlChg(2, 7, 127),
+                CtrlChg(2, 8, 0)
+            ]
+        );
+        // system common messages cancel running status, so the data that follows is ignored
+        assert_eq!(
+            parse(&[0x90, 60, 100, 0xF3, 5, 62, 0]),
+            vec![NoteOn(0, 60, 100), SongSel(5)]
+        );
+        assert_eq!(
+            parse(&[0x90, 60, 100, 0xF7, 62, 0]),
+            vec![NoteOn(0, 60, 100)]
+        );
+        // data without any status is ignored
+        assert_eq!(parse(&[60, 100, 0xC3, 5]), vec![ProgChg(3, 5)]);
+    }
+
+    #[test]
+    fn realtime_within_messages() {
+        assert_eq!(
+            parse(&[0x90, 0xF8, 60, 0xFA, 100, 61, 0xFC, 90]),
+            vec![
+                RealTime(0xF8),
+                RealTime(0xFA),
+                NoteOn(0, 60, 100),
+                RealTime(0xFC),
+                NoteOn(0, 61, 90)
+            ]
+        );
+        assert_eq!(
+            parse(&[0xF0, 1, 0xF8, 2, 0xF7]),
+            vec![RealTime(0xF8), SysEx(vec![1, 2])]
+        );
+    }
+
+    #[test]
+    fn interrupted_sysex() {
+        assert_eq!(
+            parse(&[0xF0, 1, 2, 0x90, 60, 100]),
+            vec![SysEx(vec![1, 2]), NoteOn(0, 60, 100)]
+        );
+        assert_eq!(
+            parse(&[0xF0, 1, 0xF0, 2, 0xF7]),
+            vec![SysEx(vec![1]), SysEx(vec![2])]
+        );
+        assert_eq!(parse(&[0xF0, 1, 0xF6]), vec![SysEx(vec![1]), TuneReq]);
+
+        // a byte at a time
+        let mut parser = MidiParser::new();
+        let events: Vec<_> = [0xF0, 1, 2, 0xF6, 0xB0, 1, 2]
+            .iter()
+            .map(|b| {
+                let mut out = Vec::new();
+                parser.push(*b, &mut out);
+                out
+            })
+            .collect();
+        assert_eq!(events[3], [SysEx(vec![1, 2]), TuneReq]);
+        assert_eq!(events[6], [CtrlChg(0, 1, 2)]);
+        assert!(events[..3].iter().chain(&events[4..6]).all(Vec::is_empty));
+    }
+
+    #[test]
+    fn writes_what_it_parses() {
+        let events = vec![
+            NoteOff(15, 1, 2),
+            NoteOn(0, 127, 1),
+            PlyPrs(3, 4, 5),
+            CtrlChg(4, 5, 6),
+            ProgChg(5, 6),
+            ChnlPrs(6, 7),
+            PitchWheel(7, 0x2000),
+            SysEx(vec![0x7E, 0x7F, 9, 1]),
+            TimeCode(0x35),
+            SongPos(0x3FFF),
+            SongSel(3),
+            TuneReq,
+            RealTime(0xFE),
+        ];
+        let mut bytes = Vec::new();
+        for e in events.iter() {
+            e.write(&mut bytes);
+        }
+        assert_eq!(parse(&bytes), events);
+    }
+}
@@ -0,0 +1,198 @@
+use crate::midi::{MidiEvent, MidiMessage};
+use std::collections::VecDeque;
+
+/// MIDI clock messages are sent 24 times per quarter note.
+pub const CLOCKS_PER_BEAT: u64 = 24;
+
+const CLOCK: u8 = 0xF8;
+const START: u8 = 0xFA;
+const CONTINUE: u8 = 0xFB;
+const STOP: u8 = 0xFC;
+
+/// The state of a `MidiClock`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ClockInfo {
+    /// True between a start or continue message and a stop message.
+    /// `MidiClock::take_beat()` also clears it once the clocks stop arriving.
+    pub running: bool,
+    /// The tempo of the clock, or 0.0 if it is not yet known.
+    pub bpm: f32,
+    /// The number of clocks since the start of the song.
+    pub position: u64,
+    /// The position within the current beat in the range [0.0, 1.0).
+    pub phase: f32,
+    /// True if a beat started since `MidiClock::take_beat()` was last called.
+    pub beat: bool,
+}
+
+/// Follows the tempo and position of an external MIDI clock, such as from DJ software,
+/// using the clock, start, stop, continue and song position pointer messages.
+///
+/// The tempo is measured from the timestamps of the clock messages over the last beat.
+#[derive(Clone, Debug, Default)]
+pub struct MidiClock {
+    info: ClockInfo,
+    ticks: VecDeque<u64>,
+    /// The next clock is the first after a start or a jump, which does not advance the position.
+    fresh: bool,
+}
+impl MidiClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    #[inline]
+    pub fn info(&self) -> ClockInfo {
+        self.info
+    }
+    /// Returns the state of the clock at `time`, on the same clock as the times of the
+    /// messages, and clears its `beat` flag.
+    ///
+    /// The clock is shown as stopped once no clock has arrived for about two clock intervals,
+    /// such as when the source goes away without sending a stop message.
+    pub fn take_beat(&mut self, time: u64) -> ClockInfo {
+        let mut info = self.info;
+        self.info.beat = false;
+        if let (Some(last), true) = (self.ticks.back(), info.bpm > 0.0) {
+            let interval = 60e6 / (info.bpm as f64 * CLOCKS_PER_BEAT as f64);
+            if time.saturating_sub(*last) as f64 > 2.0 * interval {
+                info.running = false;
+            }
+        }
+        info
+    }
+    /// Forget the tempo and position, such as when the MIDI source changes.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+    /// Follows `msg`, ignoring anything but clock messages.
+    pub fn process(&mut self, msg: &MidiMessage) {
+        match msg.event {
+            MidiEvent::RealTime(CLOCK) => self.clock(msg.time),
+            MidiEvent::RealTime(START) => {
+                self.info.running = true;
+                self.info.position = 0;
+                self.fresh = true;
+                self.update_phase();
+            }
+            MidiEvent::RealTime(CONTINUE) => self.info.running = true,
+            MidiEvent::RealTime(STOP) => self.info.running = false,
+            MidiEvent::SongPos(sixteenths) => {
+                self.info.position = sixteenths as u64 * (CLOCKS_PER_BEAT / 4);
+                self.fresh = true;
+                self.update_phase();
+            }
+            _ => (),
+        }
+    }
+    fn clock(&mut self, time: u64) {
+        if matches!(self.ticks.back(), Some(t) if time < *t) {
+            self.ticks.clear(); // the time went backwards, so the source changed
+        }
+        self.ticks.push_back(time);
+        while self.ticks.len() as u64 > CLOCKS_PER_BEAT + 1 {
+            self.ticks.pop_front();
+        }
+        if let (Some(first), Some(last)) = (self.ticks.front(), self.ticks.back()) {
+            let clocks = self.ticks.len() as f64 - 1.0;
+            if last > first {
+                let beat = (last - first) as f64 / clocks * CLOCKS_PER_BEAT as f64;
+                self.info.bpm = (60e6 / beat) as f32;
+            }
+        }
+        if !self.info.running {
+            return;
+        }
+        if self.fresh {
+            self.fresh = false;
+        } else {
+            self.info.position += 1;
+        }
+        if self.info.position % CLOCKS_PER_BEAT == 0 {
+            self.info.beat = true;
+        }
+        self.update_phase();
+    }
+    fn update_phase(&mut self) {
+        self.info.phase = (self.info.position % CLOCKS_PER_BEAT) as f32 / CLOCKS_PER_BEAT as f32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The time between clocks at 120 bpm, in microseconds.
+    const INTERVAL: u64 = 500_000 / CLOCKS_PER_BEAT;
+
+    fn msg(time: u64, event: MidiEvent) -> MidiMessage {
+        MidiMessage { time, event }
+    }
+
+    /// Sends `n` clocks starting at `start`, returning the time of the next one.
+    fn clocks(clock: &mut MidiClock, start: u64, n: u64) -> u64 {
+        for i in 0..n {
+            clock.process(&msg(start + i * INTERVAL, MidiEvent::RealTime(CLOCK)));
+        }
+        start + n * INTERVAL
+    }
+
+    #[test]
+    fn measures_tempo() {
+        let mut clock = MidiClock::new();
+        assert_eq!(clock.info().bpm, 0.0);
+        let time = clocks(&mut clock, 1_000_000, 2);
+        assert!((clock.info().bpm - 120.0).abs() < 0.1, "{:?}", clock.info());
+        // the tempo follows within a beat
+        let interval = 60_000_000 / (140 * CLOCKS_PER_BEAT);
+        for i in 0..=CLOCKS_PER_BEAT {
+            clock.process(&msg(time + i * interval, MidiEvent::RealTime(CLOCK)));
+        }
+        assert!((clock.info().bpm - 140.0).abs() < 0.1, "{:?}", clock.info());
+        // clocks are counted but do not move the position until started
+        assert!(!clock.info().running);
+        assert_eq!(clock.info().position, 0);
+    }
+
+    #[test]
+    fn follows_position() {
+        let mut clock = MidiClock::new();
+        clock.process(&msg(0, MidiEvent::RealTime(START)));
+        // the first clock after a start is the start of the first beat
+        let time = clocks(&mut clock, 0, 1);
+        let info = clock.take_beat(time);
+        assert!(info.running && info.beat);
+        assert_eq!((info.position, info.phase), (0, 0.0));
+        let time = clocks(&mut clock, time, 6);
+        let info = clock.take_beat(time);
+        assert!(!info.beat);
+        assert_eq!((info.position, info.phase), (6, 0.25));
+        let time = clocks(&mut clock, time, CLOCKS_PER_BEAT - 6);
+        assert!(clock.take_beat(time).beat);
+        assert!(!clock.take_beat(time).beat);
+
+        clock.process(&msg(time, MidiEvent::RealTime(STOP)));
+        let time = clocks(&mut clock, time, 3);
+        assert_eq!(clock.info().position, CLOCKS_PER_BEAT);
+        // a song position pointer counts sixteenth notes
+        clock.process(&msg(time, MidiEvent::SongPos(6)));
+        clock.process(&msg(time, MidiEvent::RealTime(CONTINUE)));
+        let time = clocks(&mut clock, time, 2);
+        let info = clock.take_beat(time);
+        assert!(info.running);
+        assert_eq!((info.position, info.phase), (37, 13.0 / 24.0));
+    }
+
+    #[test]
+    fn stops_without_clocks() {
+        let mut clock = MidiClock::new();
+        clock.process(&msg(0, MidiEvent::RealTime(START)));
+        let time = clocks(&mut clock, 0, 30);
+        let last = time - INTERVAL;
+        assert!(clock.take_beat(last + INTERVAL).running);
+        assert!(clock.take_beat(last + 2 * INTERVAL - 100).running);
+        assert!(!clock.take_beat(last + 3 * INTERVAL).running);
+        // and runs again once the clocks come back
+        let time = clocks(&mut clock, time + 10 * INTERVAL, 1);
+        assert!(clock.take_beat(time).running);
+    }
+}
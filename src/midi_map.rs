@@ -0,0 +1,285 @@
+use crate::midi::MidiEvent;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A setting of the `AudioVisualizer` that can be controlled over MIDI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Param {
+    /// Selects one of the effect presets.
+    Effect,
+    /// The gain applied to the spectra, from -32 to +31.5 dB.
+    Gain,
+    /// Selects the algorithm used by the effect.
+    Algorithm,
+    /// Swaps the channels of the effect when above the middle of the range.
+    Invert,
+    /// Scales the brightness of every message.
+    Brightness,
+    /// The smoothing time constants of the effect, from 0 to 2 seconds.
+    Attack,
+    Decay,
+}
+impl Param {
+    pub const ALL: [Param; 7] = [
+        Param::Effect,
+        Param::Gain,
+        Param::Algorithm,
+        Param::Invert,
+        Param::Brightness,
+        Param::Attack,
+        Param::Decay,
+    ];
+    pub fn name(self) -> &'static str {
+        match self {
+            Param::Effect => "effect",
+            Param::Gain => "gain",
+            Param::Algorithm => "alg",
+            Param::Invert => "invert",
+            Param::Brightness => "brightness",
+            Param::Attack => "attack",
+            Param::Decay => "decay",
+        }
+    }
+}
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+impl FromStr for Param {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Param::ALL
+            .iter()
+            .copied()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| format!("Unknown MIDI parameter: {}", s))
+    }
+}
+
+/// The MIDI messages that control a parameter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Binding {
+    /// A controller on a channel, with its value.
+    Cc { channel: u8, controller: u8 },
+    /// Program changes on a channel, with the program number as the value.
+    Program { channel: u8 },
+}
+impl Binding {
+    /// Picks one of `count` choices with `value`. Program changes pick by number
+    /// and controllers split their range evenly between the choices.
+    pub fn select(&self, value: u8, count: usize) -> usize {
+        match self {
+            Binding::Program { .. } => (value as usize).min(count.saturating_sub(1)),
+            Binding::Cc { .. } => value as usize * count / 128,
+        }
+    }
+    /// The binding matching `event` and the value it carries.
+    fn of(event: &MidiEvent) -> Option<(Binding, u8)> {
+        match *event {
+            MidiEvent::CtrlChg(channel, controller, value) => Some((
+                Binding::Cc {
+                    channel,
+                    controller,
+                },
+                value,
+            )),
+            MidiEvent::ProgChg(channel, program) => Some((Binding::Program { channel }, program)),
+            _ => None,
+        }
+    }
+}
+
+/// Maps MIDI control and program changes to parameters, with a learn mode that binds
+/// a parameter to the next control or program change received.
+///
+/// A map loaded from a file is saved back to it whenever a binding is learned. The file
+/// has one binding per line: `PARAM cc CHANNEL CONTROLLER` or `PARAM program CHANNEL`,
+/// with channels from 0 to 15. Blank lines and lines starting with `#` are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct MidiMap {
+    bindings: Vec<(Binding, Param)>,
+    learning: Option<Param>,
+    path: Option<PathBuf>,
+}
+impl MidiMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Loads the map from `path`, or starts an empty one if the file does not exist yet.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let mut map = match std::fs::read_to_string(path) {
+            Ok(s) => MidiMap::from_str(&s)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => MidiMap::new(),
+            Err(e) => return Err(format!("Could not read {}: {}", path.display(), e)),
+        };
+        map.path = Some(path.to_path_buf());
+        Ok(map)
+    }
+    /// Writes the map to the file it was loaded from, if any.
+    pub fn save(&self) -> Result<(), String> {
+        match &self.path {
+            Some(path) => std::fs::write(path, self.to_string())
+                .map_err(|e| format!("Could not write {}: {}", path.display(), e)),
+            None => Ok(()),
+        }
+    }
+    #[inline]
+    pub fn bindings(&self) -> &[(Binding, Param)] {
+        &self.bindings
+    }
+    /// Binds `param` to `binding`, replacing anything else bound to it.
+    pub fn bind(&mut self, binding: Binding, param: Param) {
+        self.bindings.retain(|(b, _)| *b != binding);
+        self.bindings.push((binding, param));
+    }
+    /// Removes every binding of `param`.
+    pub fn unbind(&mut self, param: Param) {
+        self.bindings.retain(|(_, p)| *p != param);
+    }
+    /// Binds `param` to the next control or program change received.
+    pub fn learn(&mut self, param: Param) {
+        self.learning = Some(param);
+    }
+    /// The parameter waiting to be bound, if any.
+    #[inline]
+    pub fn learning(&self) -> Option<Param> {
+        self.learning
+    }
+    /// Returns the parameter `event` controls, the binding it matched and its value,
+    /// from 0 to 127.
+    ///
+    /// While learning, the event is bound instead and the parameter is returned so that
+    /// it takes the value immediately.
+    pub fn handle(&mut self, event: &MidiEvent) -> Option<(Param, Binding, u8)> {
+        let (binding, value) = Binding::of(event)?;
+        if let Some(param) = self.learning.take() {
+            self.bind(binding, param);
+            if let Err(e) = self.save() {
+                eprintln!("Could not save the learned MIDI binding: {}", e);
+            }
+            return Some((param, binding, value));
+        }
+        self.bindings
+            .iter()
+            .find(|(b, _)| *b == binding)
+            .map(|(_, p)| (*p, binding, value))
+    }
+}
+impl fmt::Display for MidiMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (binding, param) in self.bindings.iter() {
+            match binding {
+                Binding::Cc {
+                    channel,
+                    controller,
+                } => writeln!(f, "{} cc {} {}", param, channel, controller)?,
+                Binding::Program { channel } => writeln!(f, "{} program {}", param, channel)?,
+            }
+        }
+        Ok(())
+    }
+}
+impl FromStr for MidiMap {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = MidiMap::new();
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let num = |s: &str, max: u8| match u8::from_str(s) {
+                Ok(n) if n <= max => Ok(n),
+                _ => Err(format!("Invalid MIDI binding: {}", line)),
+            };
+            let binding = match words[1..] {
+                ["cc", channel, controller] => Binding::Cc {
+                    channel: num(channel, 15)?,
+                    controller: num(controller, 127)?,
+                },
+                ["program", channel] => Binding::Program {
+                    channel: num(channel, 15)?,
+                },
+                _ => return Err(format!("Invalid MIDI binding: {}", line)),
+            };
+            map.bind(binding, Param::from_str(words[0])?);
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let text = "# a comment\n\ngain cc 0 7\neffect program 9\n  decay cc 15 127\n";
+        let map = MidiMap::from_str(text).unwrap();
+        assert_eq!(
+            map.bindings(),
+            [
+                (
+                    Binding::Cc {
+                        channel: 0,
+                        controller: 7
+                    },
+                    Param::Gain
+                ),
+                (Binding::Program { channel: 9 }, Param::Effect),
+                (
+                    Binding::Cc {
+                        channel: 15,
+                        controller: 127
+                    },
+                    Param::Decay
+                ),
+            ]
+        );
+        let printed = map.to_string();
+        assert_eq!(printed, "gain cc 0 7\neffect program 9\ndecay cc 15 127\n");
+        assert_eq!(
+            MidiMap::from_str(&printed).unwrap().bindings(),
+            map.bindings()
+        );
+        for param in Param::ALL.iter() {
+            assert_eq!(Param::from_str(&param.to_string()), Ok(*param));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_bindings() {
+        assert!(MidiMap::from_str("gain cc 16 7").is_err());
+        assert!(MidiMap::from_str("gain cc 0 128").is_err());
+        assert!(MidiMap::from_str("gain program").is_err());
+        assert!(MidiMap::from_str("gain note 0 60").is_err());
+        assert!(MidiMap::from_str("volume cc 0 7").is_err());
+    }
+
+    #[test]
+    fn learns_the_next_control() {
+        let mut map = MidiMap::from_str("gain cc 0 7").unwrap();
+        assert_eq!(map.handle(&MidiEvent::CtrlChg(0, 8, 64)), None);
+        map.learn(Param::Brightness);
+        assert_eq!(map.handle(&MidiEvent::NoteOn(0, 60, 100)), None);
+        assert_eq!(map.learning(), Some(Param::Brightness));
+        let binding = Binding::Cc {
+            channel: 0,
+            controller: 7,
+        };
+        // learning replaces the existing binding
+        assert_eq!(
+            map.handle(&MidiEvent::CtrlChg(0, 7, 64)),
+            Some((Param::Brightness, binding, 64))
+        );
+        assert_eq!(map.learning(), None);
+        assert_eq!(
+            map.handle(&MidiEvent::CtrlChg(0, 7, 10)),
+            Some((Param::Brightness, binding, 10))
+        );
+        assert_eq!(map.bindings().len(), 1);
+    }
+}
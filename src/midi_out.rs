@@ -0,0 +1,177 @@
+use crate::bands::{Bands, Spacing};
+use crate::effect::{band_levels, Frame};
+use crate::midi::{MidiEvent, MidiMessage};
+use std::ops::Range;
+
+/// Turns the analysis of each frame into MIDI: the level of each band as a control change,
+/// onsets as notes and the tempo as MIDI clock, for driving other lighting consoles and DAWs.
+///
+/// Control changes are only sent when their value changes.
+pub struct AnalysisMidi {
+    bands: Bands,
+    /// The channel of every message, from 0 to 15.
+    pub channel: u8,
+    /// The controller of the first band. The other bands use the following controllers.
+    pub first_cc: u8,
+    /// The note played on each onset, with a velocity set by how far the flux
+    /// exceeded its threshold, or `None` to not send onsets.
+    pub onset_note: Option<u8>,
+    /// The levels, in dB, sent as 0 and 127.
+    pub min: f32,
+    pub max: f32,
+    /// Only change the tempo of the clock once the beat tracker is at least this confident.
+    pub min_confidence: f32,
+    /// The clock keeps running at the last confident tempo until the beat tracker has been
+    /// less confident than `min_confidence` for this many seconds.
+    pub stop_after: f32,
+    /// The last confident tempo and when the beat tracker became unsure of it.
+    bpm: f32,
+    unsure_since: Option<u64>,
+    ranges: Vec<Range<usize>>,
+    fft_size: usize,
+    rate: u32,
+    values: Vec<Option<u8>>,
+    note_on: bool,
+}
+impl Default for AnalysisMidi {
+    /// Eight log spaced bands from 40 Hz to 16 kHz on the undefined controllers from 20,
+    /// with onsets on the note of a kick drum.
+    fn default() -> Self {
+        AnalysisMidi::new(Bands::new(Spacing::Log, 8, 40.0, 16000.0).unwrap())
+    }
+}
+impl AnalysisMidi {
+    pub fn new(bands: Bands) -> Self {
+        AnalysisMidi {
+            bands,
+            channel: 0,
+            first_cc: 20,
+            onset_note: Some(36),
+            min: -35.0,
+            max: 15.0,
+            min_confidence: 0.5,
+            stop_after: 8.0,
+            bpm: 0.0,
+            unsure_since: None,
+            ranges: Vec::new(),
+            fft_size: 0,
+            rate: 0,
+            values: Vec::new(),
+            note_on: false,
+        }
+    }
+    #[inline]
+    pub fn bands(&self) -> &Bands {
+        &self.bands
+    }
+    pub fn set_bands(&mut self, bands: Bands) {
+        self.bands = bands;
+        self.fft_size = 0; // force the bins to be recomputed
+    }
+    /// The tempo that should be sent as MIDI clock for `frame`, or 0.0 to stop the clock.
+    pub fn tempo(&mut self, frame: &Frame) -> f32 {
+        let beat = &frame.beat;
+        if beat.confidence >= self.min_confidence && beat.bpm > 0.0 {
+            self.bpm = beat.bpm;
+            self.unsure_since = None;
+        } else if self.bpm > 0.0 {
+            let since = *self.unsure_since.get_or_insert(frame.time);
+            if frame.time.saturating_sub(since) as f32 / 1_000_000.0 >= self.stop_after {
+                self.bpm = 0.0;
+                self.unsure_since = None;
+            }
+        }
+        self.bpm
+    }
+    /// Appends the messages for `frame` to `out`.
+    pub fn process(&mut self, frame: &Frame, out: &mut Vec<MidiMessage>) {
+        if frame.fft_size != self.fft_size || frame.rate != self.rate {
+            self.fft_size = frame.fft_size;
+            self.rate = frame.rate;
+            self.ranges = match self.bands.bin_ranges(frame.fft_size, frame.rate) {
+                Ok(ranges) => ranges,
+                Err(e) => {
+                    eprintln!("MIDI output of bands is disabled: {}", e);
+                    Vec::new()
+                }
+            };
+            self.values.clear();
+            self.values.resize(self.ranges.len(), None);
+        }
+        let (channel, time) = (self.channel & 0x0F, frame.time);
+        if let Some(note) = self.onset_note {
+            if self.note_on {
+                self.note_on = false;
+                out.push(MidiMessage {
+                    time,
+                    event: MidiEvent::NoteOff(channel, note, 0),
+                });
+            }
+            if frame.beat.onset {
+                let over = frame.beat.flux / frame.beat.threshold.max(f32::EPSILON);
+                let velocity = (over * 64.0).min(127.0).max(1.0) as u8;
+                self.note_on = true;
+                out.push(MidiMessage {
+                    time,
+                    event: MidiEvent::NoteOn(channel, note, velocity),
+                });
+            }
+        }
+        let left = band_levels(frame.left, &self.ranges);
+        let right = band_levels(frame.right, &self.ranges);
+        for (i, (l, r)) in left.zip(right).enumerate() {
+            let controller = match self.first_cc.checked_add(i as u8) {
+                Some(c) if c < 120 => c, // the rest are channel mode messages
+                _ => break,
+            };
+            let x = (l.max(r) - self.min) / (self.max - self.min);
+            let value = (x * 127.0).min(127.0).max(0.0).round() as u8;
+            if self.values[i] != Some(value) {
+                self.values[i] = Some(value);
+                out.push(MidiMessage {
+                    time,
+                    event: MidiEvent::CtrlChg(channel, controller, value),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beat::BeatInfo;
+
+    fn frame(time: u64, bpm: f32, confidence: f32) -> Frame<'static> {
+        Frame {
+            left: &[],
+            right: &[],
+            channels: &[],
+            rate: 48000,
+            fft_size: 256,
+            time,
+            beat: BeatInfo {
+                bpm,
+                confidence,
+                ..BeatInfo::default()
+            },
+            midi: &[],
+        }
+    }
+
+    #[test]
+    fn tempo_holds_through_confidence_dips() {
+        let mut out = AnalysisMidi::default();
+        assert_eq!(out.tempo(&frame(0, 120.0, 0.1)), 0.0);
+        assert_eq!(out.tempo(&frame(1_000_000, 120.0, 0.9)), 120.0);
+        // a short dip keeps the last confident tempo
+        assert_eq!(out.tempo(&frame(2_000_000, 90.0, 0.1)), 120.0);
+        assert_eq!(out.tempo(&frame(9_000_000, 90.0, 0.1)), 120.0);
+        assert_eq!(out.tempo(&frame(9_500_000, 124.0, 0.8)), 124.0);
+        assert_eq!(out.tempo(&frame(10_000_000, 0.0, 0.0)), 124.0);
+        assert_eq!(out.tempo(&frame(17_000_000, 0.0, 0.0)), 124.0);
+        // until it lasts long enough to stop the clock
+        assert_eq!(out.tempo(&frame(18_000_000, 0.0, 0.0)), 0.0);
+        assert_eq!(out.tempo(&frame(30_000_000, 0.0, 0.0)), 0.0);
+    }
+}
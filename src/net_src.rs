@@ -0,0 +1,695 @@
+use crate::audio::{
+    ActiveAudioSource, AudioSourceOptions, InactiveAudioSource, Sample, StereoSample,
+};
+use crate::pcm_src::PcmFormat;
+use crate::Error;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{Builder, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Magic bytes that start every framed packet.
+const MAGIC: [u8; 4] = *b"SYNA";
+const VERSION: u8 = 1;
+/// magic(4) version(1) format(1) channels(1) reserved(1) seq(4) rate(4) frame(8)
+const HEADER_LEN: usize = 24;
+const RTP_HEADER_LEN: usize = 12;
+/// Static RTP payload types of L16 at 44.1 kHz (RFC 3551).
+const RTP_PT_L16_STEREO: u8 = 10;
+const RTP_PT_L16_MONO: u8 = 11;
+/// Dynamic RTP payload types used for L24 and for L16 at other rates.
+const RTP_PT_L24: u8 = 96;
+const RTP_PT_L16: u8 = 97;
+/// Added to the first RTP timestamp so that unwrapping earlier timestamps never underflows.
+const RTP_TS_OFFSET: u64 = 0x1_0000_0000;
+
+/// The wire format of audio packets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NetFormat {
+    /// Synesthesia's own packets which carry their sample format, channel count and rate.
+    Framed(PcmFormat),
+    /// RTP with 16-bit big-endian PCM payloads (RFC 3551).
+    RtpL16,
+    /// RTP with 24-bit big-endian PCM payloads (RFC 3190).
+    RtpL24,
+}
+impl NetFormat {
+    fn pcm_code(format: PcmFormat) -> u8 {
+        match format {
+            PcmFormat::S16LE => 0,
+            PcmFormat::S32LE => 1,
+            PcmFormat::F32LE => 2,
+        }
+    }
+    fn from_pcm_code(code: u8) -> Option<PcmFormat> {
+        match code {
+            0 => Some(PcmFormat::S16LE),
+            1 => Some(PcmFormat::S32LE),
+            2 => Some(PcmFormat::F32LE),
+            _ => None,
+        }
+    }
+}
+
+/// The encoding of individual samples in a packet's payload.
+#[derive(Clone, Copy)]
+enum Encoding {
+    Pcm(PcmFormat),
+    L16,
+    L24,
+}
+impl Encoding {
+    #[inline]
+    fn width(&self) -> usize {
+        match self {
+            Encoding::Pcm(format) => format.bytes(),
+            Encoding::L16 => 2,
+            Encoding::L24 => 3,
+        }
+    }
+    #[inline]
+    fn decode(&self, b: &[u8]) -> f32 {
+        match self {
+            Encoding::Pcm(format) => format.decode(b),
+            Encoding::L16 => i16::from_be_bytes([b[0], b[1]]) as f32 / 32768.0,
+            Encoding::L24 => (i32::from_be_bytes([b[0], b[1], b[2], 0]) >> 8) as f32 / 8388608.0,
+        }
+    }
+    #[inline]
+    fn encode(&self, v: f32, out: &mut Vec<u8>) {
+        let v = v.min(1.0).max(-1.0);
+        match self {
+            Encoding::Pcm(format) => format.encode(v, out),
+            Encoding::L16 => out.extend_from_slice(&((v * 32767.0) as i16).to_be_bytes()),
+            Encoding::L24 => out.extend_from_slice(&((v * 8388607.0) as i32).to_be_bytes()[1..4]),
+        }
+    }
+}
+
+/// A decoded packet positioned on the sender's frame clock.
+struct Packet {
+    frame: u64,
+    rate: u32,
+    channels: Vec<Vec<f32>>,
+}
+impl Packet {
+    #[inline]
+    fn len(&self) -> usize {
+        self.channels[0].len()
+    }
+}
+
+/// The payload type of an L16 stream, which only has a static type at 44.1 kHz.
+fn l16_payload_type(channels: usize, rate: u32) -> u8 {
+    match (channels, rate) {
+        (2, 44100) => RTP_PT_L16_STEREO,
+        (1, 44100) => RTP_PT_L16_MONO,
+        _ => RTP_PT_L16,
+    }
+}
+
+/// Extend a wrapping 32-bit RTP timestamp to 64 bits, picking the value closest to `prev`.
+fn unwrap_ts(prev: u64, ts: u32) -> u64 {
+    let cand = (prev & !0xFFFF_FFFF) | ts as u64;
+    if cand + 0x8000_0000 < prev {
+        cand + 0x1_0000_0000
+    } else if cand > prev + 0x8000_0000 && cand >= 0x1_0000_0000 {
+        cand - 0x1_0000_0000
+    } else {
+        cand
+    }
+}
+
+/// Listens for audio packets on a UDP socket.
+///
+/// Packets are held in a jitter buffer of `latency` so that reordered packets
+/// can be put back in order. Packets that have not arrived by then are
+/// considered lost and concealed by repeating the previous packet with decaying volume.
+pub struct NetStream {
+    socket: UdpSocket,
+    pub format: NetFormat,
+    /// The number of channels of RTP streams. Framed packets carry their own.
+    /// Packets with a static payload type that does not match are rejected.
+    pub channels: u16,
+    /// The sample rate of RTP streams. Framed packets carry their own.
+    /// Packets with a static payload type that does not match are rejected.
+    pub rate: u32,
+    pub latency: Duration,
+}
+impl NetStream {
+    pub fn bind<A: ToSocketAddrs>(addr: A, format: NetFormat) -> Result<Self, Error> {
+        let socket = UdpSocket::bind(addr)?;
+        Ok(NetStream {
+            socket,
+            format,
+            channels: 2,
+            rate: 48000,
+            latency: Duration::from_millis(40),
+        })
+    }
+    #[inline]
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+}
+impl InactiveAudioSource for NetStream {
+    type ActiveType = NetSource;
+    fn activate(self, options: AudioSourceOptions) -> Result<Self::ActiveType, Error> {
+        if self.channels == 0 || self.rate == 0 {
+            return Err(Error::Unrecoverable(
+                "Network stream must have a non-zero channel count and sample rate".to_string(),
+            ));
+        }
+        // the timeout allows the receiving thread to notice when it is deactivated
+        self.socket
+            .set_read_timeout(Some(Duration::from_millis(100)))?;
+        let (sender, recv) = mpsc::sync_channel(2);
+        let stats = Arc::new(NetStats::default());
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut receiver = NetReceiver {
+            sender,
+            stats: stats.clone(),
+            stop: stop.clone(),
+            jitter: BTreeMap::new(),
+            next: None,
+            rtp_ts: 0,
+            rebase: true,
+            ssrc: None,
+            rate: self.rate,
+            out_start: 0,
+            sample_size: options.sample_size,
+            out: vec![Vec::with_capacity(options.sample_size * 2); self.channels as usize],
+            last: Vec::new(),
+            stream: self,
+        };
+        let handle = Builder::new()
+            .name("net_receiver".to_string())
+            .spawn(move || {
+                receiver.recv_loop()?;
+                Ok(receiver.stream)
+            })?;
+        Ok(NetSource {
+            recv,
+            handle,
+            stats,
+            stop,
+        })
+    }
+}
+
+/// Counters kept by a running `NetSource`.
+#[derive(Default, Debug)]
+pub struct NetStats {
+    pub received: AtomicUsize,
+    pub lost: AtomicUsize,
+    pub late: AtomicUsize,
+    pub malformed: AtomicUsize,
+    /// The sender time, in microseconds, of the most recently emitted sample.
+    pub time: AtomicU64,
+}
+
+struct NetReceiver {
+    stream: NetStream,
+    sender: mpsc::SyncSender<StereoSample>,
+    stats: Arc<NetStats>,
+    stop: Arc<AtomicBool>,
+    jitter: BTreeMap<u64, Packet>,
+    /// The next frame expected to be appended to the output.
+    next: Option<u64>,
+    rtp_ts: u64,
+    /// The next RTP timestamp starts a new frame clock instead of being unwrapped.
+    rebase: bool,
+    ssrc: Option<u32>,
+    rate: u32,
+    sample_size: usize,
+    out_start: u64,
+    /// The frames of each channel waiting to be emitted.
+    out: Vec<Vec<f32>>,
+    /// The most recent packet, repeated to conceal lost packets.
+    last: Vec<Vec<f32>>,
+}
+impl NetReceiver {
+    fn recv_loop(&mut self) -> Result<(), Error> {
+        let mut buf = [0; 65536];
+        while !self.stop.load(Ordering::Relaxed) {
+            let len = match self.stream.socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let mut packet = match self.decode(&buf[..len]) {
+                Some(p) => p,
+                None => {
+                    self.stats.malformed.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            self.stats.received.fetch_add(1, Ordering::Relaxed);
+            if packet.rate != self.rate || packet.channels.len() != self.out.len() {
+                // the sender changed its rate or channels, so the old stream is meaningless
+                self.rate = packet.rate;
+                self.out.resize(packet.channels.len(), Vec::new());
+                self.reset();
+            }
+            if let Some(next) = self.next {
+                let second = self.rate as u64;
+                if packet.frame + second < next || packet.frame > next + second {
+                    // more than a second away, assume the sender was restarted
+                    self.reset();
+                    // decode it again to start the new frame clock from this packet
+                    packet = match self.decode(&buf[..len]) {
+                        Some(p) => p,
+                        None => continue,
+                    };
+                }
+            }
+            self.jitter.insert(packet.frame, packet);
+            self.release();
+            if !self.emit() {
+                break; // the consumer was dropped
+            }
+        }
+        Ok(())
+    }
+    fn reset(&mut self) {
+        self.jitter.clear();
+        self.next = None;
+        self.rebase = true;
+        for ch in self.out.iter_mut() {
+            ch.clear();
+        }
+        self.last.clear();
+    }
+    fn decode(&mut self, buf: &[u8]) -> Option<Packet> {
+        let (frame, rate, channels, encoding, payload) = match self.stream.format {
+            NetFormat::Framed(expected) => {
+                if buf.len() < HEADER_LEN || buf[0..4] != MAGIC || buf[4] != VERSION {
+                    return None;
+                }
+                let format = NetFormat::from_pcm_code(buf[5])?;
+                if format != expected {
+                    return None;
+                }
+                let rate = u32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]);
+                let mut frame = [0; 8];
+                frame.copy_from_slice(&buf[16..24]);
+                (
+                    u64::from_be_bytes(frame),
+                    rate,
+                    buf[6] as usize,
+                    Encoding::Pcm(format),
+                    &buf[HEADER_LEN..],
+                )
+            }
+            NetFormat::RtpL16 | NetFormat::RtpL24 => {
+                if buf.len() < RTP_HEADER_LEN || buf[0] >> 6 != 2 {
+                    return None;
+                }
+                // skip CSRCs and extension headers
+                let mut start = RTP_HEADER_LEN + (buf[0] & 0x0F) as usize * 4;
+                if buf[0] & 0x10 != 0 {
+                    if buf.len() < start + 4 {
+                        return None;
+                    }
+                    start += 4 + u16::from_be_bytes([buf[start + 2], buf[start + 3]]) as usize * 4;
+                }
+                let mut end = buf.len();
+                if buf[0] & 0x20 != 0 {
+                    end = end.checked_sub(*buf.last()? as usize)?;
+                }
+                if start > end {
+                    return None;
+                }
+                let channels = self.stream.channels as usize;
+                let (pt, encoding) = match self.stream.format {
+                    NetFormat::RtpL16 => {
+                        (l16_payload_type(channels, self.stream.rate), Encoding::L16)
+                    }
+                    _ => (RTP_PT_L24, Encoding::L24),
+                };
+                let got = buf[1] & 0x7F;
+                // static payload types fix the rate and channels, dynamic ones are agreed out of band
+                if got != pt && !(got >= 96 && pt >= 96) {
+                    return None;
+                }
+                let ssrc = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
+                if self.ssrc != Some(ssrc) {
+                    // a new stream, such as a restarted sender, has its own timestamps
+                    if self.ssrc.is_some() {
+                        self.reset();
+                    }
+                    self.ssrc = Some(ssrc);
+                }
+                let ts = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+                self.rtp_ts = if self.rebase {
+                    self.rebase = false;
+                    ts as u64 + RTP_TS_OFFSET
+                } else {
+                    unwrap_ts(self.rtp_ts, ts)
+                };
+                (
+                    self.rtp_ts,
+                    self.stream.rate,
+                    self.stream.channels as usize,
+                    encoding,
+                    &buf[start..end],
+                )
+            }
+        };
+        if channels == 0 || rate == 0 {
+            return None;
+        }
+        let width = encoding.width();
+        let frame_bytes = width * channels;
+        let frames = payload.len() / frame_bytes;
+        if frames == 0 {
+            return None;
+        }
+        let mut data = vec![Vec::with_capacity(frames); channels];
+        for f in payload.chunks_exact(frame_bytes) {
+            for (ch, v) in data.iter_mut().zip(f.chunks_exact(width)) {
+                ch.push(encoding.decode(v));
+            }
+        }
+        Some(Packet {
+            frame,
+            rate,
+            channels: data,
+        })
+    }
+    /// Move packets from the jitter buffer to the output, concealing any that were lost.
+    fn release(&mut self) {
+        let latency = self.stream.latency.as_micros() as u64 * self.rate as u64 / 1_000_000;
+        while let Some(&key) = self.jitter.keys().next() {
+            let next = match self.next {
+                Some(next) => next,
+                None => {
+                    self.out_start = key;
+                    key
+                }
+            };
+            if key < next {
+                // arrived after it was concealed, or duplicated
+                self.jitter.remove(&key);
+                self.stats.late.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            if key > next {
+                let (&newest, p) = self.jitter.iter().next_back().unwrap();
+                if newest + (p.len() as u64) - next < latency {
+                    break; // wait for the missing packet
+                }
+                self.stats.lost.fetch_add(1, Ordering::Relaxed);
+                self.conceal((key - next) as usize);
+            }
+            let p = self.jitter.remove(&key).unwrap();
+            for (out, ch) in self.out.iter_mut().zip(p.channels.iter()) {
+                out.extend_from_slice(ch);
+            }
+            self.next = Some(key + p.len() as u64);
+            self.last = p.channels;
+        }
+    }
+    fn conceal(&mut self, frames: usize) {
+        for (c, out) in self.out.iter_mut().enumerate() {
+            match self.last.get(c) {
+                Some(last) if !last.is_empty() => {
+                    // halve the volume every time the last packet is repeated
+                    let len = last.len();
+                    out.extend(
+                        (0..frames).map(|i| last[i % len] * 0.5_f32.powi((i / len) as i32 + 1)),
+                    );
+                }
+                _ => out.resize(out.len() + frames, 0.0),
+            }
+        }
+    }
+    /// Send full samples to the consumer. Returns false if the consumer has disconnected.
+    fn emit(&mut self) -> bool {
+        let sample_size = self.sample_size;
+        while self.out[0].len() >= sample_size {
+            let frame = match self.stream.format {
+                NetFormat::Framed(_) => self.out_start,
+                NetFormat::RtpL16 | NetFormat::RtpL24 => {
+                    self.out_start.saturating_sub(RTP_TS_OFFSET)
+                }
+            };
+            let time = frame * 1_000_000 / self.rate as u64;
+            let mut ss = Sample::with_channels(self.out.len(), sample_size, self.rate, time);
+            ss.extend_channels(self.out.iter().map(|ch| &ch[..sample_size]));
+            for ch in self.out.iter_mut() {
+                ch.drain(..sample_size);
+            }
+            self.out_start += sample_size as u64;
+            self.stats.time.store(time, Ordering::Relaxed);
+            if self.sender.send(ss).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub struct NetSource {
+    recv: mpsc::Receiver<StereoSample>,
+    handle: JoinHandle<Result<NetStream, Error>>,
+    stats: Arc<NetStats>,
+    stop: Arc<AtomicBool>,
+}
+impl NetSource {
+    #[inline]
+    pub fn stats(&self) -> &NetStats {
+        &self.stats
+    }
+}
+impl ActiveAudioSource for NetSource {
+    type InactiveType = NetStream;
+    fn deactivate(self) -> Result<Self::InactiveType, Error> {
+        self.stop.store(true, Ordering::Relaxed);
+        drop(self.recv);
+        self.handle
+            .join()
+            .map_err(|_| Error::Unrecoverable("Network receiver thread panicked".to_string()))?
+    }
+    /// Returns the sender's time of the most recently received sample.
+    #[inline]
+    fn cur_time(&self) -> u64 {
+        self.stats.time.load(Ordering::Relaxed)
+    }
+    fn recv(&mut self) -> Result<StereoSample, Error> {
+        self.recv
+            .recv()
+            .map_err(|_| Error::Unrecoverable("Audio producer is disconnected".to_string()))
+    }
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<StereoSample, Error> {
+        self.recv.recv_timeout(timeout).map_err(|x| match x {
+            mpsc::RecvTimeoutError::Timeout => Error::Timeout("Audio timed out".to_string()),
+            mpsc::RecvTimeoutError::Disconnected => {
+                Error::Unrecoverable("Audio producer is disconnected".to_string())
+            }
+        })
+    }
+}
+
+/// Streams `StereoSample`s from any audio source to a `NetStream`.
+pub struct NetSender {
+    socket: UdpSocket,
+    pub format: NetFormat,
+    /// The maximum number of frames put in each packet.
+    pub frames_per_packet: usize,
+    seq: u32,
+    ssrc: u32,
+    buf: Vec<u8>,
+}
+impl NetSender {
+    pub fn connect<A: ToSocketAddrs, B: ToSocketAddrs>(
+        bind: A,
+        target: B,
+        format: NetFormat,
+    ) -> Result<Self, Error> {
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(target)?;
+        // a random SSRC lets the receiver tell a restarted sender apart from the old one
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let seed = std::process::id().wrapping_mul(0x9E37_79B9) ^ nanos;
+        Ok(NetSender {
+            socket,
+            format,
+            frames_per_packet: 256,
+            seq: 0,
+            ssrc: seed,
+            buf: Vec::with_capacity(2048),
+        })
+    }
+    /// Send a sample, split into as many packets as needed.
+    ///
+    /// The sample's time is converted into frames so the receiver can reconstruct its timing.
+    pub fn send(&mut self, ss: &StereoSample) -> Result<(), Error> {
+        let rate = ss.rate();
+        let channels = ss.channels();
+        let channel_count = u8::try_from(channels).map_err(|_| {
+            Error::Unrecoverable(format!(
+                "Cannot send {} channels over the network",
+                channels
+            ))
+        })?;
+        // round to the nearest frame, since times are usually truncated from frames
+        let start = (ss.time() * rate as u64 + 500_000) / 1_000_000;
+        let frames = self.frames_per_packet.max(1);
+        for (i, first) in (0..ss.len()).step_by(frames).enumerate() {
+            let frame = start + (i * frames) as u64;
+            self.buf.clear();
+            let encoding = match self.format {
+                NetFormat::Framed(pcm) => {
+                    self.buf.extend_from_slice(&MAGIC);
+                    self.buf.extend_from_slice(&[
+                        VERSION,
+                        NetFormat::pcm_code(pcm),
+                        channel_count,
+                        0,
+                    ]);
+                    self.buf.extend_from_slice(&self.seq.to_be_bytes());
+                    self.buf.extend_from_slice(&rate.to_be_bytes());
+                    self.buf.extend_from_slice(&frame.to_be_bytes());
+                    Encoding::Pcm(pcm)
+                }
+                NetFormat::RtpL16 | NetFormat::RtpL24 => {
+                    let (pt, encoding) = match self.format {
+                        NetFormat::RtpL16 => (l16_payload_type(channels, rate), Encoding::L16),
+                        _ => (RTP_PT_L24, Encoding::L24),
+                    };
+                    self.buf.extend_from_slice(&[0x80, pt]);
+                    self.buf.extend_from_slice(&(self.seq as u16).to_be_bytes());
+                    self.buf.extend_from_slice(&(frame as u32).to_be_bytes());
+                    self.buf.extend_from_slice(&self.ssrc.to_be_bytes());
+                    encoding
+                }
+            };
+            for f in first..(first + frames).min(ss.len()) {
+                for c in 0..channels {
+                    encoding.encode(ss.channel(c)[f], &mut self.buf);
+                }
+            }
+            self.socket.send(&self.buf)?;
+            self.seq = self.seq.wrapping_add(1);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(time: u64, value: f32) -> StereoSample {
+        let mut ss = StereoSample::new(256, 48000, time);
+        ss.extend(&[value; 256], &[-value; 256]);
+        ss
+    }
+
+    fn loopback(format: NetFormat) {
+        let mut stream = NetStream::bind("127.0.0.1:0", format).unwrap();
+        stream.latency = Duration::from_millis(10);
+        let addr = stream.local_addr().unwrap();
+        let options = AudioSourceOptions {
+            sample_size: 256,
+            ..AudioSourceOptions::default()
+        };
+        let mut src = stream.activate(options).unwrap();
+        let timeout = Duration::from_secs(2);
+
+        // starts ten seconds in, so the restart is clear even without an SSRC
+        let start = 10 * 48000;
+        let mut sender = NetSender::connect("127.0.0.1:0", addr, format).unwrap();
+        for i in 0..4 {
+            let time = (start + i * 256) * 1_000_000 / 48000;
+            sender.send(&sample(time, 0.5)).unwrap();
+            let ss = src.recv_timeout(timeout).unwrap();
+            assert_eq!(ss.time(), time);
+            assert!((ss.left()[0] - 0.5).abs() < 0.001);
+            assert!((ss.right()[255] + 0.5).abs() < 0.001);
+        }
+
+        // a restarted sender starts its time again, with a new SSRC for RTP
+        let mut sender = NetSender::connect("127.0.0.1:0", addr, format).unwrap();
+        sender.send(&sample(0, 0.25)).unwrap();
+        let ss = src.recv_timeout(timeout).unwrap();
+        assert_eq!(ss.time(), 0);
+        assert!((ss.left()[0] - 0.25).abs() < 0.001);
+        src.deactivate().unwrap();
+    }
+
+    #[test]
+    fn loopback_framed() {
+        loopback(NetFormat::Framed(PcmFormat::S16LE));
+    }
+
+    #[test]
+    fn loopback_rtp() {
+        loopback(NetFormat::RtpL16);
+        loopback(NetFormat::RtpL24);
+    }
+
+    #[test]
+    fn loopback_channels() {
+        let format = NetFormat::Framed(PcmFormat::F32LE);
+        let stream = NetStream::bind("127.0.0.1:0", format).unwrap();
+        let addr = stream.local_addr().unwrap();
+        let options = AudioSourceOptions {
+            sample_size: 256,
+            ..AudioSourceOptions::default()
+        };
+        let mut src = stream.activate(options).unwrap();
+        let mut sender = NetSender::connect("127.0.0.1:0", addr, format).unwrap();
+        for &channels in &[6, 1] {
+            let mut ss = Sample::with_channels(channels, 256, 48000, 0);
+            let data: Vec<Vec<f32>> = (0..channels).map(|c| vec![c as f32 / 8.0; 256]).collect();
+            ss.extend_channels(data.iter().map(|ch| ch.as_slice()));
+            sender.send(&ss).unwrap();
+            let ss = src.recv_timeout(Duration::from_secs(2)).unwrap();
+            assert_eq!(ss.channels(), channels);
+            for (c, ch) in data.iter().enumerate() {
+                assert_eq!(ss.channel(c), &ch[..]);
+            }
+        }
+        src.deactivate().unwrap();
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        let stream = NetStream::bind("127.0.0.1:0", NetFormat::Framed(PcmFormat::S16LE)).unwrap();
+        let addr = stream.local_addr().unwrap();
+        let mut src = stream.activate(AudioSourceOptions::default()).unwrap();
+        let mut sender =
+            NetSender::connect("127.0.0.1:0", addr, NetFormat::Framed(PcmFormat::F32LE)).unwrap();
+        sender.send(&sample(0, 0.5)).unwrap();
+        // at 48 kHz, L16 must use a dynamic payload type instead of the 44.1 kHz static one
+        sender.format = NetFormat::RtpL16;
+        sender.send(&sample(0, 0.5)).unwrap();
+        assert!(src.recv_timeout(Duration::from_millis(200)).is_err());
+        assert_eq!(src.stats().malformed.load(Ordering::Relaxed), 2);
+        assert_eq!(l16_payload_type(2, 48000), RTP_PT_L16);
+        assert_eq!(l16_payload_type(2, 44100), RTP_PT_L16_STEREO);
+    }
+
+    #[test]
+    fn rtp_timestamps_unwrap() {
+        assert_eq!(unwrap_ts(RTP_TS_OFFSET + 10, 20), RTP_TS_OFFSET + 20);
+        assert_eq!(
+            unwrap_ts(RTP_TS_OFFSET + 0xFFFF_FFF0, 5),
+            2 * RTP_TS_OFFSET + 5
+        );
+        assert_eq!(
+            unwrap_ts(2 * RTP_TS_OFFSET + 5, 0xFFFF_FFF0),
+            RTP_TS_OFFSET + 0xFFFF_FFF0
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use crate::audio::{
+    ActiveAudioSource, AudioSourceOptions, InactiveAudioSource, Sample, StereoSample,
+};
+use crate::Error;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Stdin};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{Builder, JoinHandle};
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, RawFd};
+
+/// The encoding of interleaved raw PCM samples.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PcmFormat {
+    S16LE,
+    S32LE,
+    F32LE,
+}
+impl PcmFormat {
+    #[inline]
+    pub fn bytes(&self) -> usize {
+        match self {
+            PcmFormat::S16LE => 2,
+            PcmFormat::S32LE | PcmFormat::F32LE => 4,
+        }
+    }
+    #[inline]
+    pub(crate) fn decode(&self, b: &[u8]) -> f32 {
+        match self {
+            PcmFormat::S16LE => i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+            PcmFormat::S32LE => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.0,
+            PcmFormat::F32LE => f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+        }
+    }
+    #[inline]
+    pub(crate) fn encode(&self, v: f32, out: &mut Vec<u8>) {
+        let v = v.min(1.0).max(-1.0);
+        match self {
+            PcmFormat::S16LE => out.extend_from_slice(&((v * 32767.0) as i16).to_le_bytes()),
+            PcmFormat::S32LE => {
+                out.extend_from_slice(&((v as f64 * 2147483647.0) as i32).to_le_bytes())
+            }
+            PcmFormat::F32LE => out.extend_from_slice(&v.to_le_bytes()),
+        }
+    }
+}
+impl FromStr for PcmFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "s16le" | "S16LE" => Ok(PcmFormat::S16LE),
+            "s32le" | "S32LE" => Ok(PcmFormat::S32LE),
+            "f32le" | "F32LE" => Ok(PcmFormat::F32LE),
+            _ => Err(format!("Unknown PCM format: {}", s)),
+        }
+    }
+}
+
+/// A stream of interleaved raw PCM, such as stdin or a named pipe, that has not been activated.
+///
+/// Samples have every channel of the stream, which are mixed down by the consumer.
+pub struct PcmStream<R: Read + Send + 'static> {
+    reader: R,
+    pub format: PcmFormat,
+    pub channels: u16,
+    pub rate: u32,
+}
+impl<R: Read + Send + 'static> PcmStream<R> {
+    pub fn new(reader: R, format: PcmFormat, channels: u16, rate: u32) -> Self {
+        PcmStream {
+            reader,
+            format,
+            channels,
+            rate,
+        }
+    }
+}
+impl PcmStream<Stdin> {
+    #[inline]
+    pub fn stdin(format: PcmFormat, channels: u16, rate: u32) -> Self {
+        Self::new(io::stdin(), format, channels, rate)
+    }
+}
+impl PcmStream<File> {
+    /// Open a named pipe (or regular file) containing raw PCM.
+    #[inline]
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        format: PcmFormat,
+        channels: u16,
+        rate: u32,
+    ) -> Result<Self, Error> {
+        Ok(Self::new(File::open(path)?, format, channels, rate))
+    }
+    /// Read raw PCM from an already open file descriptor.
+    ///
+    /// # Safety
+    /// `fd` must be an open file descriptor that is not owned by anything else.
+    #[cfg(unix)]
+    #[inline]
+    pub unsafe fn from_raw_fd(fd: RawFd, format: PcmFormat, channels: u16, rate: u32) -> Self {
+        Self::new(File::from_raw_fd(fd), format, channels, rate)
+    }
+}
+impl<R: Read + Send + 'static> InactiveAudioSource for PcmStream<R> {
+    type ActiveType = PcmSource<R>;
+    fn activate(self, options: AudioSourceOptions) -> Result<Self::ActiveType, Error> {
+        if self.channels == 0 {
+            return Err(Error::Unrecoverable(
+                "PCM stream must have at least one channel".to_string(),
+            ));
+        }
+        if self.rate == 0 {
+            return Err(Error::Unrecoverable(
+                "PCM stream must have a non-zero sample rate".to_string(),
+            ));
+        }
+        let (sender, recv) = mpsc::sync_channel(1);
+        let position = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let rate = self.rate;
+        let mut reader = PcmReader {
+            stream: self,
+            sample_size: options.sample_size,
+            sender,
+            position: position.clone(),
+            stop: stop.clone(),
+        };
+        let handle = Builder::new()
+            .name("pcm_reader".to_string())
+            .spawn(move || {
+                reader.read_loop()?;
+                Ok(reader.stream)
+            })?;
+        Ok(PcmSource {
+            recv,
+            handle,
+            position,
+            stop,
+            rate,
+        })
+    }
+}
+
+/// Reads from the stream on its own thread so that the consumer can use timeouts.
+struct PcmReader<R: Read + Send + 'static> {
+    stream: PcmStream<R>,
+    sample_size: usize,
+    sender: mpsc::SyncSender<StereoSample>,
+    position: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+}
+impl<R: Read + Send + 'static> PcmReader<R> {
+    fn read_loop(&mut self) -> Result<(), Error> {
+        let format = self.stream.format;
+        let channels = self.stream.channels as usize;
+        let rate = self.stream.rate;
+        let sample_size = self.sample_size;
+        let frame_bytes = format.bytes() * channels;
+        let mut buf = vec![0; frame_bytes * sample_size];
+        let mut data = vec![Vec::with_capacity(sample_size); channels];
+        let mut reader = BufReader::new(&mut self.stream.reader);
+        while !self.stop.load(Ordering::Relaxed) {
+            if let Err(e) = reader.read_exact(&mut buf) {
+                return match e.kind() {
+                    ErrorKind::UnexpectedEof => Ok(()), // the writer closed the stream
+                    _ => Err(e.into()),
+                };
+            }
+            for ch in data.iter_mut() {
+                ch.clear();
+            }
+            for frame in buf.chunks_exact(frame_bytes) {
+                for (ch, v) in data.iter_mut().zip(frame.chunks_exact(format.bytes())) {
+                    ch.push(format.decode(v));
+                }
+            }
+            let frames = self
+                .position
+                .fetch_add(sample_size as u64, Ordering::Relaxed);
+            let time = frames * 1_000_000 / rate as u64;
+            let mut ss = Sample::with_channels(channels, sample_size, rate, time);
+            ss.extend_channels(data.iter().map(|ch| ch.as_slice()));
+            if self.sender.send(ss).is_err() {
+                break; // the consumer was dropped
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct PcmSource<R: Read + Send + 'static> {
+    recv: mpsc::Receiver<StereoSample>,
+    handle: JoinHandle<Result<PcmStream<R>, Error>>,
+    position: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    rate: u32,
+}
+impl<R: Read + Send + 'static> ActiveAudioSource for PcmSource<R> {
+    type InactiveType = PcmStream<R>;
+    /// Stops the reading thread and returns the stream.
+    ///
+    /// This will block until the current read from the stream completes.
+    fn deactivate(self) -> Result<Self::InactiveType, Error> {
+        self.stop.store(true, Ordering::Relaxed);
+        drop(self.recv);
+        self.handle
+            .join()
+            .map_err(|_| Error::Unrecoverable("PCM reader thread panicked".to_string()))?
+    }
+    #[inline]
+    fn cur_time(&self) -> u64 {
+        self.position.load(Ordering::Relaxed) * 1_000_000 / self.rate as u64
+    }
+    fn recv(&mut self) -> Result<StereoSample, Error> {
+        self.recv
+            .recv()
+            .map_err(|_| Error::Unrecoverable("Audio producer is disconnected".to_string()))
+    }
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<StereoSample, Error> {
+        self.recv.recv_timeout(timeout).map_err(|x| match x {
+            mpsc::RecvTimeoutError::Timeout => Error::Timeout("Audio timed out".to_string()),
+            mpsc::RecvTimeoutError::Disconnected => {
+                Error::Unrecoverable("Audio producer is disconnected".to_string())
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn reads_every_channel() {
+        let mut pcm = Vec::new();
+        for f in 0..8 {
+            for c in 0..6 {
+                PcmFormat::S16LE.encode((c * 8 + f) as f32 / 64.0, &mut pcm);
+            }
+        }
+        let stream = PcmStream::new(Cursor::new(pcm), PcmFormat::S16LE, 6, 48000);
+        let options = AudioSourceOptions {
+            sample_size: 4,
+            fft_size: 4,
+            ..AudioSourceOptions::default()
+        };
+        let mut src = stream.activate(options).unwrap();
+        for s in 0..2 {
+            let ss = src.recv().unwrap();
+            assert_eq!(ss.channels(), 6);
+            assert_eq!(ss.len(), 4);
+            for c in 0..6 {
+                for (f, v) in ss.channel(c).iter().enumerate() {
+                    let expected = (c * 8 + s * 4 + f) as f32 / 64.0;
+                    assert!((v - expected).abs() < 0.001, "{} != {}", v, expected);
+                }
+            }
+        }
+        assert!(src.recv().is_err());
+    }
+}
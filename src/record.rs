@@ -0,0 +1,528 @@
+use crate::Error;
+use lecp::{Command, LedMsg, Sender};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::{Chars, FromStr};
+use std::time::{Duration, Instant};
+
+/// The first bytes of a binary recording, followed by its version.
+pub const MAGIC: [u8; 4] = *b"SYLR";
+/// The version of the recording formats written by `Recorder`.
+pub const VERSION: u8 = 1;
+
+/// The tag of `Command::FlatStack` in binary recordings.
+const TAG_FLAT_STACK: u8 = 0;
+
+/// How often the recording is flushed to disk.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The file formats of recordings.
+///
+/// A binary recording starts with `MAGIC` and `VERSION`. Each batch of messages
+/// follows as the microseconds since the previous batch and the number of messages,
+/// both LEB128 encoded, then four bytes per message: its element, color, command tag and
+/// command value.
+///
+/// A JSON lines recording starts with a line of `{"version":1}`. Each batch follows on its
+/// own line as `{"time":US,"msgs":[{"element":E,"color":C,"cmd":"flatstack","value":V},...]}`
+/// with the microseconds since the start of the recording.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Binary,
+    JsonLines,
+}
+impl Format {
+    /// JSON lines for files ending in `.jsonl` or `.json`, otherwise binary.
+    pub fn for_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("jsonl") | Some("json") => Format::JsonLines,
+            _ => Format::Binary,
+        }
+    }
+}
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "binary" => Ok(Format::Binary),
+            "json" | "jsonl" => Ok(Format::JsonLines),
+            _ => Err(format!("Unknown recording format: {}", s)),
+        }
+    }
+}
+
+/// A `Sender` that writes every batch of messages it is sent to a file with the time it was
+/// sent, to review a show afterwards or to test effects without any lights.
+///
+/// Batches passed to `Recorder::send_at()` are timed by the audio they were made from, so
+/// the recording keeps the timing of the music even when frames are processed late.
+/// Batches sent as a `Sender` are timed by the wall clock instead.
+///
+/// Commands that cannot be recorded are skipped and counted.
+pub struct Recorder<W: Write> {
+    out: W,
+    format: Format,
+    start: Instant,
+    /// The audio time, in microseconds, of the first batch passed to `send_at()`.
+    origin: Option<u64>,
+    last: u64,
+    flushed: Instant,
+    skipped: usize,
+    buf: Vec<u8>,
+}
+impl Recorder<BufWriter<File>> {
+    /// Creates the file at `path`, replacing any existing file, in the format of its extension.
+    pub fn create<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let format = Format::for_path(&path);
+        Recorder::new(BufWriter::new(File::create(path)?), format)
+    }
+}
+impl<W: Write> Recorder<W> {
+    /// Starts a recording in `format`, writing its header to `out`.
+    pub fn new(mut out: W, format: Format) -> std::io::Result<Self> {
+        match format {
+            Format::Binary => {
+                out.write_all(&MAGIC)?;
+                out.write_all(&[VERSION])?;
+            }
+            Format::JsonLines => writeln!(out, "{{\"version\":{}}}", VERSION)?,
+        }
+        let now = Instant::now();
+        Ok(Recorder {
+            out,
+            format,
+            start: now,
+            origin: None,
+            last: 0,
+            flushed: now,
+            skipped: 0,
+            buf: Vec::new(),
+        })
+    }
+    #[inline]
+    pub fn format(&self) -> Format {
+        self.format
+    }
+    /// The number of messages that were not recorded because their command is not supported.
+    #[inline]
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+    /// Records `msgs` made from the audio at `time` in microseconds, such as `Frame::time`.
+    ///
+    /// The first batch starts the recording, and batches earlier than the previous batch are
+    /// recorded at the same time as it.
+    pub fn send_at(&mut self, time: u64, msgs: &[LedMsg]) -> Result<(), lecp::Error> {
+        let origin = *self.origin.get_or_insert(time);
+        let time = time.saturating_sub(origin).max(self.last);
+        self.write(time, msgs)
+            .map_err(|e| lecp::Error::Unrecoverable(format!("Recording failed: {}", e)))
+    }
+    /// Flushes the recording and returns the writer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+    fn write(&mut self, time: u64, msgs: &[LedMsg]) -> std::io::Result<()> {
+        self.buf.clear();
+        let recorded = msgs.iter().filter_map(|msg| match msg.cmd {
+            Command::FlatStack(v) => Some((msg, TAG_FLAT_STACK, v)),
+            _ => None,
+        });
+        match self.format {
+            Format::Binary => {
+                write_leb128(&mut self.buf, time - self.last);
+                write_leb128(&mut self.buf, recorded.clone().count() as u64);
+                for (msg, tag, value) in recorded {
+                    self.buf
+                        .extend_from_slice(&[msg.element, msg.color, tag, value]);
+                }
+            }
+            Format::JsonLines => {
+                write!(self.buf, "{{\"time\":{},\"msgs\":[", time)?;
+                for (i, (msg, _, value)) in recorded.enumerate() {
+                    if i > 0 {
+                        self.buf.push(b',');
+                    }
+                    write!(
+                        self.buf,
+                        "{{\"element\":{},\"color\":{},\"cmd\":\"flatstack\",\"value\":{}}}",
+                        msg.element, msg.color, value
+                    )?;
+                }
+                self.buf.extend_from_slice(b"]}\n");
+            }
+        }
+        self.skipped += msgs
+            .iter()
+            .filter(|msg| !matches!(msg.cmd, Command::FlatStack(_)))
+            .count();
+        self.last = time;
+        self.out.write_all(&self.buf)?;
+        if self.flushed.elapsed() >= FLUSH_INTERVAL {
+            self.flushed = Instant::now();
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+}
+impl<W: Write> Sender for Recorder<W> {
+    fn send(&mut self, msgs: &[LedMsg]) -> Result<(), lecp::Error> {
+        let time = (self.start.elapsed().as_micros() as u64).max(self.last);
+        self.write(time, msgs)
+            .map_err(|e| lecp::Error::Unrecoverable(format!("Recording failed: {}", e)))
+    }
+    /// The milliseconds since the recording started, wrapping like the clock of other senders.
+    fn get_time(&self) -> u16 {
+        self.start.elapsed().as_millis() as u16
+    }
+}
+
+fn write_leb128(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let b = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(b);
+            return;
+        }
+        buf.push(b | 0x80);
+    }
+}
+
+/// A batch of messages read from a recording.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Batch {
+    /// The microseconds since the start of the recording.
+    pub time: u64,
+    pub msgs: Vec<LedMsg>,
+}
+
+/// A recording made by a `Recorder`, read back in either format.
+#[derive(Clone, Debug, Default)]
+pub struct Recording {
+    batches: Vec<Batch>,
+}
+impl Recording {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Recording::parse(&std::fs::read(path)?)
+    }
+    /// Decodes a recording, detecting its format from its header.
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.starts_with(&MAGIC) {
+            match bytes.get(MAGIC.len()) {
+                Some(&VERSION) => parse_binary(&bytes[MAGIC.len() + 1..]),
+                Some(v) => Err(invalid(&format!("Unsupported recording version: {}", v))),
+                None => Err(invalid("The recording ended unexpectedly")),
+            }
+        } else {
+            let s = std::str::from_utf8(bytes).map_err(|_| invalid("Not a recording"))?;
+            parse_json_lines(s)
+        }
+    }
+    #[inline]
+    pub fn batches(&self) -> &[Batch] {
+        &self.batches
+    }
+    /// The time of the last batch in microseconds.
+    pub fn duration(&self) -> u64 {
+        self.batches.last().map_or(0, |b| b.time)
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::Unrecoverable(msg.to_string())
+}
+
+fn read_leb128(bytes: &[u8], pos: &mut usize) -> Result<u64, Error> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let b = *bytes
+            .get(*pos)
+            .ok_or_else(|| invalid("The recording ended unexpectedly"))?;
+        *pos += 1;
+        value |= ((b & 0x7F) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("Invalid LEB128 number in the recording"))
+}
+
+fn command(tag: u8, value: u8) -> Result<Command, Error> {
+    match tag {
+        TAG_FLAT_STACK => Ok(Command::FlatStack(value)),
+        _ => Err(invalid(&format!(
+            "Unknown command in the recording: {}",
+            tag
+        ))),
+    }
+}
+
+fn parse_binary(bytes: &[u8]) -> Result<Recording, Error> {
+    let (mut pos, mut time) = (0, 0u64);
+    let mut batches = Vec::new();
+    while pos < bytes.len() {
+        time = time.saturating_add(read_leb128(bytes, &mut pos)?);
+        let count = read_leb128(bytes, &mut pos)? as usize;
+        let len = count
+            .checked_mul(4)
+            .filter(|len| *len <= bytes.len() - pos)
+            .ok_or_else(|| invalid("The recording ended unexpectedly"))?;
+        let msgs = bytes[pos..pos + len]
+            .chunks(4)
+            .map(|m| {
+                Ok(LedMsg {
+                    element: m[0],
+                    color: m[1],
+                    cmd: command(m[2], m[3])?,
+                    ..LedMsg::default()
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        pos += len;
+        batches.push(Batch { time, msgs });
+    }
+    Ok(Recording { batches })
+}
+
+fn parse_json_lines(s: &str) -> Result<Recording, Error> {
+    let mut lines = s.lines().map(str::trim).filter(|l| !l.is_empty());
+    let header = lines
+        .next()
+        .ok_or_else(|| invalid("The recording is empty"))?;
+    match Json::parse(header)?.get("version") {
+        Some(Json::Num(v)) if *v == VERSION as u64 => (),
+        Some(Json::Num(v)) => {
+            return Err(invalid(&format!("Unsupported recording version: {}", v)))
+        }
+        _ => return Err(invalid("Not a recording")),
+    }
+    let mut batches = Vec::new();
+    for line in lines {
+        let bad = || invalid(&format!("Invalid batch in the recording: {}", line));
+        let batch = Json::parse(line)?;
+        let time = batch.get("time").and_then(Json::num).ok_or_else(bad)?;
+        let msgs = match batch.get("msgs") {
+            Some(Json::Arr(msgs)) => msgs,
+            _ => return Err(bad()),
+        };
+        let byte = |msg: &Json, key: &str| {
+            let n = msg.get(key).and_then(Json::num).ok_or_else(bad)?;
+            u8::try_from(n).map_err(|_| bad())
+        };
+        let msgs = msgs
+            .iter()
+            .map(|msg| {
+                let cmd = match msg.get("cmd") {
+                    Some(Json::Str(cmd)) if cmd == "flatstack" => TAG_FLAT_STACK,
+                    _ => return Err(bad()),
+                };
+                Ok(LedMsg {
+                    element: byte(msg, "element")?,
+                    color: byte(msg, "color")?,
+                    cmd: command(cmd, byte(msg, "value")?)?,
+                    ..LedMsg::default()
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        batches.push(Batch { time, msgs });
+    }
+    Ok(Recording { batches })
+}
+
+/// The subset of JSON written by `Recorder`: objects, arrays, strings and unsigned integers.
+enum Json {
+    Num(u64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+impl Json {
+    fn parse(s: &str) -> Result<Json, Error> {
+        let mut chars = s.chars().peekable();
+        let value = Json::value(&mut chars)?;
+        match chars.find(|c| !c.is_whitespace()) {
+            Some(_) => Err(invalid(&format!("Unexpected data after JSON value: {}", s))),
+            None => Ok(value),
+        }
+    }
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Obj(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+    fn num(&self) -> Option<u64> {
+        match self {
+            Json::Num(n) => Some(*n),
+            _ => None,
+        }
+    }
+    fn next(chars: &mut Peekable<Chars>) -> Result<char, Error> {
+        chars
+            .find(|c| !c.is_whitespace())
+            .ok_or_else(|| invalid("Unexpected end of JSON"))
+    }
+    /// Parses the elements of an array or object after its opening bracket.
+    fn list<T>(
+        chars: &mut Peekable<Chars>,
+        close: char,
+        mut elem: impl FnMut(&mut Peekable<Chars>) -> Result<T, Error>,
+    ) -> Result<Vec<T>, Error> {
+        let mut list = Vec::new();
+        while matches!(chars.peek(), Some(c) if c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek() == Some(&close) {
+            chars.next();
+            return Ok(list);
+        }
+        loop {
+            list.push(elem(chars)?);
+            match Json::next(chars)? {
+                ',' => (),
+                c if c == close => return Ok(list),
+                c => return Err(invalid(&format!("Unexpected {} in JSON", c))),
+            }
+        }
+    }
+    fn string(chars: &mut Peekable<Chars>) -> Result<String, Error> {
+        let mut s = String::new();
+        loop {
+            match chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match chars.next() {
+                    Some(c @ '"') | Some(c @ '\\') | Some(c @ '/') => s.push(c),
+                    _ => return Err(invalid("Unsupported escape in JSON string")),
+                },
+                Some(c) => s.push(c),
+                None => return Err(invalid("Unexpected end of JSON")),
+            }
+        }
+    }
+    fn value(chars: &mut Peekable<Chars>) -> Result<Json, Error> {
+        match Json::next(chars)? {
+            '{' => Json::list(chars, '}', |chars| {
+                if Json::next(chars)? != '"' {
+                    return Err(invalid("Expected a key in JSON object"));
+                }
+                let key = Json::string(chars)?;
+                if Json::next(chars)? != ':' {
+                    return Err(invalid("Expected : in JSON object"));
+                }
+                Ok((key, Json::value(chars)?))
+            })
+            .map(Json::Obj),
+            '[' => Json::list(chars, ']', Json::value).map(Json::Arr),
+            '"' => Json::string(chars).map(Json::Str),
+            c @ '0'..='9' => {
+                let mut n = c.to_digit(10).unwrap() as u64;
+                while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                    chars.next();
+                    n = n
+                        .checked_mul(10)
+                        .and_then(|n| n.checked_add(d as u64))
+                        .ok_or_else(|| invalid("JSON number is too large"))?;
+                }
+                Ok(Json::Num(n))
+            }
+            c => Err(invalid(&format!("Unexpected {} in JSON", c))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(element: u8, color: u8, cmd: Command) -> LedMsg {
+        LedMsg {
+            element,
+            color,
+            cmd,
+            ..LedMsg::default()
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let batches = [
+            (5_000_000, vec![msg(0, 1, Command::FlatStack(0))]),
+            (
+                5_016_000,
+                vec![
+                    msg(3, 2, Command::FlatStack(255)),
+                    msg(4, 2, Command::PulseLinear(7)),
+                    msg(255, 255, Command::FlatStack(128)),
+                ],
+            ),
+            (5_016_000, Vec::new()),
+            // earlier than the previous batch, such as after the audio source restarted
+            (4_000_000, vec![msg(1, 1, Command::FlatStack(1))]),
+            (5_000_000_000, vec![msg(2, 0, Command::FlatStack(2))]),
+        ];
+        for &format in &[Format::Binary, Format::JsonLines] {
+            let mut recorder = Recorder::new(Vec::new(), format).unwrap();
+            for (time, msgs) in batches.iter() {
+                recorder.send_at(*time, msgs).unwrap();
+            }
+            assert_eq!(recorder.skipped(), 1);
+            let bytes = recorder.finish().unwrap();
+            let recording = Recording::parse(&bytes).unwrap();
+            let times: Vec<u64> = recording.batches().iter().map(|b| b.time).collect();
+            assert_eq!(
+                times,
+                [0, 16_000, 16_000, 16_000, 4_995_000_000],
+                "{:?}",
+                format
+            );
+            let recorded: Vec<LedMsg> = batches
+                .iter()
+                .flat_map(|(_, msgs)| msgs.iter())
+                .filter(|m| matches!(m.cmd, Command::FlatStack(_)))
+                .cloned()
+                .collect();
+            let parsed: Vec<LedMsg> = recording
+                .batches()
+                .iter()
+                .flat_map(|b| b.msgs.iter().cloned())
+                .collect();
+            assert_eq!(parsed, recorded, "{:?}", format);
+            assert_eq!(recording.duration(), 4_995_000_000);
+        }
+    }
+
+    #[test]
+    fn sender_uses_the_wall_clock() {
+        for &format in &[Format::Binary, Format::JsonLines] {
+            let mut recorder = Recorder::new(Vec::new(), format).unwrap();
+            recorder.send(&[msg(0, 1, Command::FlatStack(9))]).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+            recorder.send(&[]).unwrap();
+            let recording = Recording::parse(&recorder.finish().unwrap()).unwrap();
+            let batches = recording.batches();
+            assert_eq!(batches.len(), 2);
+            assert_eq!(batches[0].msgs, [msg(0, 1, Command::FlatStack(9))]);
+            assert!(batches[1].time >= batches[0].time + 20_000, "{:?}", batches);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_recordings() {
+        assert!(Recording::parse(b"").is_err());
+        assert!(Recording::parse(b"SYLR").is_err());
+        assert!(Recording::parse(b"SYLR\x02").is_err());
+        // a batch of two messages with only one
+        assert!(Recording::parse(b"SYLR\x01\x00\x02\x00\x00\x00\x00").is_err());
+        assert!(Recording::parse(b"{\"version\":2}").is_err());
+        assert!(Recording::parse(b"{\"version\":1}\n{\"time\":0}").is_err());
+        let bad_cmd = "{\"version\":1}\n{\"time\":0,\"msgs\":[{\"element\":0,\"color\":0,\"cmd\":\"pulse\",\"value\":0}]}";
+        assert!(Recording::parse(bad_cmd.as_bytes()).is_err());
+        let empty = Recording::parse(b"SYLR\x01").unwrap();
+        assert!(empty.batches().is_empty());
+    }
+}
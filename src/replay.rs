@@ -0,0 +1,228 @@
+use crate::record::{Batch, Recording};
+use crate::Error;
+use lecp::Sender;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Plays a `Recording` back with its original timing, at any speed, with looping and seeking.
+///
+/// The messages are restamped with the time of each sender, like those of an `AudioVisualizer`.
+pub struct Player {
+    recording: Recording,
+    /// The index of the next batch.
+    pos: usize,
+    speed: f64,
+    /// Starts again from the beginning at the end of the recording.
+    pub looping: bool,
+    /// The position in the recording, in microseconds, at `origin`.
+    origin_time: u64,
+    origin: Instant,
+}
+impl Player {
+    pub fn new(recording: Recording) -> Self {
+        Player {
+            recording,
+            pos: 0,
+            speed: 1.0,
+            looping: false,
+            origin_time: 0,
+            origin: Instant::now(),
+        }
+    }
+    #[inline]
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+    #[inline]
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+    /// Sets how fast the recording is played, where 1.0 is the speed it was recorded at.
+    /// When 0.0, batches are released as fast as they are asked for.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.origin_time = self.position();
+        self.origin = Instant::now();
+        self.speed = speed.max(0.0);
+    }
+    /// The position of playback in microseconds from the start of the recording.
+    pub fn position(&self) -> u64 {
+        if self.speed > 0.0 {
+            let elapsed = self.origin.elapsed().as_secs_f64() * self.speed * 1e6;
+            self.origin_time + elapsed as u64
+        } else {
+            self.origin_time
+        }
+    }
+    /// Continues playback from `time`, in microseconds from the start of the recording.
+    pub fn seek(&mut self, time: u64) {
+        let batches = self.recording.batches();
+        self.pos = batches.iter().take_while(|b| b.time < time).count();
+        self.origin_time = time;
+        self.origin = Instant::now();
+    }
+    /// Waits until the next batch is due and returns it, or `None` at the end of the recording.
+    pub fn next_batch(&mut self) -> Option<&Batch> {
+        if self.pos >= self.recording.batches().len() {
+            // a recording without any length would be sent over and over without waiting
+            if !self.looping || self.recording.duration() == 0 {
+                return None;
+            }
+            self.seek(0);
+        }
+        let time = self.recording.batches()[self.pos].time;
+        if self.speed > 0.0 {
+            let deadline = self.deadline(time);
+            let now = Instant::now();
+            if deadline > now {
+                sleep(deadline - now);
+            }
+        } else {
+            self.origin_time = time;
+        }
+        self.pos += 1;
+        Some(&self.recording.batches()[self.pos - 1])
+    }
+    /// When the batch at `time` is due, at the current speed.
+    fn deadline(&self, time: u64) -> Instant {
+        let ahead = time.saturating_sub(self.origin_time) as f64 / 1e6 / self.speed;
+        self.origin + Duration::from_secs_f64(ahead)
+    }
+    /// Sends every batch to `senders` until the end of the recording, which never comes when looping.
+    ///
+    /// Playback continues from the position the player is at, even if it was set long before.
+    pub fn play(&mut self, senders: &mut [Box<dyn Sender>]) -> Result<(), Error> {
+        // connecting the senders can take seconds, which must not count as played
+        self.origin = Instant::now();
+        let mut msgs = Vec::new();
+        while let Some(batch) = self.next_batch() {
+            msgs.clear();
+            msgs.extend_from_slice(&batch.msgs);
+            for sender in senders.iter_mut() {
+                let cur_time = sender.get_time();
+                for msg in msgs.iter_mut() {
+                    msg.time = cur_time;
+                }
+                sender.send(&msgs)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lecp::LedMsg;
+    use std::sync::{Arc, Mutex};
+
+    /// A recording with a batch with the element of each of `times`.
+    fn recording(times: &[u64]) -> Recording {
+        let mut s = String::from("{\"version\":1}\n");
+        for (i, time) in times.iter().enumerate() {
+            s += &format!(
+                "{{\"time\":{},\"msgs\":[{{\"element\":{},\"color\":0,\"cmd\":\"flatstack\",\"value\":0}}]}}\n",
+                time, i
+            );
+        }
+        Recording::parse(s.as_bytes()).unwrap()
+    }
+    fn next_time(player: &mut Player) -> Option<u64> {
+        player.next_batch().map(|b| b.time)
+    }
+
+    #[test]
+    fn seek() {
+        let mut player = Player::new(recording(&[0, 100, 200, 300]));
+        player.set_speed(0.0);
+        player.seek(150);
+        assert_eq!(player.position(), 150);
+        assert_eq!(next_time(&mut player), Some(200));
+        assert_eq!(player.position(), 200);
+        // a batch exactly at the position is played
+        player.seek(100);
+        assert_eq!(next_time(&mut player), Some(100));
+        player.seek(1000);
+        assert_eq!(next_time(&mut player), None);
+    }
+
+    #[test]
+    fn speed() {
+        let mut player = Player::new(recording(&[0, 1_000_000, 3_000_000]));
+        player.set_speed(2.0);
+        player.seek(1_000_000);
+        assert_eq!(
+            player.deadline(3_000_000) - player.origin,
+            Duration::from_secs(1)
+        );
+        // batches before the position are due straight away
+        assert_eq!(player.deadline(0), player.origin);
+        player.set_speed(0.5);
+        // changing the speed keeps the position reached so far
+        assert!(player.origin_time >= 1_000_000);
+        let ahead = (3_000_000 - player.origin_time) as f64 / 1e6 / 0.5;
+        let deadline = player.deadline(3_000_000) - player.origin;
+        assert!((deadline.as_secs_f64() - ahead).abs() < 1e-6);
+        player.set_speed(-1.0);
+        assert_eq!(player.speed(), 0.0);
+    }
+
+    #[test]
+    fn loop_wraps_around() {
+        let mut player = Player::new(recording(&[0, 100, 200]));
+        player.set_speed(0.0);
+        player.looping = true;
+        let times: Vec<_> = (0..7).map(|_| next_time(&mut player).unwrap()).collect();
+        assert_eq!(times, [0, 100, 200, 0, 100, 200, 0]);
+        assert_eq!(player.position(), 0);
+
+        player.looping = false;
+        player.seek(200);
+        assert_eq!(next_time(&mut player), Some(200));
+        assert_eq!(next_time(&mut player), None);
+    }
+
+    #[test]
+    fn loop_has_a_zero_length_last_frame() {
+        // the recording ends with its last batch, so the first one follows it straight away
+        let mut player = Player::new(recording(&[0, 1_000_000]));
+        player.looping = true;
+        player.seek(1_000_000);
+        let start = Instant::now();
+        assert_eq!(next_time(&mut player), Some(1_000_000));
+        assert_eq!(next_time(&mut player), Some(0));
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // a recording without any length is played once instead of over and over
+        let mut player = Player::new(recording(&[0]));
+        player.set_speed(0.0);
+        player.looping = true;
+        assert_eq!(next_time(&mut player), Some(0));
+        assert_eq!(next_time(&mut player), None);
+    }
+
+    /// Collects the elements and times of the messages sent.
+    struct Sent(Arc<Mutex<Vec<(u8, u16)>>>);
+    impl Sender for Sent {
+        fn send(&mut self, msgs: &[LedMsg]) -> Result<(), lecp::Error> {
+            let mut sent = self.0.lock().unwrap();
+            sent.extend(msgs.iter().map(|m| (m.element, m.time)));
+            Ok(())
+        }
+        fn get_time(&self) -> u16 {
+            42
+        }
+    }
+
+    #[test]
+    fn play_starts_when_called() {
+        let mut player = Player::new(recording(&[0, 50_000, 100_000]));
+        // time spent before playing, such as connecting the senders, is not played
+        sleep(Duration::from_millis(150));
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut senders: Vec<Box<dyn Sender>> = vec![Box::new(Sent(sent.clone()))];
+        let start = Instant::now();
+        player.play(&mut senders).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(*sent.lock().unwrap(), [(0, 42), (1, 42), (2, 42)]);
+    }
+}
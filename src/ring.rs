@@ -0,0 +1,175 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Slot<T> {
+    /// Equal to the position of the slot when it is free to be pushed to,
+    /// and one past it when it holds a value that can be popped.
+    seq: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A fixed-size lock-free queue for passing values between threads without
+/// allocating or blocking, such as out of a realtime audio callback.
+///
+/// Either end may pop, so a producer can make room by discarding the oldest value
+/// when the consumer falls behind. This is the bounded queue of Dmitry Vyukov.
+pub struct Ring<T> {
+    slots: Box<[Slot<T>]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+impl<T> Ring<T> {
+    /// Creates a queue of `capacity` values, which must be at least 2.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity >= 2, "a ring needs at least two slots");
+        let slots = (0..capacity)
+            .map(|i| Slot {
+                seq: AtomicUsize::new(i),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect();
+        Ring {
+            slots,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+    /// The number of values queued, which may already be out of date.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(head).min(self.capacity())
+    }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Appends `value`, or gives it back if the queue is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % self.slots.len()];
+            let seq = slot.seq.load(Ordering::Acquire);
+            match seq.wrapping_sub(pos) as isize {
+                0 => match self.tail.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).as_mut_ptr().write(value) };
+                        slot.seq.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(cur) => pos = cur,
+                },
+                d if d < 0 => return Err(value),
+                _ => pos = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+    /// Removes the oldest value, or returns `None` if the queue is empty.
+    pub fn pop(&self) -> Option<T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % self.slots.len()];
+            let seq = slot.seq.load(Ordering::Acquire);
+            match seq.wrapping_sub(pos.wrapping_add(1)) as isize {
+                0 => match self.head.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).as_ptr().read() };
+                        let next = pos.wrapping_add(self.slots.len());
+                        slot.seq.store(next, Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(cur) => pos = cur,
+                },
+                d if d < 0 => return None,
+                _ => pos = self.head.load(Ordering::Relaxed),
+            }
+        }
+    }
+}
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn fifo() {
+        let ring = Ring::new(3);
+        assert_eq!(ring.pop(), None);
+        for round in 0..5 {
+            for i in 0..3 {
+                assert_eq!(ring.push(round * 3 + i), Ok(()));
+            }
+            assert_eq!(ring.len(), 3);
+            assert_eq!(ring.push(99), Err(99));
+            for i in 0..3 {
+                assert_eq!(ring.pop(), Some(round * 3 + i));
+            }
+            assert!(ring.is_empty());
+        }
+    }
+
+    #[test]
+    fn drops_remaining_values() {
+        let value = Arc::new(());
+        let ring = Ring::new(4);
+        ring.push(value.clone()).unwrap();
+        ring.push(value.clone()).unwrap();
+        assert_eq!(Arc::strong_count(&value), 3);
+        drop(ring);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn producer_discards_oldest() {
+        const N: usize = 100_000;
+        let ring = Arc::new(Ring::new(2));
+        let producer = {
+            let ring = ring.clone();
+            thread::spawn(move || {
+                let mut dropped = 0;
+                for i in 0..N {
+                    if let Err(i) = ring.push(i) {
+                        if ring.pop().is_some() {
+                            dropped += 1;
+                        }
+                        ring.push(i).unwrap();
+                    }
+                }
+                dropped
+            })
+        };
+        let mut received = Vec::new();
+        while received.last() != Some(&(N - 1)) {
+            if let Some(i) = ring.pop() {
+                received.push(i);
+            }
+        }
+        let dropped = producer.join().unwrap();
+        assert!(received.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(received.len() + dropped, N);
+    }
+}
@@ -0,0 +1,428 @@
+use crate::midi::{
+    data_len, ActiveMidiSource, InactiveMidiSource, MidiEvent, MidiMessage, MidiParser,
+};
+use crate::Error;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// A Standard MIDI File, of format 0 or 1, that has not started playing yet.
+pub struct MidiFile {
+    path: PathBuf,
+    /// How fast the file is played back, where 1.0 is the speed it was written at.
+    /// When 0.0, messages are released as fast as the consumer receives them.
+    pub speed: f64,
+}
+impl MidiFile {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        MidiFile {
+            path: path.as_ref().to_path_buf(),
+            speed: 1.0,
+        }
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Decodes the events of every track of a Standard MIDI File, merged in order of time.
+    ///
+    /// The time of each message is in microseconds from the start of the file.
+    /// Meta events, such as tempo changes, are used for timing but not returned.
+    pub fn parse(bytes: &[u8]) -> Result<Vec<MidiMessage>, Error> {
+        let mut reader = Reader { bytes, pos: 0 };
+        let (id, mut header) = reader.chunk()?;
+        if id != b"MThd" || header.bytes.len() < 6 {
+            return Err(invalid("Not a Standard MIDI File"));
+        }
+        let format = header.u16()?;
+        let tracks = header.u16()?;
+        let division = header.u16()?;
+        if format > 1 {
+            return Err(invalid(&format!(
+                "Unsupported MIDI file format: {}",
+                format
+            )));
+        }
+        let timing = if division & 0x8000 == 0 {
+            if division == 0 {
+                return Err(invalid("The MIDI file has no ticks per quarter note"));
+            }
+            Timing::Ppq(division as u64)
+        } else {
+            // the upper byte is the negated frame rate, where 29 is 30 drop frame
+            let fps = match ((division >> 8) as u8 as i8).checked_neg() {
+                Some(24) => 24.0,
+                Some(25) => 25.0,
+                Some(29) => 29.97,
+                Some(30) => 30.0,
+                _ => return Err(invalid("Invalid SMPTE frame rate in the MIDI file")),
+            };
+            let ticks = division & 0xFF;
+            if ticks == 0 {
+                return Err(invalid("The MIDI file has no ticks per SMPTE frame"));
+            }
+            Timing::Smpte(1e6 / (fps * ticks as f64))
+        };
+
+        // (tick, track, event) for every event, including tempo changes
+        let mut events = Vec::new();
+        let mut found = 0;
+        while found < tracks && reader.remaining() > 0 {
+            let (id, track) = reader.chunk()?;
+            if id == b"MTrk" {
+                read_track(track, found as usize, &mut events)?;
+                found += 1;
+            }
+        }
+        // a stable sort keeps the order of simultaneous events from the same track
+        events.sort_by_key(|(tick, track, _)| (*tick, *track));
+
+        // the default tempo is 120 BPM
+        let (mut tempo, mut base_tick, mut base_time) = (500_000, 0, 0);
+        let mut messages = Vec::with_capacity(events.len());
+        for (tick, _, event) in events {
+            let time = match timing {
+                Timing::Ppq(ppq) => base_time + (tick - base_tick) * tempo / ppq,
+                Timing::Smpte(us) => (tick as f64 * us) as u64,
+            };
+            match event {
+                Event::Tempo(t) => {
+                    tempo = t;
+                    base_tick = tick;
+                    base_time = time;
+                }
+                Event::Midi(event) => messages.push(MidiMessage { time, event }),
+            }
+        }
+        Ok(messages)
+    }
+}
+impl InactiveMidiSource for MidiFile {
+    type ActiveType = SmfSource;
+    fn activate(self) -> Result<Self::ActiveType, Error> {
+        let bytes = std::fs::read(&self.path)?;
+        let messages = MidiFile::parse(&bytes)?;
+        Ok(SmfSource {
+            file: self,
+            messages: messages.into_iter(),
+            next: None,
+            time: 0,
+            start: Instant::now(),
+        })
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::Unrecoverable(msg.to_string())
+}
+
+enum Timing {
+    /// Ticks per quarter note.
+    Ppq(u64),
+    /// Microseconds per tick.
+    Smpte(f64),
+}
+
+enum Event {
+    /// Microseconds per quarter note.
+    Tempo(u64),
+    Midi(MidiEvent),
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+impl<'a> Reader<'a> {
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.remaining() {
+            return Err(invalid("The MIDI file ended unexpectedly"));
+        }
+        self.pos += len;
+        Ok(&self.bytes[self.pos - len..self.pos])
+    }
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16, Error> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+    /// A variable length quantity of up to four bytes.
+    fn vlq(&mut self) -> Result<u64, Error> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let b = self.u8()?;
+            value = value << 7 | (b & 0x7F) as u64;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("Invalid variable length quantity in the MIDI file"))
+    }
+    fn chunk(&mut self) -> Result<(&'a [u8], Reader<'a>), Error> {
+        let id = self.take(4)?;
+        let b = self.take(4)?;
+        let len = u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize;
+        // tolerate a truncated final chunk
+        let len = len.min(self.remaining());
+        let bytes = self.take(len)?;
+        Ok((id, Reader { bytes, pos: 0 }))
+    }
+}
+
+fn read_track(
+    mut r: Reader,
+    track: usize,
+    events: &mut Vec<(u64, usize, Event)>,
+) -> Result<(), Error> {
+    let mut parser = MidiParser::new();
+    let mut parsed = Vec::new();
+    let mut status = None;
+    let mut tick = 0;
+    while r.remaining() > 0 {
+        tick += r.vlq()?;
+        let first = r.u8()?;
+        if first >= 0xF0 {
+            // meta, SysEx and system common events cancel running status
+            status = None;
+        }
+        match first {
+            0xFF => {
+                let kind = r.u8()?;
+                let len = r.vlq()? as usize;
+                let data = r.take(len)?;
+                match (kind, data) {
+                    (0x2F, _) => break, // end of track
+                    (0x51, [a, b, c]) => {
+                        let tempo = (*a as u64) << 16 | (*b as u64) << 8 | *c as u64;
+                        events.push((tick, track, Event::Tempo(tempo)));
+                    }
+                    _ => (),
+                }
+            }
+            0xF0 => {
+                let len = r.vlq()? as usize;
+                let data = r.take(len)?;
+                let data = data.strip_suffix(&[0xF7]).unwrap_or(data);
+                events.push((tick, track, Event::Midi(MidiEvent::SysEx(data.to_vec()))));
+            }
+            0xF7 => {
+                // an escape containing any bytes, such as a SysEx split into packets
+                let len = r.vlq()? as usize;
+                parser.parse(r.take(len)?, &mut parsed);
+            }
+            _ => {
+                let (status, data) = if first & 0x80 != 0 {
+                    if first < 0xF0 {
+                        status = Some(first);
+                    }
+                    (first, r.take(data_len(first).unwrap_or(0))?)
+                } else {
+                    // running status: the byte just read is the first data byte
+                    let s = status.ok_or_else(|| invalid("MIDI data without a status byte"))?;
+                    r.pos -= 1;
+                    (s, r.take(data_len(s).unwrap_or(0))?)
+                };
+                parser.parse(&[status], &mut parsed);
+                parser.parse(data, &mut parsed);
+            }
+        }
+        events.extend(parsed.drain(..).map(|e| (tick, track, Event::Midi(e))));
+    }
+    Ok(())
+}
+
+/// A MIDI file that is being played back.
+pub struct SmfSource {
+    file: MidiFile,
+    messages: std::vec::IntoIter<MidiMessage>,
+    next: Option<MidiMessage>,
+    time: u64,
+    start: Instant,
+}
+impl SmfSource {
+    /// The instant at which a message at `time` is due.
+    fn deadline(&self, time: u64) -> Instant {
+        self.start + Duration::from_secs_f64(time as f64 / 1e6 / self.file.speed)
+    }
+    fn peek(&mut self) -> Option<&MidiMessage> {
+        if self.next.is_none() {
+            self.next = self.messages.next();
+        }
+        self.next.as_ref()
+    }
+    fn take(&mut self) -> Option<MidiMessage> {
+        let msg = self.next.take()?;
+        self.time = msg.time;
+        Some(msg)
+    }
+}
+impl Iterator for SmfSource {
+    type Item = MidiMessage;
+    fn next(&mut self) -> Option<Self::Item> {
+        let time = self.peek()?.time;
+        if self.file.speed > 0.0 {
+            let deadline = self.deadline(time);
+            let now = Instant::now();
+            if deadline > now {
+                sleep(deadline - now);
+            }
+        }
+        self.take()
+    }
+}
+impl ActiveMidiSource for SmfSource {
+    type InactiveType = MidiFile;
+    #[inline]
+    fn deactivate(self) -> Result<Self::InactiveType, Error> {
+        Ok(self.file)
+    }
+    /// The position of playback in microseconds from the start of the file.
+    fn cur_time(&self) -> u64 {
+        if self.file.speed > 0.0 {
+            (self.start.elapsed().as_secs_f64() * self.file.speed * 1e6) as u64
+        } else {
+            self.time
+        }
+    }
+    fn try_recv(&mut self) -> Option<MidiMessage> {
+        let time = self.peek()?.time;
+        if self.file.speed > 0.0 && self.deadline(time) > Instant::now() {
+            return None;
+        }
+        self.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MidiEvent::*;
+
+    fn vlq(mut v: u64, out: &mut Vec<u8>) {
+        let mut bytes = vec![(v & 0x7F) as u8];
+        v >>= 7;
+        while v > 0 {
+            bytes.push((v & 0x7F) as u8 | 0x80);
+            v >>= 7;
+        }
+        out.extend(bytes.iter().rev());
+    }
+
+    /// A file with tracks of `(delta ticks, event bytes)`.
+    fn smf(format: u16, division: u16, tracks: &[&[(u64, &[u8])]]) -> Vec<u8> {
+        let mut out = b"MThd".to_vec();
+        out.extend_from_slice(&6u32.to_be_bytes());
+        out.extend_from_slice(&format.to_be_bytes());
+        out.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        out.extend_from_slice(&division.to_be_bytes());
+        for track in tracks {
+            let mut data = Vec::new();
+            for (delta, event) in track.iter() {
+                vlq(*delta, &mut data);
+                data.extend_from_slice(event);
+            }
+            data.extend_from_slice(&[0, 0xFF, 0x2F, 0]);
+            out.extend_from_slice(b"MTrk");
+            out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            out.extend(data);
+        }
+        out
+    }
+
+    fn timed(messages: Vec<MidiMessage>) -> Vec<(u64, MidiEvent)> {
+        messages.into_iter().map(|m| (m.time, m.event)).collect()
+    }
+
+    #[test]
+    fn format_0() {
+        let file = smf(
+            0,
+            96,
+            &[&[
+                (0, &[0x90, 60, 100]),
+                (48, &[62, 100]), // running status
+                (0, &[0xF0, 3, 0x7E, 1, 0xF7]),
+                (48, &[0x80, 60, 0]),
+                (0, &[0xFF, 0x01, 2, b'h', b'i']),
+                (96, &[0xB1, 7, 64]),
+            ]],
+        );
+        assert_eq!(
+            timed(MidiFile::parse(&file).unwrap()),
+            vec![
+                (0, NoteOn(0, 60, 100)),
+                (250_000, NoteOn(0, 62, 100)),
+                (250_000, SysEx(vec![0x7E, 1])),
+                (500_000, NoteOff(0, 60, 0)),
+                (1_000_000, CtrlChg(1, 7, 64)),
+            ]
+        );
+    }
+
+    #[test]
+    fn format_1_tempo_map() {
+        let tempo = |us: u32| {
+            let b = us.to_be_bytes();
+            [0xFF, 0x51, 3, b[1], b[2], b[3]]
+        };
+        let (slow, fast) = (tempo(1_000_000), tempo(250_000));
+        let file = smf(
+            1,
+            96,
+            &[
+                &[(0, &slow), (96, &fast)],
+                &[(96, &[0x90, 60, 100]), (96, &[0x90, 60, 0])],
+                &[(48, &[0x91, 61, 100]), (144, &[0x81, 61, 0])],
+            ],
+        );
+        assert_eq!(
+            timed(MidiFile::parse(&file).unwrap()),
+            vec![
+                (500_000, NoteOn(1, 61, 100)),
+                (1_000_000, NoteOn(0, 60, 100)),
+                (1_250_000, NoteOn(0, 60, 0)),
+                (1_250_000, NoteOff(1, 61, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn meta_and_sysex_cancel_running_status() {
+        let events: [&[u8]; 3] = [&[0xFF, 0x01, 0], &[0xF0, 1, 0xF7], &[0xF7, 1, 0xF8]];
+        for event in events.iter() {
+            let file = smf(
+                0,
+                96,
+                &[&[(0, &[0x90, 60, 100]), (0, event), (0, &[61, 100])]],
+            );
+            assert!(MidiFile::parse(&file).is_err());
+        }
+    }
+
+    #[test]
+    fn smpte() {
+        // 25 fps with 40 ticks per frame is a millisecond per tick
+        let file = smf(0, 0xE728, &[&[(500, &[0x90, 60, 100])]]);
+        assert_eq!(
+            timed(MidiFile::parse(&file).unwrap()),
+            vec![(500_000, NoteOn(0, 60, 100))]
+        );
+        let file = smf(0, 0xE350, &[&[(2997, &[0x90, 60, 100])]]);
+        assert_eq!(MidiFile::parse(&file).unwrap()[0].time, 1_250_000);
+        for &division in &[0x8001, 0xE900, 0xE700, 0xFF01] {
+            assert!(MidiFile::parse(&smf(0, division, &[])).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(MidiFile::parse(b"RIFF").is_err());
+        assert!(MidiFile::parse(&smf(2, 96, &[])).is_err());
+        assert!(MidiFile::parse(&smf(0, 0, &[])).is_err());
+        assert!(MidiFile::parse(&smf(0, 96, &[])).unwrap().is_empty());
+    }
+}
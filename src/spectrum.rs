@@ -0,0 +1,377 @@
+use crate::audio::StereoSample;
+use rustfft::num_complex::Complex;
+use rustfft::num_traits::Zero;
+use rustfft::{FFTplanner, FFT};
+use std::f64::consts::PI;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// The smallest power used when converting to dB, which keeps silence from becoming -inf.
+const MIN_POWER: f32 = 1e-20;
+
+/// Window functions applied to each block of samples before the FFT to reduce spectral leakage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Window {
+    Rectangular,
+    Hann,
+    Hamming,
+    /// The 4-term Blackman-Harris window.
+    BlackmanHarris,
+    /// The Kaiser window with the given beta.
+    Kaiser(f32),
+    FlatTop,
+}
+impl Default for Window {
+    fn default() -> Self {
+        Window::Rectangular
+    }
+}
+impl FromStr for Window {
+    type Err = String;
+    /// Parses `rect`, `hann`, `hamming`, `blackman-harris`, `flat-top` or `kaiser[:BETA]`,
+    /// where `BETA` defaults to 8.6.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap().to_ascii_lowercase();
+        match (name.as_str(), parts.next()) {
+            ("rect", None) | ("rectangular", None) => Ok(Window::Rectangular),
+            ("hann", None) => Ok(Window::Hann),
+            ("hamming", None) => Ok(Window::Hamming),
+            ("blackman-harris", None) => Ok(Window::BlackmanHarris),
+            ("flat-top", None) => Ok(Window::FlatTop),
+            ("kaiser", None) => Ok(Window::Kaiser(8.6)),
+            ("kaiser", Some(beta)) => f32::from_str(beta)
+                .map(Window::Kaiser)
+                .map_err(|e| format!("Invalid Kaiser beta: {:?}", e)),
+            _ => Err(format!("Unknown window: {}", s)),
+        }
+    }
+}
+impl Window {
+    /// Computes the periodic (DFT-even) coefficients of the window for `len` samples.
+    pub fn coefficients(&self, len: usize) -> Vec<f32> {
+        let n = len as f64;
+        let cosine_sum = |a: &[f64], i: usize| {
+            let x = 2.0 * PI * i as f64 / n;
+            let mut sum = 0.0;
+            for (k, a) in a.iter().enumerate() {
+                let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+                sum += sign * a * (k as f64 * x).cos();
+            }
+            sum
+        };
+        (0..len)
+            .map(|i| {
+                let c = match self {
+                    Window::Rectangular => 1.0,
+                    Window::Hann => cosine_sum(&[0.5, 0.5], i),
+                    Window::Hamming => cosine_sum(&[0.54, 0.46], i),
+                    Window::BlackmanHarris => cosine_sum(&[0.35875, 0.48829, 0.14128, 0.01168], i),
+                    Window::FlatTop => cosine_sum(
+                        &[
+                            0.21557895,
+                            0.41663158,
+                            0.277263158,
+                            0.083578947,
+                            0.006947368,
+                        ],
+                        i,
+                    ),
+                    Window::Kaiser(beta) => {
+                        let beta = *beta as f64;
+                        let r = 2.0 * i as f64 / n - 1.0;
+                        bessel_i0(beta * (1.0 - r * r).sqrt()) / bessel_i0(beta)
+                    }
+                };
+                c as f32
+            })
+            .collect()
+    }
+}
+
+/// The zeroth-order modified Bessel function of the first kind.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..64 {
+        term *= half / k as f64;
+        let t = term * term;
+        sum += t;
+        if t < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+/// How the magnitude of each bin is scaled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Normalization {
+    /// The raw magnitude of the FFT, as if a rectangular window was used.
+    None,
+    /// Compensates for the coherent gain of the window so a tone reads the same level
+    /// that it would with a rectangular window. This keeps levels that were tuned without
+    /// a window unchanged.
+    CoherentGain,
+    /// Scales the magnitude so that a full-scale sine wave reads 0 dB.
+    Amplitude,
+}
+impl Default for Normalization {
+    fn default() -> Self {
+        Normalization::None
+    }
+}
+impl FromStr for Normalization {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Normalization::None),
+            "coherent" => Ok(Normalization::CoherentGain),
+            "amplitude" => Ok(Normalization::Amplitude),
+            _ => Err(format!("Unknown normalization: {}", s)),
+        }
+    }
+}
+
+/// Options controlling how a `StereoSample` is converted into a spectrum.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SpectrumOptions {
+    pub window: Window,
+    pub normalization: Normalization,
+    /// The fraction of each window shared with the next, from 0.0 to 1.0,
+    /// or `None` to use `AudioSourceOptions::hop_size`.
+    pub overlap: Option<f32>,
+}
+impl SpectrumOptions {
+    /// The number of frames between the starts of successive windows of `fft_size`.
+    pub fn hop_size(&self, fft_size: usize, default: usize) -> usize {
+        match self.overlap {
+            Some(overlap) => {
+                let hop = (fft_size as f32 * (1.0 - overlap.min(1.0).max(0.0))).round();
+                (hop as usize).max(1)
+            }
+            None => default.max(1),
+        }
+    }
+    /// Precompute the window for FFTs of `fft_size`.
+    pub fn build(&self, fft_size: usize) -> SpectrumWindow {
+        let coefficients = self.window.coefficients(fft_size);
+        let sum: f32 = coefficients.iter().sum();
+        let scale = match self.normalization {
+            Normalization::None => 1.0,
+            Normalization::CoherentGain => fft_size as f32 / sum,
+            Normalization::Amplitude => 2.0 / sum,
+        };
+        SpectrumWindow {
+            coefficients,
+            offset: 20.0 * scale.log10(),
+        }
+    }
+}
+
+/// The precomputed coefficients of a window and its normalization.
+pub struct SpectrumWindow {
+    coefficients: Vec<f32>,
+    offset: f32,
+}
+impl SpectrumWindow {
+    #[inline]
+    pub fn coefficients(&self) -> &[f32] {
+        &self.coefficients
+    }
+    /// The normalization, in dB, added to every bin.
+    #[inline]
+    pub fn offset(&self) -> f32 {
+        self.offset
+    }
+}
+
+/// Computes power spectra of `StereoSample`s without allocating.
+///
+/// Both channels are real, so they are packed into the real and imaginary parts of a
+/// single complex FFT and separated afterwards, halving the number of transforms.
+/// Only the `fft_size / 2 + 1` bins up to the Nyquist frequency are produced.
+pub struct SpectrumAnalyzer {
+    fft: Arc<dyn FFT<f32>>,
+    window: SpectrumWindow,
+    hop_size: usize,
+    /// The hop size used when the options do not set an overlap.
+    default_hop_size: usize,
+    input: Vec<Complex<f32>>,
+    output: Vec<Complex<f32>>,
+}
+impl SpectrumAnalyzer {
+    pub fn new(fft_size: usize, hop_size: usize, options: &SpectrumOptions) -> Self {
+        SpectrumAnalyzer {
+            fft: FFTplanner::new(false).plan_fft(fft_size),
+            window: options.build(fft_size),
+            hop_size: options.hop_size(fft_size, hop_size),
+            default_hop_size: hop_size,
+            input: vec![Complex::zero(); fft_size],
+            output: vec![Complex::zero(); fft_size],
+        }
+    }
+    #[inline]
+    pub fn fft_size(&self) -> usize {
+        self.fft.len()
+    }
+    /// The number of bins written by `process()`.
+    #[inline]
+    pub fn bins(&self) -> usize {
+        self.fft.len() / 2 + 1
+    }
+    pub fn set_options(&mut self, options: &SpectrumOptions) {
+        self.window = options.build(self.fft.len());
+        self.hop_size = options.hop_size(self.fft.len(), self.default_hop_size);
+    }
+    /// Writes the power, in dB, of each bin of `ss` into `left` and `right`,
+    /// averaged over every window in the sample.
+    ///
+    /// `left` and `right` must be at least `bins()` long.
+    /// Samples shorter than the FFT are padded with silence.
+    #[inline]
+    pub fn process(&mut self, ss: &StereoSample, left: &mut [f32], right: &mut [f32]) {
+        self.process_pair(ss.left(), ss.right(), left, right)
+    }
+    /// Like `process()`, but for any two channels of equal length.
+    pub fn process_pair(
+        &mut self,
+        l_in: &[f32],
+        r_in: &[f32],
+        left: &mut [f32],
+        right: &mut [f32],
+    ) {
+        let n = self.fft.len();
+        let bins = self.bins();
+        let n_windows = if l_in.len() >= n {
+            (l_in.len() - n) / self.hop_size + 1
+        } else {
+            1
+        };
+        let (left, right) = (&mut left[..bins], &mut right[..bins]);
+        for v in left.iter_mut().chain(right.iter_mut()) {
+            *v = 0.0;
+        }
+        let coefficients = self.window.coefficients();
+        for w in 0..n_windows {
+            let start = w * self.hop_size;
+            for (i, z) in self.input.iter_mut().enumerate() {
+                let c = coefficients[i];
+                let l = l_in.get(start + i).copied().unwrap_or(0.0);
+                let r = r_in.get(start + i).copied().unwrap_or(0.0);
+                *z = Complex::new(l * c, r * c);
+            }
+            self.fft.process(&mut self.input, &mut self.output);
+            /* Separate the channels using the symmetry of real-valued FFTs:
+               L[k] = (Z[k] + conj(Z[N-k])) / 2 and R[k] = (Z[k] - conj(Z[N-k])) / 2i.
+               The magnitude is then converted to dB as 10 * log_10(amp^2), which
+               avoids an expensive sqrt.
+            */
+            for k in 0..bins {
+                let z = self.output[k];
+                let zc = self.output[(n - k) % n].conj();
+                let l = (z + zc) * 0.5;
+                let r = (z - zc) * Complex::new(0.0, -0.5);
+                left[k] += l.norm_sqr().max(MIN_POWER).log10() * 10.0;
+                right[k] += r.norm_sqr().max(MIN_POWER).log10() * 10.0;
+            }
+        }
+        let offset = self.window.offset();
+        let n_windows = n_windows as f32;
+        for v in left.iter_mut().chain(right.iter_mut()) {
+            *v = *v / n_windows + offset;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlap_overrides_hop_size() {
+        let mut options = SpectrumOptions::default();
+        assert_eq!(options.hop_size(256, 128), 128);
+        options.overlap = Some(0.75);
+        assert_eq!(options.hop_size(256, 128), 64);
+        options.overlap = Some(1.0);
+        assert_eq!(options.hop_size(256, 128), 1);
+
+        let mut analyzer = SpectrumAnalyzer::new(256, 256, &SpectrumOptions::default());
+        assert_eq!(analyzer.hop_size, 256);
+        analyzer.set_options(&options);
+        assert_eq!(analyzer.hop_size, 1);
+        analyzer.set_options(&SpectrumOptions::default());
+        assert_eq!(analyzer.hop_size, 256);
+    }
+
+    /// The spectrum of a single channel computed with its own FFT, as `process_pair()` would.
+    fn reference(analyzer: &SpectrumAnalyzer, input: &[f32]) -> Vec<f32> {
+        let n = analyzer.fft_size();
+        let coefficients = analyzer.window.coefficients();
+        let mut z: Vec<Complex<f32>> = input
+            .iter()
+            .zip(coefficients)
+            .map(|(v, c)| Complex::new(v * c, 0.0))
+            .collect();
+        let mut out = vec![Complex::zero(); n];
+        FFTplanner::new(false).plan_fft(n).process(&mut z, &mut out);
+        out[..analyzer.bins()]
+            .iter()
+            .map(|z| z.norm_sqr().max(MIN_POWER).log10() * 10.0 + analyzer.window.offset())
+            .collect()
+    }
+
+    fn sine(freq: f32, amp: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (i as f64 * freq as f64 / 48000.0 * 2.0 * PI).sin() as f32 * amp)
+            .collect()
+    }
+
+    #[test]
+    fn packed_fft_separates_channels() {
+        let mut analyzer = SpectrumAnalyzer::new(256, 256, &SpectrumOptions::default());
+        let (mut left, mut right) = (vec![0.0; 129], vec![0.0; 129]);
+        // the left is centered on bin 10 and the right falls between bins 37 and 38
+        let (l_in, r_in) = (sine(1875.0, 0.5, 256), sine(7000.0, 0.1, 256));
+        analyzer.process_pair(&l_in, &r_in, &mut left, &mut right);
+        // rounding errors are relative to the loudest bin of either channel
+        let amp = |db: f32| 10.0_f32.powf(db / 20.0);
+        let loudest = amp(left
+            .iter()
+            .chain(right.iter())
+            .copied()
+            .fold(f32::MIN, f32::max));
+        for (out, input) in [(&left, &l_in), (&right, &r_in)].iter() {
+            let expected = reference(&analyzer, input);
+            for (k, (v, e)) in out.iter().zip(expected.iter()).enumerate() {
+                let error = (amp(*v) - amp(*e)).abs() / loudest;
+                assert!(error < 1e-4, "bin {}: {} != {}", k, v, e);
+            }
+        }
+        assert!(left[10] > right[10] + 40.0, "{} {}", left[10], right[10]);
+        assert!(right[37] > left[37] + 40.0, "{} {}", right[37], left[37]);
+
+        // a silent channel is only rounding errors next to a loud one
+        let silence = vec![0.0; 256];
+        analyzer.process_pair(&l_in, &silence, &mut left, &mut right);
+        let l_peak = left.iter().copied().fold(f32::MIN, f32::max);
+        let r_peak = right.iter().copied().fold(f32::MIN, f32::max);
+        assert!(r_peak < l_peak - 90.0, "{} {}", l_peak, r_peak);
+    }
+
+    #[test]
+    fn silence_is_floored() {
+        let mut analyzer = SpectrumAnalyzer::new(256, 128, &SpectrumOptions::default());
+        let (mut left, mut right) = (vec![1.0; 129], vec![1.0; 129]);
+        let silence = vec![0.0; 512];
+        analyzer.process_pair(&silence, &silence, &mut left, &mut right);
+        let floor = MIN_POWER.log10() * 10.0 + analyzer.window.offset();
+        assert!(left
+            .iter()
+            .chain(right.iter())
+            .all(|v| (v - floor).abs() < 0.001));
+        assert!(left.iter().all(|v| v.is_finite()));
+    }
+}
@@ -0,0 +1,240 @@
+use std::str::FromStr;
+
+/// Frequency weightings that compensate for the ear's varying sensitivity to different frequencies.
+///
+/// Weightings are in dB and are added to the spectrum before it is split into bands.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Weighting {
+    /// The original hand-tuned `WEIGHT` table of the flat stack,
+    /// which was made for a 256 bin FFT at 48 kHz.
+    Flatstack,
+    /// IEC 61672 A-weighting.
+    A,
+    /// IEC 61672 C-weighting.
+    C,
+    /// No weighting.
+    Z,
+    /// The inverse of the ISO 226:2003 equal-loudness contour at the given phon level,
+    /// normalized to 0 dB at 1 kHz.
+    Iso226(f32),
+}
+impl Default for Weighting {
+    fn default() -> Self {
+        Weighting::Flatstack
+    }
+}
+impl FromStr for Weighting {
+    type Err = String;
+    /// Parses `flatstack`, `a`, `c`, `z`, or `iso226[:PHON]`, where `PHON` defaults to 40.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap().to_ascii_lowercase();
+        match (name.as_str(), parts.next()) {
+            ("flatstack", None) => Ok(Weighting::Flatstack),
+            ("a", None) => Ok(Weighting::A),
+            ("c", None) => Ok(Weighting::C),
+            ("z", None) => Ok(Weighting::Z),
+            ("iso226", None) => Ok(Weighting::Iso226(40.0)),
+            ("iso226", Some(phon)) => {
+                let phon =
+                    f32::from_str(phon).map_err(|e| format!("Invalid phon level: {:?}", e))?;
+                if 0.0 <= phon && phon <= 90.0 {
+                    Ok(Weighting::Iso226(phon))
+                } else {
+                    Err("ISO 226 phon level must be between [0,90].".to_string())
+                }
+            }
+            _ => Err(format!("Unknown weighting: {}", s)),
+        }
+    }
+}
+impl Weighting {
+    /// The weighting in dB at `freq` Hz, limited to no less than `FLOOR`.
+    pub fn at(&self, freq: f32) -> f32 {
+        let db = match self {
+            Weighting::Flatstack => table_lookup(freq * WEIGHT.len() as f32 / 48000.0),
+            Weighting::A => {
+                let f2 = (freq as f64).powi(2);
+                let r = 12194.0_f64.powi(2) * f2 * f2
+                    / ((f2 + 20.6_f64.powi(2))
+                        * ((f2 + 107.7_f64.powi(2)) * (f2 + 737.9_f64.powi(2))).sqrt()
+                        * (f2 + 12194.0_f64.powi(2)));
+                (20.0 * r.log10() + 2.00) as f32
+            }
+            Weighting::C => {
+                let f2 = (freq as f64).powi(2);
+                let r = 12194.0_f64.powi(2) * f2
+                    / ((f2 + 20.6_f64.powi(2)) * (f2 + 12194.0_f64.powi(2)));
+                (20.0 * r.log10() + 0.06) as f32
+            }
+            Weighting::Z => 0.0,
+            Weighting::Iso226(phon) => iso226_spl(*phon, 1000.0) - iso226_spl(*phon, freq),
+        };
+        db.max(FLOOR)
+    }
+    /// Computes the weighting of every bin, up to the Nyquist frequency,
+    /// of an FFT of `fft_size` at `rate` Hz.
+    pub fn weights(&self, fft_size: usize, rate: u32) -> Vec<f32> {
+        let bin_width = rate as f32 / fft_size as f32;
+        (0..fft_size / 2 + 1)
+            .map(|i| self.at(i as f32 * bin_width))
+            .collect()
+    }
+}
+
+/// The lowest weighting returned, which keeps the weighting of DC from being -inf.
+pub const FLOOR: f32 = -100.0;
+
+/// Interpolates `WEIGHT` at a fractional index, clamping to the ends of the table.
+fn table_lookup(pos: f32) -> f32 {
+    let last = WEIGHT.len() - 1;
+    let pos = pos.max(0.0).min(last as f32);
+    let i = pos as usize;
+    let next = WEIGHT[(i + 1).min(last)];
+    WEIGHT[i] + (next - WEIGHT[i]) * pos.fract()
+}
+
+const ISO226_F: [f32; 29] = [
+    20.0, 25.0, 31.5, 40.0, 50.0, 63.0, 80.0, 100.0, 125.0, 160.0, 200.0, 250.0, 315.0, 400.0,
+    500.0, 630.0, 800.0, 1000.0, 1250.0, 1600.0, 2000.0, 2500.0, 3150.0, 4000.0, 5000.0, 6300.0,
+    8000.0, 10000.0, 12500.0,
+];
+const ISO226_AF: [f32; 29] = [
+    0.532, 0.506, 0.480, 0.455, 0.432, 0.409, 0.387, 0.367, 0.349, 0.330, 0.315, 0.301, 0.288,
+    0.276, 0.267, 0.259, 0.253, 0.250, 0.246, 0.244, 0.243, 0.243, 0.243, 0.242, 0.242, 0.245,
+    0.254, 0.271, 0.301,
+];
+const ISO226_LU: [f32; 29] = [
+    -31.6, -27.2, -23.0, -19.1, -15.9, -13.0, -10.3, -8.1, -6.2, -4.5, -3.1, -2.0, -1.1, -0.4, 0.0,
+    0.3, 0.5, 0.0, -2.7, -4.1, -1.0, 1.7, 2.5, 1.2, -2.1, -7.1, -11.2, -10.7, -3.1,
+];
+const ISO226_TF: [f32; 29] = [
+    78.5, 68.7, 59.5, 51.1, 44.0, 37.5, 31.5, 26.5, 22.1, 17.9, 14.4, 11.4, 8.6, 6.2, 4.4, 3.0,
+    2.2, 2.4, 3.5, 1.7, -1.3, -4.2, -6.0, -5.4, -1.5, 6.0, 12.6, 13.9, 12.3,
+];
+
+/// The sound pressure level in dB at `freq` Hz of the ISO 226:2003 contour for `phon`.
+///
+/// The contour is only defined from 20 Hz to 12.5 kHz,
+/// so its parameters are interpolated in log-frequency and held constant outside of that range.
+fn iso226_spl(phon: f32, freq: f32) -> f32 {
+    let last = ISO226_F.len() - 1;
+    let (i, t) = if freq <= ISO226_F[0] {
+        (0, 0.0)
+    } else if freq >= ISO226_F[last] {
+        (last - 1, 1.0)
+    } else {
+        let i = ISO226_F.iter().position(|f| *f > freq).unwrap() - 1;
+        let t = (freq / ISO226_F[i]).ln() / (ISO226_F[i + 1] / ISO226_F[i]).ln();
+        (i, t)
+    };
+    let lerp = |v: &[f32; 29]| (v[i] + (v[i + 1] - v[i]) * t) as f64;
+    let (af, lu, tf) = (lerp(&ISO226_AF), lerp(&ISO226_LU), lerp(&ISO226_TF));
+    let a = 4.47e-3 * (10_f64.powf(0.025 * phon as f64) - 1.15)
+        + (0.4 * 10_f64.powf((tf + lu) / 10.0 - 9.0)).powf(af);
+    ((10.0 / af) * a.log10() - lu + 94.0) as f32
+}
+
+pub(crate) const WEIGHT: [f32; 256] = [
+    0.0, -20.45, -14.43, -10.92, -8.43, -6.50, -4.93, -3.61, -2.47, -1.47, -0.58, 0.22, 0.95, 1.61,
+    2.22, 2.79, 3.31, 3.80, 4.26, 4.68, 5.09, 5.47, 5.83, 6.17, 6.49, 6.80, 7.09, 7.37, 7.64, 7.89,
+    8.14, 8.37, 8.60, 8.81, 9.02, 9.22, 9.41, 9.59, 9.77, 9.93, 10.10, 10.25, 10.40, 10.54, 10.68,
+    10.81, 10.94, 11.06, 11.17, 11.27, 11.38, 11.47, 11.56, 11.64, 11.72, 11.79, 11.85, 11.91,
+    11.97, 12.01, 12.05, 12.09, 12.12, 12.14, 12.16, 12.17, 12.18, 12.18, 12.17, 12.16, 12.15,
+    12.13, 12.10, 12.07, 12.04, 12.00, 11.95, 11.91, 11.85, 11.79, 11.73, 11.67, 11.60, 11.52,
+    11.44, 11.36, 11.27, 11.18, 11.08, 10.98, 10.87, 10.76, 10.64, 10.51, 10.38, 10.24, 10.10,
+    9.95, 9.79, 9.63, 9.45, 9.27, 9.08, 8.89, 8.68, 8.47, 8.25, 8.02, 7.78, 7.54, 7.28, 7.02, 6.75,
+    6.48, 6.20, 5.91, 5.61, 5.31, 5.01, 4.70, 4.38, 4.06, 3.74, 3.41, 3.09, 2.76, 2.42, 2.09, 1.75,
+    1.41, 1.08, 0.74, 0.40, 0.06, -0.28, -0.62, -0.96, -1.29, -1.63, -1.97, -2.30, -2.63, -2.97,
+    -3.30, -3.63, -3.95, -4.28, -4.60, -4.93, -5.25, -5.57, -5.88, -6.20, -6.51, -6.83, -7.13,
+    -7.44, -7.75, -8.05, -8.35, -8.65, -8.95, -9.25, -9.54, -9.84, -10.13, -10.42, -10.70, -10.99,
+    -11.27, -11.55, -11.83, -12.11, -12.38, -12.66, -12.93, -13.20, -13.47, -13.74, -14.00, -14.27,
+    -14.53, -14.79, -15.05, -15.31, -15.56, -15.82, -16.07, -16.32, -16.57, -16.82, -17.06, -17.31,
+    -17.55, -17.79, -18.04, -18.27, -18.51, -18.75, -18.98, -19.22, -19.45, -19.68, -19.91, -20.14,
+    -20.37, -20.59, -20.82, -21.04, -21.27, -21.49, -21.71, -21.93, -22.14, -22.36, -22.58, -22.79,
+    -23.00, -23.21, -23.43, -23.64, -23.84, -24.05, -24.26, -24.46, -24.67, -24.87, -25.08, -25.28,
+    -25.48, -25.68, -25.88, -26.07, -26.27, -26.47, -26.66, -26.86, -27.05, -27.24, -27.43, -27.62,
+    -27.81, -28.00, -28.19, -28.38, -28.56, -28.75, -28.93, -29.12, -29.30, -29.48, -29.66, -29.84,
+    -30.02, -30.20, -30.38,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(weighting: Weighting, reference: &[(f32, f32)], tolerance: f32) {
+        for &(freq, db) in reference {
+            let got = weighting.at(freq);
+            assert!(
+                (got - db).abs() <= tolerance,
+                "{:?} at {} Hz is {} dB, not {} dB",
+                weighting,
+                freq,
+                got,
+                db
+            );
+        }
+    }
+
+    #[test]
+    fn a_and_c_match_iec_61672() {
+        // the IEC 61672-1 table at the exact base 10 frequencies of its nominal bands
+        let f =
+            |nominal: f32| 1000.0 * 10_f32.powf((10.0 * (nominal / 1000.0).log10()).round() / 10.0);
+        assert_close(
+            Weighting::A,
+            &[
+                (f(31.5), -39.4),
+                (f(63.0), -26.2),
+                (f(125.0), -16.1),
+                (f(250.0), -8.6),
+                (f(500.0), -3.2),
+                (f(1000.0), 0.0),
+                (f(2000.0), 1.2),
+                (f(4000.0), 1.0),
+                (f(8000.0), -1.1),
+                (f(16000.0), -6.6),
+            ],
+            0.15,
+        );
+        assert_close(
+            Weighting::C,
+            &[
+                (f(31.5), -3.0),
+                (f(63.0), -0.8),
+                (f(125.0), -0.2),
+                (f(250.0), 0.0),
+                (f(1000.0), 0.0),
+                (f(4000.0), -0.8),
+                (f(8000.0), -3.0),
+                (f(16000.0), -8.5),
+            ],
+            0.15,
+        );
+        assert_eq!(Weighting::A.at(0.0), FLOOR);
+    }
+
+    #[test]
+    fn iso226_is_normalized_at_1khz() {
+        for phon in [20.0, 40.0, 80.0].iter() {
+            assert!((iso226_spl(*phon, 1000.0) - phon).abs() < 0.1);
+            assert_eq!(Weighting::Iso226(*phon).at(1000.0), 0.0);
+        }
+        // the ear is far less sensitive to low frequencies at quiet levels
+        assert!(Weighting::Iso226(40.0).at(50.0) < -20.0);
+        assert!(Weighting::Iso226(40.0).at(3150.0) > 0.0);
+    }
+
+    #[test]
+    fn parses() {
+        assert_eq!(Weighting::from_str("A"), Ok(Weighting::A));
+        assert_eq!(Weighting::from_str("iso226"), Ok(Weighting::Iso226(40.0)));
+        assert_eq!(
+            Weighting::from_str("iso226:60"),
+            Ok(Weighting::Iso226(60.0))
+        );
+        assert!(Weighting::from_str("iso226:100").is_err());
+        assert!(Weighting::from_str("b").is_err());
+        assert_eq!(Weighting::Z.weights(256, 48000).len(), 129);
+    }
+}